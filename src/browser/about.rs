@@ -0,0 +1,318 @@
+use std::env;
+use std::path::{Component, PathBuf};
+
+use url::Url;
+use url::form_urlencoded;
+
+use cache::format_http_date;
+use download::DownloadState;
+use reader::reader_page;
+use session::Session;
+
+/// Build the HTML of a page of the browser itself, such as about:cookies, acting on any form
+/// posted to it. Returns None for pages that do not exist.
+pub fn about_page(url: &Url, body: Option<&[u8]>, session: &Session) -> Option<String> {
+    match url.path() {
+        "bookmarks" => Some(bookmarks_page(body, session)),
+        "cookies" => Some(cookies_page(body, session)),
+        "downloads" => Some(downloads_page(body, session)),
+        "history" => Some(history_page(url, body, session)),
+        "reader" => Some(reader_page(url, session)),
+        _ => None
+    }
+}
+
+/// Escape text for use in HTML content or a quoted attribute
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Build the HTML of a page explaining why a URL could not be shown
+pub fn error_page(title: &str, message: &str, url: &Url) -> String {
+    format!(
+        "<html><head><title>{}</title></head><body>\n<h1>{}</h1>\n<p>{}</p>\n<p><a href=\"{}\">Try again</a></p>\n</body></html>\n",
+        escape_html(title), escape_html(title), escape_html(message), escape_html(url.as_str())
+    )
+}
+
+/// The file a bookmark file path posted to about:bookmarks names, which has to be inside the
+/// home directory. Relative paths start from the home directory.
+fn bookmark_file(path: &str) -> Result<PathBuf, String> {
+    let home = match env::home_dir() {
+        Some(home) => home,
+        None => return Err("There is no home directory to keep bookmark files in".to_string())
+    };
+
+    let path = home.join(path.trim());
+    if path.components().any(|component| component == Component::ParentDir) || ! path.starts_with(&home) || path == home {
+        return Err(format!("Bookmark files have to be inside {}", home.display()));
+    }
+    Ok(path)
+}
+
+fn bookmarks_page(body: Option<&[u8]>, session: &Session) -> String {
+    let mut message = None;
+    let mut replace_form = None;
+    if let Some(body) = body {
+        let mut action = String::new();
+        let mut url = String::new();
+        let mut folder = String::new();
+        let mut path = String::new();
+        let mut replace = false;
+        for (name, value) in form_urlencoded::parse(body) {
+            match &*name {
+                "action" => action = value.into_owned(),
+                "url" => url = value.into_owned(),
+                "folder" => folder = value.into_owned(),
+                "path" => path = value.into_owned(),
+                "replace" => replace = true,
+                _ => ()
+            }
+        }
+
+        match &action[..] {
+            "Move" => session.bookmarks.move_to(&url, &folder),
+            "Remove" => session.bookmarks.remove(&url),
+            "Import" => message = Some(match bookmark_file(&path).and_then(|path| session.bookmarks.import(&path).map(|count| (count, path))) {
+                Ok((count, path)) => format!("Imported {} bookmarks from {}", count, path.display()),
+                Err(err) => err
+            }),
+            "Export" => match bookmark_file(&path) {
+                // An existing file is only written over once the user has seen which one it is
+                Ok(ref path) if path.exists() && ! replace => replace_form = Some(path.display().to_string()),
+                Ok(path) => message = Some(match session.bookmarks.export(&path, replace) {
+                    Ok(()) => format!("Exported bookmarks to {}", path.display()),
+                    Err(err) => err
+                }),
+                Err(err) => message = Some(err)
+            },
+            _ => ()
+        }
+    }
+
+    let mut html = String::from("<html><head><title>Bookmarks</title></head><body>\n<h1>Bookmarks</h1>\n");
+
+    let default_path = env::home_dir().map(|home| home.join("bookmarks.html").display().to_string()).unwrap_or(String::new());
+    html.push_str(&format!(
+        "<form method=\"post\" action=\"about:bookmarks\">Bookmark file: <input type=\"text\" name=\"path\" size=\"40\" value=\"{}\"> \
+        <input type=\"submit\" name=\"action\" value=\"Import\"> <input type=\"submit\" name=\"action\" value=\"Export\"></form>\n",
+        escape_html(&default_path)
+    ));
+    if let Some(message) = message {
+        html.push_str(&format!("<p>{}</p>\n", escape_html(&message)));
+    }
+    if let Some(path) = replace_form {
+        html.push_str(&format!(
+            "<form method=\"post\" action=\"about:bookmarks\">{} already exists. \
+            <input type=\"hidden\" name=\"path\" value=\"{}\"><input type=\"hidden\" name=\"replace\" value=\"1\">\
+            <input type=\"submit\" name=\"action\" value=\"Export\"> to replace it.</form>\n",
+            escape_html(&path), escape_html(&path)
+        ));
+    }
+
+    let bookmarks = session.bookmarks.list();
+    if bookmarks.is_empty() {
+        html.push_str("<p>No pages are bookmarked. Press Ctrl+D to bookmark a page.</p>\n");
+    }
+
+    let mut folder = None;
+    for bookmark in bookmarks.iter() {
+        if folder != Some(&bookmark.folder) {
+            if folder.is_some() {
+                html.push_str("</table>\n");
+            }
+            folder = Some(&bookmark.folder);
+            if ! bookmark.folder.is_empty() {
+                html.push_str(&format!("<h2>{}</h2>\n", escape_html(&bookmark.folder)));
+            }
+            html.push_str("<table cellpadding=\"4\" cellspacing=\"0\">\n");
+        }
+
+        let title = if bookmark.title.is_empty() { &bookmark.url } else { &bookmark.title };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td><form method=\"post\" action=\"about:bookmarks\">\
+            <input type=\"hidden\" name=\"url\" value=\"{}\"><input type=\"text\" name=\"folder\" value=\"{}\"> \
+            <input type=\"submit\" name=\"action\" value=\"Move\"> <input type=\"submit\" name=\"action\" value=\"Remove\"></form></td></tr>\n",
+            escape_html(&bookmark.url), escape_html(title), escape_html(&bookmark.url), escape_html(&bookmark.folder)
+        ));
+    }
+    if folder.is_some() {
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn downloads_page(body: Option<&[u8]>, session: &Session) -> String {
+    let mut message = None;
+    if let Some(body) = body {
+        let mut action = String::new();
+        let mut id = None;
+        for (name, value) in form_urlencoded::parse(body) {
+            match &*name {
+                "action" => action = value.into_owned(),
+                "id" => id = value.parse::<usize>().ok(),
+                _ => ()
+            }
+        }
+
+        if let Some(id) = id {
+            match &action[..] {
+                "Open" => if let Err(err) = session.downloads.open(id) {
+                    message = Some(err);
+                },
+                "Cancel" => session.downloads.cancel(id),
+                _ => ()
+            }
+        }
+    }
+
+    let mut html = String::from("<html><head><title>Downloads</title></head><body>\n<h1>Downloads</h1>\n");
+    if let Some(message) = message {
+        html.push_str(&format!("<p>{}</p>\n", escape_html(&message)));
+    }
+
+    let downloads = session.downloads.list();
+    if downloads.is_empty() {
+        html.push_str("<p>Nothing has been downloaded.</p>\n");
+    } else {
+        html.push_str("<table cellpadding=\"4\" cellspacing=\"0\">\n");
+        // Newest first
+        for (id, download) in downloads.iter().enumerate().rev() {
+            let (status, action) = match download.state {
+                DownloadState::InProgress => (download.progress(), "Cancel"),
+                DownloadState::Done => (download.progress(), "Open"),
+                DownloadState::Failed(ref err) => (err.clone(), ""),
+                DownloadState::Cancelled => ("Cancelled".to_string(), ""),
+            };
+            let button = if action.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    "<form method=\"post\" action=\"about:downloads\"><input type=\"hidden\" name=\"id\" value=\"{}\">\
+                    <input type=\"submit\" name=\"action\" value=\"{}\"></form>",
+                    id, action
+                )
+            };
+
+            html.push_str(&format!(
+                "<tr><td><a href=\"{}\">{}</a><br>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&download.url), escape_html(&download.path.display().to_string()), escape_html(&download.url), escape_html(&status), button
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// Most search results shown on the history page
+const HISTORY_RESULTS: usize = 500;
+
+fn history_page(url: &Url, body: Option<&[u8]>, session: &Session) -> String {
+    if clear_posted(body) {
+        session.history.clear();
+    }
+
+    let mut query = String::new();
+    for (name, value) in url.query_pairs() {
+        if name == "q" {
+            query = value.into_owned();
+        }
+    }
+
+    let mut html = String::from("<html><head><title>History</title></head><body>\n<h1>History</h1>\n");
+    html.push_str(&format!(
+        "<form action=\"about:history\"><input type=\"text\" name=\"q\" size=\"40\" value=\"{}\"> <input type=\"submit\" value=\"Search\"></form>\n",
+        escape_html(&query)
+    ));
+    html.push_str("<form method=\"post\" action=\"about:history\"><input type=\"submit\" name=\"clear\" value=\"Clear history\"></form>\n");
+
+    let visits = session.history.search(&query);
+    if visits.is_empty() {
+        if query.is_empty() {
+            html.push_str("<p>No pages have been visited.</p>\n");
+        } else {
+            html.push_str("<p>No pages match the search.</p>\n");
+        }
+    } else {
+        html.push_str("<table cellpadding=\"4\" cellspacing=\"0\">\n");
+        html.push_str("<tr><th>Last visited</th><th>Page</th><th>Visits</th></tr>\n");
+        for visit in visits.iter().take(HISTORY_RESULTS) {
+            let title = if visit.title.is_empty() { &visit.url } else { &visit.title };
+            html.push_str(&format!(
+                "<tr><td>{}</td><td><a href=\"{}\">{}</a><br>{}</td><td>{}</td></tr>\n",
+                format_http_date(visit.last), escape_html(&visit.url), escape_html(title), escape_html(&visit.url), visit.visits
+            ));
+        }
+        html.push_str("</table>\n");
+        if visits.len() > HISTORY_RESULTS {
+            html.push_str(&format!("<p>Showing the {} most recent of {} pages.</p>\n", HISTORY_RESULTS, visits.len()));
+        }
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// Was the clear button of a page pressed to post the form
+fn clear_posted(body: Option<&[u8]>) -> bool {
+    body.map_or(false, |body| form_urlencoded::parse(body).any(|(name, _)| name == "clear"))
+}
+
+fn cookies_page(body: Option<&[u8]>, session: &Session) -> String {
+    if clear_posted(body) {
+        session.cookies.clear();
+    }
+
+    let mut html = String::from("<html><head><title>Cookies</title></head><body>\n<h1>Cookies</h1>\n");
+    html.push_str("<form method=\"post\" action=\"about:cookies\"><input type=\"submit\" name=\"clear\" value=\"Clear all cookies\"></form>\n");
+
+    let cookies = session.cookies.list();
+    if cookies.is_empty() {
+        html.push_str("<p>No cookies are stored.</p>\n");
+    } else {
+        html.push_str("<table border=\"1\" cellpadding=\"4\" cellspacing=\"0\">\n");
+        html.push_str("<tr><th>Domain</th><th>Path</th><th>Name</th><th>Value</th><th>Expires</th><th>Flags</th></tr>\n");
+        for cookie in cookies.iter() {
+            let domain = if cookie.host_only {
+                cookie.domain.clone()
+            } else {
+                format!(".{}", cookie.domain)
+            };
+            let expires = match cookie.expires {
+                Some(expires) => format_http_date(expires),
+                None => "End of session".to_string()
+            };
+            let mut flags = Vec::new();
+            if cookie.secure {
+                flags.push("Secure");
+            }
+            if cookie.http_only {
+                flags.push("HttpOnly");
+            }
+
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&domain), escape_html(&cookie.path), escape_html(&cookie.name),
+                escape_html(&cookie.value), expires, flags.join(" ")
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
@@ -0,0 +1,266 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use about::escape_html;
+use cache::now;
+use decode::ascii_lowercase;
+
+/// A saved page
+#[derive(Clone, Debug)]
+pub struct Bookmark {
+    pub title: String,
+    pub url: String,
+    /// The folders the bookmark is in, separated by slashes, or empty for the top level
+    pub folder: String,
+    /// When the bookmark was added in seconds since the epoch
+    pub added: u64,
+}
+
+/// The bookmarks, kept in the Netscape bookmark file format that other browsers import and export
+pub struct Bookmarks {
+    /// Bookmarks in the order they were added
+    bookmarks: Mutex<Vec<Bookmark>>,
+    /// The file bookmarks are saved to, if there is a home directory
+    path: Option<PathBuf>,
+}
+
+impl Bookmarks {
+    pub fn new() -> Bookmarks {
+        let path = env::home_dir().map(|home| home.join(".config").join("browser").join("bookmarks.html"));
+
+        let mut bookmarks = Vec::new();
+        if let Some(ref path) = path {
+            if path.exists() {
+                match read_file(path) {
+                    Ok(data) => bookmarks = parse_netscape(&data),
+                    Err(err) => println!("{}", err)
+                }
+            }
+        }
+
+        Bookmarks {
+            bookmarks: Mutex::new(bookmarks),
+            path: path,
+        }
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.bookmarks.lock().unwrap().iter().any(|bookmark| bookmark.url == url)
+    }
+
+    /// Bookmark a page at the top level, returning false if it was already bookmarked
+    pub fn add(&self, url: &str, title: &str) -> bool {
+        {
+            let mut bookmarks = self.bookmarks.lock().unwrap();
+            if bookmarks.iter().any(|bookmark| bookmark.url == url) {
+                return false;
+            }
+            bookmarks.push(Bookmark {
+                title: title.to_string(),
+                url: url.to_string(),
+                folder: String::new(),
+                added: now(),
+            });
+        }
+        self.save();
+        true
+    }
+
+    pub fn remove(&self, url: &str) {
+        self.bookmarks.lock().unwrap().retain(|bookmark| bookmark.url != url);
+        self.save();
+    }
+
+    /// Move a bookmark into a folder, given as folder names separated by slashes
+    pub fn move_to(&self, url: &str, folder: &str) {
+        let names: Vec<&str> = folder.split('/').map(|name| name.trim()).filter(|name| ! name.is_empty()).collect();
+        for bookmark in self.bookmarks.lock().unwrap().iter_mut() {
+            if bookmark.url == url {
+                bookmark.folder = names.join("/");
+            }
+        }
+        self.save();
+    }
+
+    /// Every bookmark, sorted by folder and then in the order they were added
+    pub fn list(&self) -> Vec<Bookmark> {
+        let mut bookmarks = self.bookmarks.lock().unwrap().clone();
+        sort_by_folder(&mut bookmarks);
+        bookmarks
+    }
+
+    /// Add the bookmarks from a Netscape bookmark file that are not already here, returning
+    /// how many were added
+    pub fn import(&self, path: &Path) -> Result<usize, String> {
+        let imported = parse_netscape(&read_file(path)?);
+
+        let mut count = 0;
+        {
+            let mut bookmarks = self.bookmarks.lock().unwrap();
+            for bookmark in imported {
+                if ! bookmarks.iter().any(|old| old.url == bookmark.url) {
+                    bookmarks.push(bookmark);
+                    count += 1;
+                }
+            }
+        }
+        self.save();
+        Ok(count)
+    }
+
+    /// Write every bookmark to a Netscape bookmark file, failing if it exists unless replace is set
+    pub fn export(&self, path: &Path, replace: bool) -> Result<(), String> {
+        let data = write_netscape(&self.list());
+        write_file(path, &data, replace)
+    }
+
+    fn save(&self) {
+        if let Some(ref path) = self.path {
+            if let Err(err) = self.export(path, true) {
+                println!("{}", err);
+            }
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    let mut data = Vec::new();
+    File::open(path).and_then(|mut file| file.read_to_end(&mut data)).map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+fn write_file(path: &Path, data: &str, replace: bool) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    if replace {
+        options.write(true).create(true).truncate(true);
+    } else {
+        options.write(true).create_new(true);
+    }
+
+    match path.parent() {
+        Some(parent) if ! parent.as_os_str().is_empty() => fs::create_dir_all(parent),
+        _ => Ok(())
+    }.and_then(|_| options.open(path)).and_then(|mut file| file.write_all(data.as_bytes())).map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+/// Sort bookmarks so that each folder comes straight after its parent, keeping the order within
+/// a folder
+fn sort_by_folder(bookmarks: &mut Vec<Bookmark>) {
+    bookmarks.sort_by(|a, b| {
+        let a_names: Vec<&str> = a.folder.split('/').filter(|name| ! name.is_empty()).collect();
+        let b_names: Vec<&str> = b.folder.split('/').filter(|name| ! name.is_empty()).collect();
+        a_names.cmp(&b_names)
+    });
+}
+
+/// Read the bookmarks from a Netscape bookmark file, where each folder is an H3 heading followed
+/// by a DL list, and each bookmark is a link
+fn parse_netscape(data: &str) -> Vec<Bookmark> {
+    let mut bookmarks = Vec::new();
+    // The folder each open list belongs to, or None for lists without a heading
+    let mut folders: Vec<Option<String>> = Vec::new();
+    let mut heading = None;
+
+    for part in data.split('<').skip(1) {
+        let (tag, text) = match part.find('>') {
+            Some(end) => (&part[..end], &part[end + 1..]),
+            None => continue
+        };
+        let name = ascii_lowercase(tag.split_whitespace().next().unwrap_or(""));
+
+        match &name[..] {
+            "h3" => heading = Some(unescape_html(text.trim()).replace('/', "-")),
+            "dl" => folders.push(heading.take()),
+            "/dl" => {
+                folders.pop();
+            },
+            "a" => if let Some(url) = attribute(tag, "href") {
+                let names: Vec<&str> = folders.iter().filter_map(|folder| folder.as_ref().map(|name| &name[..])).collect();
+                bookmarks.push(Bookmark {
+                    title: unescape_html(text.trim()),
+                    url: url,
+                    folder: names.join("/"),
+                    added: attribute(tag, "add_date").and_then(|date| date.parse().ok()).unwrap_or(0),
+                });
+            },
+            _ => ()
+        }
+    }
+
+    bookmarks
+}
+
+/// The value of an attribute inside a tag, ignoring the case of its name
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = ascii_lowercase(tag);
+    let mut start = 0;
+    while let Some(i) = lower[start..].find(name) {
+        let name_start = start + i;
+        let rest = lower[name_start + name.len()..].trim_left();
+        let preceded = name_start > 0 && (lower.as_bytes()[name_start - 1] as char).is_whitespace();
+        if preceded && rest.starts_with('=') {
+            let value_start = tag.len() - rest.len() + 1;
+            let value = tag[value_start..].trim_left();
+            let value = if value.starts_with('"') || value.starts_with('\'') {
+                let quote = &value[..1];
+                let value = &value[1..];
+                &value[..value.find(quote).unwrap_or(value.len())]
+            } else {
+                &value[..value.find(char::is_whitespace).unwrap_or(value.len())]
+            };
+            return Some(unescape_html(value));
+        }
+        start = name_start + name.len();
+    }
+    None
+}
+
+/// Undo the escaping of the few entities used in bookmark files
+fn unescape_html(s: &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&")
+}
+
+/// Write bookmarks, already sorted by folder, as a Netscape bookmark file
+fn write_netscape(bookmarks: &[Bookmark]) -> String {
+    let mut data = String::from("<!DOCTYPE NETSCAPE-Bookmark-file-1>\n");
+    data.push_str("<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n");
+    data.push_str("<TITLE>Bookmarks</TITLE>\n<H1>Bookmarks</H1>\n<DL><p>\n");
+
+    let mut open: Vec<&str> = Vec::new();
+    for bookmark in bookmarks.iter() {
+        let names: Vec<&str> = bookmark.folder.split('/').filter(|name| ! name.is_empty()).collect();
+
+        let mut common = 0;
+        while common < open.len() && common < names.len() && open[common] == names[common] {
+            common += 1;
+        }
+        while open.len() > common {
+            open.pop();
+            data.push_str(&format!("{}</DL><p>\n", indent(open.len() + 1)));
+        }
+        for name in names[common..].iter() {
+            data.push_str(&format!("{}<DT><H3>{}</H3>\n", indent(open.len() + 1), escape_html(name)));
+            data.push_str(&format!("{}<DL><p>\n", indent(open.len() + 1)));
+            open.push(name);
+        }
+
+        data.push_str(&format!(
+            "{}<DT><A HREF=\"{}\" ADD_DATE=\"{}\">{}</A>\n",
+            indent(open.len() + 1), escape_html(&bookmark.url), bookmark.added, escape_html(&bookmark.title)
+        ));
+    }
+    while ! open.is_empty() {
+        open.pop();
+        data.push_str(&format!("{}</DL><p>\n", indent(open.len() + 1)));
+    }
+
+    data.push_str("</DL><p>\n");
+    data
+}
+
+fn indent(level: usize) -> String {
+    "    ".repeat(level)
+}
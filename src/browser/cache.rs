@@ -0,0 +1,529 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::Headers;
+use url::Url;
+
+/// Bytes of response bodies kept in memory before the oldest are dropped
+const MEMORY_LIMIT: usize = 32 * 1024 * 1024;
+
+/// Largest response body that is stored at all
+const ENTRY_LIMIT: usize = MEMORY_LIMIT / 4;
+
+/// Bytes of entries kept on disk between runs, with the least recently written removed first
+const DISK_LIMIT: u64 = 256 * 1024 * 1024;
+
+/// Longest a response is assumed fresh from its Last-Modified date alone, in seconds
+const HEURISTIC_LIMIT: u64 = 24 * 60 * 60;
+
+/// Headers that only apply to a single response and are never stored
+const UNSTORED_HEADERS: [&'static str; 4] = ["connection", "keep-alive", "set-cookie", "transfer-encoding"];
+
+/// A stored response
+pub struct Entry {
+    /// The response headers as names and values
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+    /// When the response was received, in seconds since the epoch
+    pub stored: u64,
+}
+
+impl Entry {
+    fn new(headers: &Headers, data: Vec<u8>) -> Entry {
+        let mut entry = Entry {
+            headers: Vec::new(),
+            data: data,
+            stored: now(),
+        };
+        entry.update(headers);
+        entry
+    }
+
+    /// Replace stored headers with the ones in a newer response
+    fn update(&mut self, headers: &Headers) {
+        for view in headers.iter() {
+            let name = view.name().to_lowercase();
+            if UNSTORED_HEADERS.contains(&&name[..]) {
+                continue;
+            }
+
+            let value = view.value_string();
+            match self.headers.iter().position(|&(ref key, _)| *key == name) {
+                Some(i) => self.headers[i].1 = value,
+                None => self.headers.push((name, value))
+            }
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|&&(ref key, _)| *key == name).map(|&(_, ref value)| &value[..])
+    }
+
+    /// The headers to hand to the page parser
+    pub fn response_headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        for &(ref name, ref value) in self.headers.iter() {
+            headers.set_raw(name.clone(), vec![value.clone().into_bytes()]);
+        }
+        headers
+    }
+
+    /// The headers to send to check whether the stored response is still current
+    pub fn validators(&self) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(etag) = self.header("etag") {
+            headers.set_raw("If-None-Match", vec![etag.to_string().into_bytes()]);
+        }
+        if let Some(last_modified) = self.header("last-modified") {
+            headers.set_raw("If-Modified-Since", vec![last_modified.to_string().into_bytes()]);
+        }
+        headers
+    }
+
+    fn has_validators(&self) -> bool {
+        self.header("etag").is_some() || self.header("last-modified").is_some()
+    }
+
+    /// How long the response may be used without checking with the server, in seconds
+    fn lifetime(&self) -> u64 {
+        let directives = cache_control(self.header("cache-control"));
+        if directives.iter().any(|&(ref name, _)| name == "no-cache") {
+            return 0;
+        }
+        for &(ref name, ref value) in directives.iter() {
+            if name == "max-age" {
+                return value.as_ref().and_then(|value| value.parse().ok()).unwrap_or(0);
+            }
+        }
+
+        let date = self.header("date").and_then(parse_http_date).unwrap_or(self.stored);
+        if let Some(expires) = self.header("expires") {
+            // Invalid dates, such as "0", mean already expired
+            return parse_http_date(expires).map_or(0, |expires| expires.saturating_sub(date));
+        }
+
+        // Without an explicit lifetime, assume a tenth of the time since it last changed
+        if let Some(last_modified) = self.header("last-modified").and_then(parse_http_date) {
+            return cmp::min(HEURISTIC_LIMIT, date.saturating_sub(last_modified) / 10);
+        }
+
+        0
+    }
+
+    /// How old the response is now, in seconds
+    fn age(&self) -> u64 {
+        let age = self.header("age").and_then(|age| age.trim().parse().ok()).unwrap_or(0);
+        age + now().saturating_sub(self.stored)
+    }
+
+    fn size(&self) -> usize {
+        self.data.len() + self.headers.iter().map(|&(ref name, ref value)| name.len() + value.len()).sum::<usize>()
+    }
+
+    /// Write the entry as its URL, the time stored, header lines, a blank line and the body
+    fn write<W: Write>(&self, key: &str, w: &mut W) -> io::Result<()> {
+        write!(w, "{}\n{}\n", key, self.stored)?;
+        for &(ref name, ref value) in self.headers.iter() {
+            write!(w, "{}: {}\n", name, value)?;
+        }
+        w.write_all(b"\n")?;
+        w.write_all(&self.data)
+    }
+
+    /// Read an entry written by write, returning None if it is damaged or for another URL
+    fn read(key: &str, data: &[u8]) -> Option<Entry> {
+        let split = match data.windows(2).position(|window| window == b"\n\n") {
+            Some(split) => split,
+            None => return None
+        };
+        let head = match str::from_utf8(&data[..split]) {
+            Ok(head) => head,
+            Err(_) => return None
+        };
+
+        let mut lines = head.lines();
+        if lines.next() != Some(key) {
+            return None;
+        }
+        let stored = match lines.next().and_then(|line| line.parse().ok()) {
+            Some(stored) => stored,
+            None => return None
+        };
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let mut parts = line.splitn(2, ": ");
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => headers.push((name.to_string(), value.to_string())),
+                _ => return None
+            }
+        }
+
+        Some(Entry {
+            headers: headers,
+            data: data[split + 2..].to_vec(),
+            stored: stored,
+        })
+    }
+}
+
+/// The result of looking up a URL
+pub enum Lookup {
+    /// A response that can be used as it is
+    Fresh(Arc<Entry>),
+    /// A response that has to be checked with the server first
+    Stale(Arc<Entry>),
+    Miss,
+}
+
+struct Memory {
+    entries: BTreeMap<String, Arc<Entry>>,
+    size: usize,
+}
+
+/// Responses to GET requests, kept in memory and on disk
+pub struct Cache {
+    memory: Mutex<Memory>,
+    /// Where entries are written, if there is a home directory
+    dir: Option<PathBuf>,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        let dir = env::home_dir().map(|home| home.join(".cache").join("browser"));
+        if let Some(ref dir) = dir {
+            match fs::create_dir_all(dir) {
+                Ok(()) => prune(dir),
+                Err(err) => println!("Failed to create cache directory {}: {}", dir.display(), err)
+            }
+        }
+
+        Cache {
+            memory: Mutex::new(Memory {
+                entries: BTreeMap::new(),
+                size: 0,
+            }),
+            dir: dir,
+        }
+    }
+
+    /// Find a stored response for a URL
+    pub fn get(&self, url: &Url) -> Lookup {
+        let key = cache_key(url);
+
+        let entry = self.memory.lock().unwrap().entries.get(&key).cloned();
+        let entry = match entry {
+            Some(entry) => entry,
+            None => match self.load(&key) {
+                Some(entry) => {
+                    let entry = Arc::new(entry);
+                    self.remember(key.clone(), entry.clone());
+                    entry
+                },
+                None => return Lookup::Miss
+            }
+        };
+
+        if entry.age() < entry.lifetime() {
+            Lookup::Fresh(entry)
+        } else if entry.has_validators() {
+            Lookup::Stale(entry)
+        } else {
+            // There is no way to check it with the server, so it will never be used again
+            self.remove(&key);
+            Lookup::Miss
+        }
+    }
+
+    /// Store a successful response to a GET request, if its headers allow it
+    pub fn put(&self, url: &Url, headers: &Headers, data: Vec<u8>) {
+        let entry = Entry::new(headers, data);
+        if storable(&entry) {
+            self.save(cache_key(url), entry);
+        }
+    }
+
+    /// Take the headers of a 304 Not Modified response into a stale entry, returning the updated entry
+    pub fn revalidate(&self, url: &Url, entry: &Entry, headers: &Headers) -> Arc<Entry> {
+        let mut updated = Entry {
+            headers: entry.headers.clone(),
+            data: entry.data.clone(),
+            stored: now(),
+        };
+        updated.update(headers);
+
+        let key = cache_key(url);
+        if storable(&updated) {
+            self.save(key, updated)
+        } else {
+            self.remove(&key);
+            Arc::new(updated)
+        }
+    }
+
+    /// Wrap a response body so that it is stored once it has all been read
+    pub fn store<R: Read>(&self, url: &Url, headers: &Headers, inner: R) -> Store<R> {
+        // Check the headers now to avoid holding on to a body that will not be kept
+        let keep = storable(&Entry::new(headers, Vec::new()));
+        Store {
+            cache: self,
+            url: url.clone(),
+            headers: if keep { Some(headers.clone()) } else { None },
+            data: Vec::new(),
+            inner: inner,
+        }
+    }
+
+    fn save(&self, key: String, entry: Entry) -> Arc<Entry> {
+        if let Some(path) = self.path(&key) {
+            let result = File::create(&path).and_then(|mut file| entry.write(&key, &mut file));
+            if let Err(err) = result {
+                println!("Failed to write cache entry {}: {}", path.display(), err);
+            }
+        }
+
+        let entry = Arc::new(entry);
+        self.remember(key, entry.clone());
+        entry
+    }
+
+    fn load(&self, key: &str) -> Option<Entry> {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return None
+        };
+
+        let mut data = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => if let Err(err) = file.read_to_end(&mut data) {
+                println!("Failed to read cache entry {}: {}", path.display(), err);
+                return None;
+            },
+            Err(_) => return None
+        }
+
+        Entry::read(key, &data)
+    }
+
+    fn remove(&self, key: &str) {
+        let mut memory = self.memory.lock().unwrap();
+        if let Some(old) = memory.entries.remove(key) {
+            memory.size -= old.size();
+        }
+        if let Some(path) = self.path(key) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Keep an entry in memory, dropping the oldest ones to stay under the limit
+    fn remember(&self, key: String, entry: Arc<Entry>) {
+        let mut memory = self.memory.lock().unwrap();
+        memory.size += entry.size();
+        if let Some(old) = memory.entries.insert(key, entry) {
+            memory.size -= old.size();
+        }
+
+        while memory.size > MEMORY_LIMIT {
+            let oldest = match memory.entries.iter().min_by_key(|&(_, entry)| entry.stored) {
+                Some((key, _)) => key.clone(),
+                None => break
+            };
+            if let Some(old) = memory.entries.remove(&oldest) {
+                memory.size -= old.size();
+            }
+        }
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            dir.join(format!("{:016x}", hasher.finish()))
+        })
+    }
+}
+
+/// Remove the least recently written entries on disk until they fit in DISK_LIMIT
+fn prune(dir: &Path) {
+    let read_dir = match fs::read_dir(dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) => {
+            println!("Failed to read cache directory {}: {}", dir.display(), err);
+            return;
+        }
+    };
+
+    let mut files = Vec::new();
+    let mut total = 0;
+    for dir_entry in read_dir {
+        if let Ok(dir_entry) = dir_entry {
+            if let Ok(metadata) = dir_entry.metadata() {
+                if metadata.is_file() {
+                    total += metadata.len();
+                    files.push((metadata.modified().unwrap_or(UNIX_EPOCH), metadata.len(), dir_entry.path()));
+                }
+            }
+        }
+    }
+
+    files.sort();
+    for &(_, len, ref path) in files.iter() {
+        if total <= DISK_LIMIT {
+            break;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => total -= len,
+            Err(err) => println!("Failed to remove cache entry {}: {}", path.display(), err)
+        }
+    }
+}
+
+/// A response body being read from the network, stored in the cache when it reaches the end
+pub struct Store<'c, R: Read> {
+    cache: &'c Cache,
+    url: Url,
+    /// The response headers, or None if the response is not to be stored
+    headers: Option<Headers>,
+    data: Vec<u8>,
+    inner: R,
+}
+
+impl<'c, R: Read> Read for Store<'c, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count == 0 {
+            if let Some(headers) = self.headers.take() {
+                let data = mem::replace(&mut self.data, Vec::new());
+                self.cache.put(&self.url, &headers, data);
+            }
+        } else if self.headers.is_some() {
+            self.data.extend_from_slice(&buf[..count]);
+            // Large responses such as downloads are streamed through without being kept
+            if self.data.len() > ENTRY_LIMIT {
+                self.headers = None;
+                self.data = Vec::new();
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Can the response be stored at all
+fn storable(entry: &Entry) -> bool {
+    let directives = cache_control(entry.header("cache-control"));
+    if directives.iter().any(|&(ref name, _)| name == "no-store") {
+        return false;
+    }
+    if entry.header("vary").map_or(false, |vary| vary.trim() == "*") {
+        return false;
+    }
+    entry.lifetime() > 0 || entry.has_validators()
+}
+
+/// The URL without its fragment, which is never sent to the server
+fn cache_key(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// The current time in seconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+/// Split a Cache-Control header into lowercase directive names and their values
+fn cache_control(header: Option<&str>) -> Vec<(String, Option<String>)> {
+    let mut directives = Vec::new();
+    if let Some(header) = header {
+        for directive in header.split(',') {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim().to_lowercase();
+            if ! name.is_empty() {
+                let value = parts.next().map(|value| value.trim().trim_matches('"').to_string());
+                directives.push((name, value));
+            }
+        }
+    }
+    directives
+}
+
+/// Parse an HTTP date in any of the three formats of RFC 7231 to seconds since the epoch
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    const MONTHS: [&'static str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+    // Drop the weekday, leaving "06 Nov 1994 08:49:37 GMT", "06 Nov 94 08:49:37 GMT" or "Nov 6 08:49:37 1994"
+    let parts: Vec<&str> = date.split(|c| c == ' ' || c == ',' || c == '-').filter(|part| ! part.is_empty()).skip(1).collect();
+    if parts.len() < 4 {
+        return None;
+    }
+
+    let (day, month, year, time) = if parts[0].parse::<u64>().is_ok() {
+        (parts[0], parts[1], parts[2], parts[3])
+    } else {
+        (parts[1], parts[0], parts[3], parts[2])
+    };
+
+    let day: u64 = match day.parse() {
+        Ok(day) if day >= 1 && day <= 31 => day,
+        _ => return None
+    };
+    let month = match MONTHS.iter().position(|name| *name == month.to_lowercase()) {
+        Some(month) => month as u64 + 1,
+        None => return None
+    };
+    let year: u64 = match year.parse() {
+        Ok(year) if year < 70 => year + 2000,
+        Ok(year) if year < 100 => year + 1900,
+        Ok(year) if year >= 1970 => year,
+        _ => return None
+    };
+
+    let mut clock = time.split(':').map(|part| part.parse::<u64>());
+    let (hour, minute, second) = match (clock.next(), clock.next(), clock.next()) {
+        (Some(Ok(hour)), Some(Ok(minute)), Some(Ok(second))) if hour < 24 && minute < 60 && second < 61 => (hour, minute, second),
+        _ => return None
+    };
+
+    // Days since the epoch, counting years from March so the leap day comes last
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let days = 365 * y + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day - 1 - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Format seconds since the epoch as an HTTP date, such as "Sun, 06 Nov 1994 08:49:37 GMT"
+pub fn format_http_date(time: u64) -> String {
+    const DAYS: [&'static str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let days = time / 86400;
+    let seconds = time % 86400;
+
+    // The inverse of the day count in parse_http_date
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+        seconds / 3600, seconds % 3600 / 60, seconds % 60
+    )
+}
@@ -0,0 +1,356 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use url::Url;
+
+use cache::{now, parse_http_date};
+
+/// Suffixes of more than one label under which unrelated sites register their names, so a cookie
+/// for one would be sent to all of them. Suffixes of a single label, such as com, are never
+/// allowed either.
+const PUBLIC_SUFFIXES: [&'static str; 44] = [
+    "ac.jp", "ac.uk", "appspot.com", "blogspot.com", "co.in", "co.jp", "co.kr", "co.nz", "co.uk",
+    "co.za", "com.ar", "com.au", "com.br", "com.cn", "com.hk", "com.mx", "com.sg", "com.tr", "com.tw",
+    "edu.au", "github.io", "gov.au", "gov.uk", "herokuapp.com", "ltd.uk", "me.uk", "ne.jp", "net.au",
+    "net.br", "net.cn", "net.in", "net.nz", "net.uk", "or.jp", "or.kr", "org.au", "org.br", "org.cn",
+    "org.in", "org.nz", "org.uk", "org.za", "plc.uk", "sch.uk",
+];
+
+/// A cookie as stored by the jar, following RFC 6265
+#[derive(Clone, Debug)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    /// Is the cookie only sent to exactly its domain, rather than also to subdomains
+    pub host_only: bool,
+    pub path: String,
+    /// When the cookie expires in seconds since the epoch, or None for the end of the session
+    pub expires: Option<u64>,
+    pub secure: bool,
+    pub http_only: bool,
+}
+
+impl Cookie {
+    /// Parse a Set-Cookie header received from a URL, returning None if it is invalid or not
+    /// allowed to be set by that URL
+    pub fn parse(header: &str, url: &Url) -> Option<Cookie> {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return None
+        };
+
+        let mut parts = header.split(';');
+        let mut pair = parts.next().unwrap_or("").splitn(2, '=');
+        let name = pair.next().unwrap_or("").trim();
+        let value = match pair.next() {
+            Some(value) => value.trim(),
+            None => return None
+        };
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url),
+            expires: None,
+            secure: false,
+            http_only: false,
+        };
+
+        let mut max_age = None;
+        for part in parts {
+            let mut attribute = part.splitn(2, '=');
+            let key = attribute.next().unwrap_or("").trim().to_lowercase();
+            let value = attribute.next().unwrap_or("").trim();
+            match &key[..] {
+                "expires" => if let Some(expires) = parse_http_date(value) {
+                    cookie.expires = Some(expires);
+                },
+                "max-age" => if let Ok(seconds) = value.parse::<i64>() {
+                    max_age = Some(seconds);
+                },
+                "domain" => {
+                    let domain = value.trim_left_matches('.').to_lowercase();
+                    if ! domain.is_empty() {
+                        if ! domain_match(&host, &domain) {
+                            return None;
+                        }
+                        // A public suffix could be used to track across every site under it, so
+                        // only a host that is one itself may name it, keeping the cookie to itself
+                        if is_public_suffix(&domain) {
+                            if domain != host {
+                                return None;
+                            }
+                        } else {
+                            cookie.domain = domain;
+                            cookie.host_only = false;
+                        }
+                    }
+                },
+                "path" => if value.starts_with('/') {
+                    cookie.path = value.to_string();
+                },
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                _ => ()
+            }
+        }
+
+        // Max-Age takes precedence over Expires
+        if let Some(seconds) = max_age {
+            cookie.expires = Some(if seconds <= 0 { 0 } else { now() + seconds as u64 });
+        }
+
+        Some(cookie)
+    }
+
+    fn expired(&self, time: u64) -> bool {
+        self.expires.map_or(false, |expires| expires <= time)
+    }
+
+    /// Should the cookie be sent with a request to a URL
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false
+        };
+
+        if self.host_only {
+            if host != self.domain {
+                return false;
+            }
+        } else if ! domain_match(&host, &self.domain) {
+            return false;
+        }
+
+        if self.secure && url.scheme() != "https" {
+            return false;
+        }
+
+        path_match(url.path(), &self.path)
+    }
+
+    /// Write the cookie as a line of a Netscape cookies.txt file
+    fn line(&self) -> String {
+        format!(
+            "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            if self.http_only { "#HttpOnly_" } else { "" },
+            if self.host_only { self.domain.clone() } else { format!(".{}", self.domain) },
+            if self.host_only { "FALSE" } else { "TRUE" },
+            self.path,
+            if self.secure { "TRUE" } else { "FALSE" },
+            self.expires.unwrap_or(0),
+            self.name,
+            self.value
+        )
+    }
+
+    /// Read a line of a Netscape cookies.txt file
+    fn from_line(line: &str) -> Option<Cookie> {
+        let (http_only, line) = if line.starts_with("#HttpOnly_") {
+            (true, &line["#HttpOnly_".len()..])
+        } else if line.starts_with('#') {
+            return None;
+        } else {
+            (false, line)
+        };
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 7 {
+            return None;
+        }
+
+        let expires = match fields[4].parse() {
+            Ok(expires) => expires,
+            Err(_) => return None
+        };
+
+        Some(Cookie {
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+            domain: fields[0].trim_left_matches('.').to_string(),
+            host_only: fields[1] != "TRUE",
+            path: fields[2].to_string(),
+            expires: if expires == 0 { None } else { Some(expires) },
+            secure: fields[3] == "TRUE",
+            http_only: http_only,
+        })
+    }
+}
+
+/// Does a host fall under a cookie domain
+fn domain_match(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true;
+    }
+    // Addresses only ever match themselves
+    host.parse::<IpAddr>().is_err() && host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.')
+}
+
+fn is_public_suffix(domain: &str) -> bool {
+    ! domain.contains('.') || PUBLIC_SUFFIXES.contains(&domain)
+}
+
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path || (path.starts_with(cookie_path) && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// The directory of the request path, used when a cookie has no Path attribute
+fn default_path(url: &Url) -> String {
+    let path = url.path();
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string()
+    }
+}
+
+/// The cookies of every site, shared by all requests and saved between runs
+pub struct CookieJar {
+    /// Cookies in the order they were created
+    cookies: Mutex<Vec<Cookie>>,
+    /// The file persistent cookies are saved to, if there is a home directory
+    path: Option<PathBuf>,
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        let path = env::home_dir().map(|home| home.join(".config").join("browser").join("cookies.txt"));
+
+        let mut cookies = Vec::new();
+        if let Some(ref path) = path {
+            let mut data = String::new();
+            if let Ok(mut file) = File::open(path) {
+                match file.read_to_string(&mut data) {
+                    Ok(_) => {
+                        let time = now();
+                        for line in data.lines() {
+                            if let Some(cookie) = Cookie::from_line(line) {
+                                if ! cookie.expired(time) {
+                                    cookies.push(cookie);
+                                }
+                            }
+                        }
+                    },
+                    Err(err) => println!("Failed to read {}: {}", path.display(), err)
+                }
+            }
+        }
+
+        CookieJar {
+            cookies: Mutex::new(cookies),
+            path: path,
+        }
+    }
+
+    /// Store the cookies from the Set-Cookie headers of a response to a URL
+    pub fn set(&self, url: &Url, headers: &[Vec<u8>]) {
+        let time = now();
+        let mut persistent = false;
+        {
+            let mut cookies = self.cookies.lock().unwrap();
+            for header in headers.iter() {
+                let cookie = match Cookie::parse(&String::from_utf8_lossy(header), url) {
+                    Some(cookie) => cookie,
+                    None => continue
+                };
+                persistent = persistent || cookie.expires.is_some();
+
+                let existing = cookies.iter().position(|old| old.name == cookie.name && old.domain == cookie.domain && old.path == cookie.path);
+                if let Some(i) = existing {
+                    persistent = persistent || cookies[i].expires.is_some();
+                    // Setting an expired cookie is how sites delete one
+                    if cookie.expired(time) {
+                        cookies.remove(i);
+                    } else {
+                        cookies[i] = cookie;
+                    }
+                } else if ! cookie.expired(time) {
+                    cookies.push(cookie);
+                }
+            }
+        }
+
+        if persistent {
+            self.save();
+        }
+    }
+
+    /// The value of the Cookie header for a request to a URL, if any cookies apply
+    pub fn header(&self, url: &Url) -> Option<String> {
+        let time = now();
+        let cookies = self.cookies.lock().unwrap();
+        let mut matching: Vec<&Cookie> = cookies.iter().filter(|cookie| ! cookie.expired(time) && cookie.matches(url)).collect();
+        if matching.is_empty() {
+            return None;
+        }
+
+        // Longer paths first, then the oldest, which the stable sort keeps from the jar order
+        matching.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        let pairs: Vec<String> = matching.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect();
+        Some(pairs.join("; "))
+    }
+
+    /// Every cookie that has not expired, sorted by domain
+    pub fn list(&self) -> Vec<Cookie> {
+        let time = now();
+        let mut cookies: Vec<Cookie> = self.cookies.lock().unwrap().iter().filter(|cookie| ! cookie.expired(time)).cloned().collect();
+        cookies.sort_by(|a, b| (&a.domain, &a.name).cmp(&(&b.domain, &b.name)));
+        cookies
+    }
+
+    /// Remove every cookie, including those saved to disk
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+        self.save();
+    }
+
+    /// Write the persistent cookies out, dropping session cookies
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return
+        };
+
+        let mut data = String::from("# Netscape HTTP Cookie File\n");
+        let time = now();
+        for cookie in self.cookies.lock().unwrap().iter() {
+            if cookie.expires.is_some() && ! cookie.expired(time) {
+                data.push_str(&cookie.line());
+            }
+        }
+
+        // Written to the side and moved into place, so that a crash cannot leave half a jar
+        let temp = path.with_extension("txt.tmp");
+        let _ = fs::remove_file(&temp);
+        let result = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(())
+        }.and_then(|_| create_private(&temp))
+            .and_then(|mut file| file.write_all(data.as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&temp, path));
+        if let Err(err) = result {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Create a file only its owner can read, since cookies log in to sites
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
@@ -0,0 +1,1010 @@
+use html5ever::rcdom::{Element, Handle};
+use orbclient::Color;
+
+/// Built in defaults, replacing the per tag looks that used to be hard coded in walk
+pub static USER_AGENT_CSS: &'static str = "
+html, body, div, p, h1, h2, h3, h4, h5, h6, ul, ol, li, dl, dt, dd, form,
+hr, pre, blockquote, center, address, article, aside, footer, header, main, nav,
+section, figure, figcaption, fieldset, details, summary, xmp, listing, plaintext { display: block }
+li { display: list-item }
+head, title, link, meta, script, style, template, noscript { display: none }
+body { margin: 8px }
+p, dl { margin: 1em 0 }
+h1 { font-size: 2em; font-weight: bold; margin: 0.67em 0 }
+h2 { font-size: 1.5em; font-weight: bold; margin: 0.83em 0 }
+h3 { font-size: 1.17em; font-weight: bold; margin: 1em 0 }
+h4 { font-size: 1em; font-weight: bold; margin: 1.33em 0 }
+h5 { font-size: 0.83em; font-weight: bold; margin: 1.67em 0 }
+h6 { font-size: 0.67em; font-weight: bold; margin: 2.33em 0 }
+b, strong, th, dt { font-weight: bold }
+i, em, cite, var, dfn, address { font-style: italic }
+u, ins { text-decoration: underline }
+s, strike, del { text-decoration: line-through }
+pre, xmp, listing, plaintext, code, kbd, samp, tt { font-family: monospace }
+pre, xmp, listing, plaintext { white-space: pre; margin: 1em 0 }
+nobr { white-space: nowrap }
+sub { vertical-align: sub; font-size: smaller }
+sup { vertical-align: super; font-size: smaller }
+ul, ol, menu, dir { margin: 1em 0; padding-left: 40px }
+ul, menu, dir { list-style-type: disc }
+ol { list-style-type: decimal }
+ul ul, ol ul, ul menu, ol menu { list-style-type: circle }
+ul ul ul, ul ol ul, ol ul ul, ol ol ul { list-style-type: square }
+ul ul, ul ol, ol ul, ol ol { margin-top: 0; margin-bottom: 0 }
+blockquote, figure { margin: 1em 40px }
+hr { margin: 0.5em 0; border: 1px solid #a0a0a0 }
+small { font-size: smaller }
+big { font-size: larger }
+a:link { color: #0000ff; text-decoration: underline }
+center { text-align: center }
+dd { margin-left: 40px }
+table { display: table; border-spacing: 2px }
+caption { display: table-caption; text-align: center }
+thead, tbody, tfoot { display: table-row-group }
+tr { display: table-row }
+td, th { display: table-cell; padding: 1px }
+th { text-align: center }
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Display {
+    Block,
+    Inline,
+    ListItem,
+    None,
+    Table,
+    TableCaption,
+    TableRowGroup,
+    TableRow,
+    TableCell,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhiteSpace {
+    /// Runs of whitespace collapse and lines wrap
+    Normal,
+    /// Runs of whitespace collapse but lines never wrap
+    NoWrap,
+    /// Whitespace and line breaks are kept as written
+    Pre,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerticalAlign {
+    Baseline,
+    Sub,
+    Super,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListStyle {
+    None,
+    Disc,
+    Circle,
+    Square,
+    Decimal,
+    LowerAlpha,
+    UpperAlpha,
+    LowerRoman,
+    UpperRoman,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BorderStyle {
+    None,
+    Solid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Length {
+    Auto,
+    Px(f32),
+    Percent(f32),
+}
+
+impl Length {
+    /// Convert to pixels, using reference for percentages
+    pub fn resolve(&self, reference: i32) -> i32 {
+        match *self {
+            Length::Auto => 0,
+            Length::Px(px) => px.round() as i32,
+            Length::Percent(percent) => (reference as f32 * percent / 100.0).round() as i32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edges {
+    pub top: Length,
+    pub right: Length,
+    pub bottom: Length,
+    pub left: Length,
+}
+
+impl Edges {
+    pub fn zero() -> Edges {
+        Edges {
+            top: Length::Px(0.0),
+            right: Length::Px(0.0),
+            bottom: Length::Px(0.0),
+            left: Length::Px(0.0),
+        }
+    }
+}
+
+/// The computed style of a node
+#[derive(Clone)]
+pub struct Style {
+    pub color: Color,
+    pub background: Option<Color>,
+    pub font_size: f32,
+    pub bold: bool,
+    pub italic: bool,
+    pub monospace: bool,
+    pub underline: bool,
+    pub line_through: bool,
+    pub white_space: WhiteSpace,
+    pub vertical_align: VerticalAlign,
+    pub list_style: ListStyle,
+    pub display: Display,
+    pub margin: Edges,
+    pub padding: Edges,
+    pub text_align: TextAlign,
+    pub width: Length,
+    pub max_width: Length,
+    pub border_width: i32,
+    pub border_style: BorderStyle,
+    pub border_color: Option<Color>,
+    pub border_spacing: i32,
+    pub border_collapse: bool,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            color: Color::rgb(0, 0, 0),
+            background: None,
+            font_size: 16.0,
+            bold: false,
+            italic: false,
+            monospace: false,
+            underline: false,
+            line_through: false,
+            white_space: WhiteSpace::Normal,
+            vertical_align: VerticalAlign::Baseline,
+            list_style: ListStyle::Disc,
+            display: Display::Inline,
+            margin: Edges::zero(),
+            padding: Edges::zero(),
+            text_align: TextAlign::Left,
+            width: Length::Auto,
+            max_width: Length::Auto,
+            border_width: 3,
+            border_style: BorderStyle::None,
+            border_color: None,
+            border_spacing: 0,
+            border_collapse: false,
+        }
+    }
+}
+
+impl Style {
+    /// A fresh style for a child of parent, with only the inherited properties kept. Text
+    /// decorations and vertical alignment are kept too, as they carry over to the text inside.
+    pub fn inherit(parent: &Style) -> Style {
+        Style {
+            color: parent.color,
+            font_size: parent.font_size,
+            bold: parent.bold,
+            italic: parent.italic,
+            monospace: parent.monospace,
+            underline: parent.underline,
+            line_through: parent.line_through,
+            white_space: parent.white_space,
+            vertical_align: parent.vertical_align,
+            list_style: parent.list_style,
+            text_align: parent.text_align,
+            border_spacing: parent.border_spacing,
+            border_collapse: parent.border_collapse,
+            ..Style::default()
+        }
+    }
+
+    /// The visible border width and color, if any
+    pub fn border(&self) -> Option<(i32, Color)> {
+        if self.border_style == BorderStyle::Solid && self.border_width > 0 {
+            Some((self.border_width, self.border_color.unwrap_or(self.color)))
+        } else {
+            None
+        }
+    }
+
+    fn apply_font(&mut self, decl: &Declaration, parent: &Style) {
+        match decl.name.as_str() {
+            "font-size" => if decl.value == "inherit" {
+                self.font_size = parent.font_size;
+            } else if let Some(size) = parse_font_size(&decl.value, parent.font_size) {
+                self.font_size = size;
+            },
+            "font-weight" => if decl.value == "inherit" {
+                self.bold = parent.bold;
+            } else if let Some(bold) = parse_font_weight(&decl.value) {
+                self.bold = bold;
+            },
+            "font-style" => match decl.value.as_str() {
+                "italic" | "oblique" => self.italic = true,
+                "normal" => self.italic = false,
+                "inherit" => self.italic = parent.italic,
+                _ => ()
+            },
+            "font-family" => if decl.value == "inherit" {
+                self.monospace = parent.monospace;
+            } else {
+                self.monospace = is_monospace(&decl.value);
+            },
+            "font" => {
+                for part in decl.value.split_whitespace() {
+                    let size = part.split('/').next().unwrap_or("");
+                    if let Some(bold) = parse_font_weight(part) {
+                        self.bold = bold;
+                    } else if part == "italic" || part == "oblique" {
+                        self.italic = true;
+                    } else if let Some(size) = parse_font_size(size, parent.font_size) {
+                        self.font_size = size;
+                    }
+                }
+                self.monospace = is_monospace(&decl.value);
+            },
+            _ => ()
+        }
+    }
+
+    fn apply(&mut self, decl: &Declaration, parent: &Style) {
+        let value = decl.value.as_str();
+        let font_size = self.font_size;
+        match decl.name.as_str() {
+            "color" => if value == "inherit" {
+                self.color = parent.color;
+            } else if let Some(color) = parse_color(value) {
+                self.color = color;
+            },
+            "background-color" | "background" => if value == "inherit" {
+                self.background = parent.background;
+            } else if value == "none" || value == "transparent" {
+                self.background = None;
+            } else {
+                for part in value.split_whitespace() {
+                    if let Some(color) = parse_color(part) {
+                        self.background = if color.data >> 24 == 0 {
+                            None
+                        } else {
+                            Some(color)
+                        };
+                        break;
+                    }
+                }
+            },
+            "display" => match value {
+                "block" | "flex" | "grid" => self.display = Display::Block,
+                "list-item" => self.display = Display::ListItem,
+                "inline" | "inline-block" | "inline-flex" => self.display = Display::Inline,
+                "table" | "inline-table" => self.display = Display::Table,
+                "table-caption" => self.display = Display::TableCaption,
+                "table-row-group" | "table-header-group" | "table-footer-group" => self.display = Display::TableRowGroup,
+                "table-row" => self.display = Display::TableRow,
+                "table-cell" => self.display = Display::TableCell,
+                "none" => self.display = Display::None,
+                "inherit" => self.display = parent.display,
+                _ => ()
+            },
+            "text-align" => match value {
+                "left" | "justify" | "start" => self.text_align = TextAlign::Left,
+                "center" => self.text_align = TextAlign::Center,
+                "right" | "end" => self.text_align = TextAlign::Right,
+                "inherit" => self.text_align = parent.text_align,
+                _ => ()
+            },
+            "text-decoration" | "text-decoration-line" => if value == "inherit" {
+                self.underline = parent.underline;
+                self.line_through = parent.line_through;
+            } else if value.split_whitespace().any(|part| part == "none") {
+                self.underline = false;
+                self.line_through = false;
+            } else {
+                for part in value.split_whitespace() {
+                    match part {
+                        "underline" => self.underline = true,
+                        "line-through" => self.line_through = true,
+                        _ => ()
+                    }
+                }
+            },
+            "white-space" => match value {
+                "normal" => self.white_space = WhiteSpace::Normal,
+                "nowrap" => self.white_space = WhiteSpace::NoWrap,
+                "pre" | "pre-wrap" | "pre-line" | "break-spaces" => self.white_space = WhiteSpace::Pre,
+                "inherit" => self.white_space = parent.white_space,
+                _ => ()
+            },
+            "vertical-align" => match value {
+                "sub" => self.vertical_align = VerticalAlign::Sub,
+                "super" => self.vertical_align = VerticalAlign::Super,
+                "baseline" => self.vertical_align = VerticalAlign::Baseline,
+                "inherit" => self.vertical_align = parent.vertical_align,
+                _ => ()
+            },
+            "list-style-type" | "list-style" => if value == "inherit" {
+                self.list_style = parent.list_style;
+            } else {
+                for part in value.split_whitespace() {
+                    if let Some(list_style) = parse_list_style(part) {
+                        self.list_style = list_style;
+                    }
+                }
+            },
+            "width" => if let Some(length) = parse_length(value, font_size) {
+                self.width = length;
+            },
+            "max-width" => if value == "none" {
+                self.max_width = Length::Auto;
+            } else if let Some(length) = parse_length(value, font_size) {
+                self.max_width = length;
+            },
+            "border" => for part in value.split_whitespace() {
+                if let Some(width) = parse_border_width(part, font_size) {
+                    self.border_width = width;
+                } else if let Some(border_style) = parse_border_style(part) {
+                    self.border_style = border_style;
+                } else if let Some(color) = parse_color(part) {
+                    self.border_color = Some(color);
+                }
+            },
+            "border-width" => if let Some(width) = value.split_whitespace().next().and_then(|part| parse_border_width(part, font_size)) {
+                self.border_width = width;
+            },
+            "border-style" => if let Some(border_style) = value.split_whitespace().next().and_then(parse_border_style) {
+                self.border_style = border_style;
+            },
+            "border-color" => if let Some(color) = value.split_whitespace().next().and_then(parse_color) {
+                self.border_color = Some(color);
+            },
+            "border-spacing" => if let Some(length) = value.split_whitespace().next().and_then(|part| parse_length(part, font_size)) {
+                self.border_spacing = length.resolve(0);
+            },
+            "border-collapse" => match value {
+                "collapse" => self.border_collapse = true,
+                "separate" => self.border_collapse = false,
+                _ => ()
+            },
+            "margin" => if let Some(edges) = parse_edges(value, font_size) {
+                self.margin = edges;
+            },
+            "padding" => if let Some(edges) = parse_edges(value, font_size) {
+                self.padding = edges;
+            },
+            "margin-top" => if let Some(length) = parse_length(value, font_size) { self.margin.top = length; },
+            "margin-right" => if let Some(length) = parse_length(value, font_size) { self.margin.right = length; },
+            "margin-bottom" => if let Some(length) = parse_length(value, font_size) { self.margin.bottom = length; },
+            "margin-left" => if let Some(length) = parse_length(value, font_size) { self.margin.left = length; },
+            "padding-top" => if let Some(length) = parse_length(value, font_size) { self.padding.top = length; },
+            "padding-right" => if let Some(length) = parse_length(value, font_size) { self.padding.right = length; },
+            "padding-bottom" => if let Some(length) = parse_length(value, font_size) { self.padding.bottom = length; },
+            "padding-left" => if let Some(length) = parse_length(value, font_size) { self.padding.left = length; },
+            _ => ()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Origin {
+    UserAgent,
+    /// The user stylesheet and dark mode colours
+    User,
+    Author,
+}
+
+impl Origin {
+    /// Cascade rank, where important declarations reverse the origin order
+    fn rank(&self, important: bool) -> u8 {
+        match (*self, important) {
+            (Origin::UserAgent, false) => 0,
+            (Origin::User, false) => 1,
+            (Origin::Author, false) => 2,
+            (Origin::Author, true) => 3,
+            (Origin::User, true) => 4,
+            (Origin::UserAgent, true) => 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Declaration {
+    pub name: String,
+    pub value: String,
+    pub important: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+#[derive(Clone, Debug)]
+struct Compound {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+    link: bool,
+}
+
+impl Compound {
+    fn parse(token: &str) -> Option<Compound> {
+        let mut compound = Compound {
+            tag: None,
+            id: None,
+            classes: Vec::new(),
+            link: false,
+        };
+
+        let mut kind = ' ';
+        let mut name = String::new();
+        for c in token.chars().chain(Some('\0')) {
+            match c {
+                '.' | '#' | ':' | '[' | '\0' => {
+                    match kind {
+                        ' ' => if ! name.is_empty() && name != "*" {
+                            compound.tag = Some(name.to_lowercase());
+                        },
+                        '.' => compound.classes.push(name.clone()),
+                        '#' => compound.id = Some(name.clone()),
+                        ':' => match name.as_str() {
+                            "link" | "visited" => compound.link = true,
+                            // Dynamic pseudo classes and pseudo elements never match a static page
+                            _ => return None
+                        },
+                        // Attribute selectors are not supported
+                        _ => return None
+                    }
+
+                    kind = c;
+                    name.clear();
+                },
+                _ => name.push(c)
+            }
+        }
+
+        Some(compound)
+    }
+
+    fn specificity(&self) -> u32 {
+        let ids = if self.id.is_some() { 1 } else { 0 };
+        let classes = self.classes.len() as u32 + if self.link { 1 } else { 0 };
+        let tags = if self.tag.is_some() { 1 } else { 0 };
+        ids * 10000 + classes * 100 + tags
+    }
+
+    fn matches(&self, handle: &Handle) -> bool {
+        let node = handle.borrow();
+        if let Element(ref name, _, ref attrs) = node.node {
+            if let Some(ref tag) = self.tag {
+                if &*name.local != tag.as_str() {
+                    return false;
+                }
+            }
+
+            let mut id_found = self.id.is_none();
+            let mut classes_found = 0;
+            let mut href_found = false;
+            for attr in attrs.iter() {
+                match &*attr.name.local {
+                    "id" => if let Some(ref id) = self.id {
+                        id_found = &*attr.value == id.as_str();
+                    },
+                    "class" => for class in attr.value.split_whitespace() {
+                        if self.classes.iter().any(|c| c == class) {
+                            classes_found += 1;
+                        }
+                    },
+                    "href" => href_found = true,
+                    _ => ()
+                }
+            }
+
+            id_found && classes_found >= self.classes.len() && (href_found || ! self.link)
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Selector {
+    parts: Vec<(Combinator, Compound)>,
+}
+
+impl Selector {
+    fn parse(text: &str) -> Option<Selector> {
+        let mut parts = Vec::new();
+        let mut combinator = Combinator::Descendant;
+        for token in text.replace(">", " > ").split_whitespace() {
+            match token {
+                ">" => combinator = Combinator::Child,
+                // Sibling combinators are not supported
+                "+" | "~" => return None,
+                _ => match Compound::parse(token) {
+                    Some(compound) => {
+                        parts.push((combinator, compound));
+                        combinator = Combinator::Descendant;
+                    },
+                    None => return None
+                }
+            }
+        }
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(Selector {
+                parts: parts
+            })
+        }
+    }
+
+    fn specificity(&self) -> u32 {
+        self.parts.iter().map(|part| part.1.specificity()).sum()
+    }
+
+    fn matches(&self, handle: &Handle) -> bool {
+        let last = self.parts.len() - 1;
+        self.parts[last].1.matches(handle) && matches_ancestors(&self.parts, last, handle)
+    }
+}
+
+fn parent_of(handle: &Handle) -> Option<Handle> {
+    handle.borrow().parent.as_ref().and_then(|parent| parent.upgrade())
+}
+
+/// parts[i] matched handle, so check the compounds before it against the ancestors
+fn matches_ancestors(parts: &[(Combinator, Compound)], i: usize, handle: &Handle) -> bool {
+    if i == 0 {
+        return true;
+    }
+
+    let mut current = parent_of(handle);
+    match parts[i].0 {
+        Combinator::Child => match current {
+            Some(parent) => parts[i - 1].1.matches(&parent) && matches_ancestors(parts, i - 1, &parent),
+            None => false
+        },
+        Combinator::Descendant => {
+            while let Some(ancestor) = current {
+                if parts[i - 1].1.matches(&ancestor) && matches_ancestors(parts, i - 1, &ancestor) {
+                    return true;
+                }
+                current = parent_of(&ancestor);
+            }
+            false
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    origin: Origin,
+    selectors: Vec<Selector>,
+    declarations: Vec<Declaration>,
+}
+
+/// All rules that apply to a document, in cascade order
+pub struct Stylesheet {
+    rules: Vec<Rule>,
+    /// Do prefers-color-scheme: dark media queries match
+    dark: bool,
+}
+
+impl Stylesheet {
+    pub fn new(dark: bool) -> Stylesheet {
+        Stylesheet {
+            rules: Vec::new(),
+            dark: dark,
+        }
+    }
+
+    /// A stylesheet preloaded with the browser defaults
+    pub fn user_agent(dark: bool) -> Stylesheet {
+        let mut stylesheet = Stylesheet::new(dark);
+        stylesheet.add(USER_AGENT_CSS, Origin::UserAgent);
+        stylesheet
+    }
+
+    /// Parse css and append its rules
+    pub fn add(&mut self, css: &str, origin: Origin) {
+        let css = strip_comments(css);
+        parse_rules(&css, origin, self.dark, &mut self.rules);
+    }
+
+    /// Compute the cascaded style of an element
+    pub fn style(&self, handle: &Handle, parent: &Style) -> Style {
+        let inline = inline_declarations(handle);
+
+        let mut matched: Vec<(u8, u32, usize, &Declaration)> = Vec::new();
+        for (order, rule) in self.rules.iter().enumerate() {
+            let mut specificity = None;
+            for selector in rule.selectors.iter() {
+                if selector.matches(handle) {
+                    let s = selector.specificity();
+                    if specificity.map_or(true, |best| s > best) {
+                        specificity = Some(s);
+                    }
+                }
+            }
+
+            if let Some(specificity) = specificity {
+                for decl in rule.declarations.iter() {
+                    matched.push((rule.origin.rank(decl.important), specificity, order, decl));
+                }
+            }
+        }
+
+        for decl in inline.iter() {
+            matched.push((Origin::Author.rank(decl.important), 1000000, self.rules.len(), decl));
+        }
+
+        matched.sort_by_key(|m| (m.0, m.1, m.2));
+
+        let mut style = Style::inherit(parent);
+        // Font sizes go first, so that em lengths resolve against the final size
+        for m in matched.iter() {
+            style.apply_font(m.3, parent);
+        }
+        for m in matched.iter() {
+            style.apply(m.3, parent);
+        }
+        style
+    }
+}
+
+fn inline_declarations(handle: &Handle) -> Vec<Declaration> {
+    let node = handle.borrow();
+    if let Element(_, _, ref attrs) = node.node {
+        for attr in attrs.iter() {
+            if &*attr.name.local == "style" {
+                return parse_declarations(&attr.value);
+            }
+        }
+    }
+    Vec::new()
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut string = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        string.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => ""
+        };
+    }
+    string.push_str(rest);
+
+    // HTML comment markers are allowed around the contents of style elements
+    string.replace("<!--", " ").replace("-->", " ")
+}
+
+/// Find the index of the brace closing the one at open, or the end of the string
+fn matching_brace(css: &str, open: usize) -> usize {
+    let mut depth = 0;
+    for (i, c) in css[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return open + i;
+                }
+            },
+            _ => ()
+        }
+    }
+    css.len()
+}
+
+fn media_matches(query: &str, dark: bool) -> bool {
+    let query = query.trim().to_lowercase();
+    query.is_empty() || query.split(',').any(|medium| {
+        let medium = medium.trim();
+        if medium.contains("prefers-color-scheme") {
+            (medium.starts_with("screen") || medium.starts_with("all") || medium.starts_with('('))
+                && medium.contains("dark") == dark
+        } else {
+            medium.starts_with("screen") || medium.starts_with("all")
+        }
+    })
+}
+
+fn parse_rules(css: &str, origin: Origin, dark: bool, rules: &mut Vec<Rule>) {
+    let mut rest = css.trim_left();
+    while ! rest.is_empty() {
+        let open = match rest.find('{') {
+            Some(open) => open,
+            None => break
+        };
+
+        if rest.starts_with('@') {
+            if let Some(semi) = rest.find(';') {
+                if semi < open {
+                    // Statement at-rules like @import and @charset
+                    rest = rest[semi + 1..].trim_left();
+                    continue;
+                }
+            }
+        }
+
+        let close = matching_brace(rest, open);
+        let prelude = rest[..open].trim();
+        let body = &rest[open + 1..close];
+
+        if prelude.starts_with("@media") {
+            if media_matches(&prelude[6..], dark) {
+                parse_rules(body, origin, dark, rules);
+            }
+        } else if ! prelude.starts_with('@') {
+            let selectors: Vec<Selector> = prelude.split(',').filter_map(Selector::parse).collect();
+            if ! selectors.is_empty() {
+                rules.push(Rule {
+                    origin: origin,
+                    selectors: selectors,
+                    declarations: parse_declarations(body),
+                });
+            }
+        }
+
+        rest = if close < rest.len() {
+            rest[close + 1..].trim_left()
+        } else {
+            ""
+        };
+    }
+}
+
+pub fn parse_declarations(text: &str) -> Vec<Declaration> {
+    let mut declarations = Vec::new();
+    for part in text.split(';') {
+        if let Some(colon) = part.find(':') {
+            let name = part[..colon].trim().to_lowercase();
+            let mut value = part[colon + 1..].trim();
+            let mut important = false;
+            if let Some(bang) = value.find('!') {
+                important = value[bang + 1..].trim().to_lowercase() == "important";
+                value = value[..bang].trim();
+            }
+
+            if ! name.is_empty() && ! value.is_empty() {
+                declarations.push(Declaration {
+                    name: name,
+                    value: value.to_lowercase(),
+                    important: important,
+                });
+            }
+        }
+    }
+    declarations
+}
+
+pub fn parse_length(value: &str, font_size: f32) -> Option<Length> {
+    let value = value.trim();
+    let number = |suffix: &str| value[..value.len() - suffix.len()].trim().parse::<f32>().ok();
+
+    if value == "auto" {
+        Some(Length::Auto)
+    } else if value.ends_with('%') {
+        number("%").map(Length::Percent)
+    } else if value.ends_with("px") {
+        number("px").map(Length::Px)
+    } else if value.ends_with("rem") {
+        number("rem").map(|n| Length::Px(n * 16.0))
+    } else if value.ends_with("em") {
+        number("em").map(|n| Length::Px(n * font_size))
+    } else if value.ends_with("ex") {
+        number("ex").map(|n| Length::Px(n * font_size / 2.0))
+    } else if value.ends_with("pt") {
+        number("pt").map(|n| Length::Px(n * 4.0 / 3.0))
+    } else if value.ends_with("pc") {
+        number("pc").map(|n| Length::Px(n * 16.0))
+    } else if value.ends_with("in") {
+        number("in").map(|n| Length::Px(n * 96.0))
+    } else if value.ends_with("cm") {
+        number("cm").map(|n| Length::Px(n * 96.0 / 2.54))
+    } else if value.ends_with("mm") {
+        number("mm").map(|n| Length::Px(n * 96.0 / 25.4))
+    } else {
+        value.parse::<f32>().ok().map(Length::Px)
+    }
+}
+
+/// Parse a one to four value margin or padding shorthand
+fn parse_edges(value: &str, font_size: f32) -> Option<Edges> {
+    let mut lengths = Vec::new();
+    for part in value.split_whitespace() {
+        match parse_length(part, font_size) {
+            Some(length) => lengths.push(length),
+            None => return None
+        }
+    }
+
+    match lengths.len() {
+        1 => Some(Edges { top: lengths[0], right: lengths[0], bottom: lengths[0], left: lengths[0] }),
+        2 => Some(Edges { top: lengths[0], right: lengths[1], bottom: lengths[0], left: lengths[1] }),
+        3 => Some(Edges { top: lengths[0], right: lengths[1], bottom: lengths[2], left: lengths[1] }),
+        4 => Some(Edges { top: lengths[0], right: lengths[1], bottom: lengths[2], left: lengths[3] }),
+        _ => None
+    }
+}
+
+fn parse_border_width(value: &str, font_size: f32) -> Option<i32> {
+    match value {
+        "thin" => Some(1),
+        "medium" => Some(3),
+        "thick" => Some(5),
+        _ => match parse_length(value, font_size) {
+            Some(Length::Px(px)) if px >= 0.0 => Some(px.round() as i32),
+            _ => None
+        }
+    }
+}
+
+fn parse_border_style(value: &str) -> Option<BorderStyle> {
+    match value {
+        "none" | "hidden" => Some(BorderStyle::None),
+        "solid" | "dotted" | "dashed" | "double" | "groove" | "ridge" | "inset" | "outset" => Some(BorderStyle::Solid),
+        _ => None
+    }
+}
+
+fn parse_font_size(value: &str, parent_size: f32) -> Option<f32> {
+    match value {
+        "xx-small" => Some(9.0),
+        "x-small" => Some(10.0),
+        "small" => Some(13.0),
+        "medium" => Some(16.0),
+        "large" => Some(18.0),
+        "x-large" => Some(24.0),
+        "xx-large" => Some(32.0),
+        "smaller" => Some(parent_size * 5.0 / 6.0),
+        "larger" => Some(parent_size * 6.0 / 5.0),
+        _ => match parse_length(value, parent_size) {
+            Some(Length::Px(px)) if px > 0.0 => Some(px),
+            Some(Length::Percent(percent)) if percent > 0.0 => Some(parent_size * percent / 100.0),
+            _ => None
+        }
+    }
+}
+
+fn parse_list_style(value: &str) -> Option<ListStyle> {
+    match value {
+        "none" => Some(ListStyle::None),
+        "disc" => Some(ListStyle::Disc),
+        "circle" => Some(ListStyle::Circle),
+        "square" => Some(ListStyle::Square),
+        "decimal" | "decimal-leading-zero" => Some(ListStyle::Decimal),
+        "lower-alpha" | "lower-latin" => Some(ListStyle::LowerAlpha),
+        "upper-alpha" | "upper-latin" => Some(ListStyle::UpperAlpha),
+        "lower-roman" => Some(ListStyle::LowerRoman),
+        "upper-roman" => Some(ListStyle::UpperRoman),
+        _ => None
+    }
+}
+
+/// Does a font family list ask for a fixed width face
+fn is_monospace(value: &str) -> bool {
+    value.split(',').any(|family| {
+        let family = family.trim().trim_matches(|c| c == '"' || c == '\'');
+        family.ends_with("monospace") || family.ends_with(" mono") || family.starts_with("courier") ||
+            family == "consolas" || family == "menlo" || family == "monaco"
+    })
+}
+
+fn parse_font_weight(value: &str) -> Option<bool> {
+    match value {
+        "bold" | "bolder" => Some(true),
+        "normal" | "lighter" => Some(false),
+        _ => value.parse::<u32>().ok().map(|weight| weight >= 600)
+    }
+}
+
+pub fn parse_color(value: &str) -> Option<Color> {
+    let value = value.trim();
+
+    if value.starts_with('#') {
+        let hex = &value[1..];
+        if ! hex.chars().all(|c| c.is_digit(16)) {
+            return None;
+        }
+        let digit = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok().map(|n| n * 17);
+        let pair = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        return match hex.len() {
+            3 | 4 => match (digit(0), digit(1), digit(2)) {
+                (Some(r), Some(g), Some(b)) => Some(Color::rgb(r, g, b)),
+                _ => None
+            },
+            6 | 8 => match (pair(0), pair(2), pair(4)) {
+                (Some(r), Some(g), Some(b)) => Some(Color::rgb(r, g, b)),
+                _ => None
+            },
+            _ => None
+        };
+    }
+
+    if value.starts_with("rgb") {
+        let open = match value.find('(') {
+            Some(open) => open,
+            None => return None
+        };
+        let inner = value[open + 1..].trim_right_matches(')');
+        let mut channels = Vec::new();
+        for part in inner.split(|c: char| c == ',' || c == ' ' || c == '/').filter(|part| ! part.is_empty()) {
+            let channel = if part.ends_with('%') {
+                part[..part.len() - 1].parse::<f32>().ok().map(|n| n * 255.0 / 100.0)
+            } else {
+                part.parse::<f32>().ok()
+            };
+            match channel {
+                Some(channel) => channels.push(channel),
+                None => return None
+            }
+        }
+
+        let clamp = |n: f32| if n < 0.0 { 0 } else if n > 255.0 { 255 } else { n.round() as u8 };
+        return match channels.len() {
+            3 => Some(Color::rgb(clamp(channels[0]), clamp(channels[1]), clamp(channels[2]))),
+            4 => {
+                let alpha = if channels[3] <= 1.0 { channels[3] * 255.0 } else { channels[3] };
+                Some(Color::rgba(clamp(channels[0]), clamp(channels[1]), clamp(channels[2]), clamp(alpha)))
+            },
+            _ => None
+        };
+    }
+
+    let (r, g, b) = match value {
+        "transparent" => return Some(Color::rgba(0, 0, 0, 0)),
+        "black" => (0, 0, 0),
+        "silver" => (192, 192, 192),
+        "gray" | "grey" => (128, 128, 128),
+        "darkgray" | "darkgrey" => (169, 169, 169),
+        "lightgray" | "lightgrey" => (211, 211, 211),
+        "white" => (255, 255, 255),
+        "maroon" => (128, 0, 0),
+        "red" => (255, 0, 0),
+        "darkred" => (139, 0, 0),
+        "purple" => (128, 0, 128),
+        "fuchsia" | "magenta" => (255, 0, 255),
+        "green" => (0, 128, 0),
+        "darkgreen" => (0, 100, 0),
+        "lime" => (0, 255, 0),
+        "olive" => (128, 128, 0),
+        "yellow" => (255, 255, 0),
+        "gold" => (255, 215, 0),
+        "orange" => (255, 165, 0),
+        "brown" => (165, 42, 42),
+        "pink" => (255, 192, 203),
+        "navy" => (0, 0, 128),
+        "blue" => (0, 0, 255),
+        "darkblue" => (0, 0, 139),
+        "teal" => (0, 128, 128),
+        "aqua" | "cyan" => (0, 255, 255),
+        "whitesmoke" => (245, 245, 245),
+        "beige" => (245, 245, 220),
+        "ivory" => (255, 255, 240),
+        _ => return None
+    };
+    Some(Color::rgb(r, g, b))
+}
@@ -0,0 +1,290 @@
+use std::cmp;
+use std::io::{self, Read};
+use std::str;
+
+use inflate::InflateStream;
+
+/// The character encodings pages are decoded from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charset {
+    Utf8,
+    /// Also used for pages labelled as Latin-1, as every other browser does, since the
+    /// characters that differ are control codes nobody means to send
+    Windows1252,
+    Utf16Le,
+    Utf16Be,
+}
+
+/// The characters of Windows-1252 from 0x80 to 0x9F, where it differs from Latin-1
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+impl Charset {
+    /// Look up a charset by one of its labels
+    pub fn from_label(label: &str) -> Option<Charset> {
+        match &label.trim().trim_matches(|c| c == '"' || c == '\'').to_lowercase()[..] {
+            "utf-8" | "utf8" | "unicode-1-1-utf-8" => Some(Charset::Utf8),
+            "windows-1252" | "cp1252" | "x-cp1252" | "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1"
+                | "cp819" | "ibm819" | "iso-ir-100" | "csisolatin1" | "us-ascii" | "ascii" => Some(Charset::Windows1252),
+            "utf-16" | "utf-16le" => Some(Charset::Utf16Le),
+            "utf-16be" => Some(Charset::Utf16Be),
+            _ => None
+        }
+    }
+
+    /// Decode text, replacing anything invalid
+    pub fn decode(&self, data: &[u8]) -> String {
+        match *self {
+            Charset::Utf8 => {
+                let data = if data.starts_with(b"\xEF\xBB\xBF") { &data[3..] } else { data };
+                String::from_utf8_lossy(data).into_owned()
+            },
+            Charset::Windows1252 => data.iter().map(|&b| {
+                if b >= 0x80 && b < 0xA0 {
+                    WINDOWS_1252[(b - 0x80) as usize]
+                } else {
+                    b as char
+                }
+            }).collect(),
+            Charset::Utf16Le | Charset::Utf16Be => {
+                let little = *self == Charset::Utf16Le;
+                let units: Vec<u16> = data.chunks(2).filter(|pair| pair.len() == 2).map(|pair| {
+                    if little {
+                        pair[0] as u16 | (pair[1] as u16) << 8
+                    } else {
+                        (pair[0] as u16) << 8 | pair[1] as u16
+                    }
+                }).collect();
+                let units = if units.first() == Some(&0xFEFF) { &units[1..] } else { &units[..] };
+                String::from_utf16_lossy(units)
+            }
+        }
+    }
+}
+
+/// Work out the charset of a document from its byte order mark, then the charset parameter of its
+/// Content-Type, then for HTML a meta tag near the start
+pub fn detect_charset(content_type: &str, data: &[u8], html: bool) -> Charset {
+    if data.starts_with(b"\xEF\xBB\xBF") {
+        return Charset::Utf8;
+    } else if data.starts_with(b"\xFF\xFE") {
+        return Charset::Utf16Le;
+    } else if data.starts_with(b"\xFE\xFF") {
+        return Charset::Utf16Be;
+    }
+
+    if let Some(charset) = find_charset(content_type).and_then(Charset::from_label) {
+        return charset;
+    }
+
+    if html {
+        let start = &data[..cmp::min(data.len(), 1024)];
+        if let Some(charset) = meta_charset(start).and_then(|label| Charset::from_label(&label)) {
+            // A meta tag that could be read as ASCII means the page is not really UTF-16
+            return match charset {
+                Charset::Utf16Le | Charset::Utf16Be => Charset::Utf8,
+                charset => charset
+            };
+        }
+    }
+
+    // Unlabelled pages are mostly UTF-8 now, allowing for a sequence cut off at the end
+    match str::from_utf8(data) {
+        Ok(_) => Charset::Utf8,
+        Err(err) if err.valid_up_to() + 4 > data.len() => Charset::Utf8,
+        Err(_) => Charset::Windows1252
+    }
+}
+
+/// The value after "charset=" in a Content-Type or meta content attribute
+fn find_charset(value: &str) -> Option<&str> {
+    let lower = ascii_lowercase(value);
+    lower.find("charset").and_then(|i| {
+        let rest = value[i + "charset".len()..].trim_left();
+        if rest.starts_with('=') {
+            let rest = rest[1..].trim_left().trim_left_matches(|c| c == '"' || c == '\'');
+            let end = rest.find(|c: char| c == ';' || c == '"' || c == '\'' || c == '>' || c == '/' || c.is_whitespace()).unwrap_or(rest.len());
+            Some(&rest[..end])
+        } else {
+            None
+        }
+    })
+}
+
+/// Lowercase only ASCII letters, so that byte offsets stay the same
+pub fn ascii_lowercase(s: &str) -> String {
+    s.chars().map(|c| if c >= 'A' && c <= 'Z' { (c as u8 + 32) as char } else { c }).collect()
+}
+
+/// Find the charset of a <meta charset> or <meta http-equiv="Content-Type"> tag
+fn meta_charset(data: &[u8]) -> Option<String> {
+    // Labels are ASCII, so anything else can be replaced without losing them
+    let text = String::from_utf8_lossy(data);
+    let lower = ascii_lowercase(&text);
+
+    let mut start = 0;
+    while let Some(i) = lower[start..].find("<meta") {
+        let tag_start = start + i;
+        let tag_end = lower[tag_start..].find('>').map_or(lower.len(), |end| tag_start + end);
+        if let Some(charset) = find_charset(&text[tag_start..tag_end]) {
+            return Some(charset.to_string());
+        }
+        start = tag_end;
+    }
+    None
+}
+
+/// Wrap a response body to undo a Content-Encoding
+pub fn content_decoder<'a>(encoding: &str, r: Box<Read + 'a>) -> Result<Box<Read + 'a>, String> {
+    match &encoding.trim().to_lowercase()[..] {
+        "" | "identity" => Ok(r),
+        "gzip" | "x-gzip" => Ok(Box::new(Inflate::new(r, true))),
+        "deflate" => Ok(Box::new(Inflate::new(r, false))),
+        other => Err(format!("Unsupported content encoding {}", other))
+    }
+}
+
+/// Decompresses a gzip or deflate stream as it is read
+struct Inflate<R: Read> {
+    inner: R,
+    /// Is there a gzip header still to be skipped
+    gzip: bool,
+    /// Created once the start of the data has been seen
+    stream: Option<InflateStream>,
+    /// Compressed data not taken by the stream yet
+    input: Vec<u8>,
+    output: Vec<u8>,
+    position: usize,
+    eof: bool,
+}
+
+impl<R: Read> Inflate<R> {
+    fn new(inner: R, gzip: bool) -> Inflate<R> {
+        Inflate {
+            inner: inner,
+            gzip: gzip,
+            stream: None,
+            input: Vec::new(),
+            output: Vec::new(),
+            position: 0,
+            eof: false,
+        }
+    }
+
+    /// Read more compressed data and decompress as much of it as possible
+    fn fill(&mut self) -> io::Result<()> {
+        self.output.clear();
+        self.position = 0;
+
+        let mut buf = [0; 16384];
+        let count = self.inner.read(&mut buf)?;
+        if count == 0 {
+            self.eof = true;
+        }
+        self.input.extend_from_slice(&buf[..count]);
+
+        if self.stream.is_none() {
+            if self.gzip {
+                match gzip_header(&self.input) {
+                    Ok(Some(len)) => {
+                        self.input.drain(..len);
+                        self.stream = Some(InflateStream::new());
+                    },
+                    Ok(None) => (),
+                    Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err))
+                }
+            } else if self.input.len() >= 2 {
+                // Deflate should have a zlib wrapper, but some servers send raw deflate data
+                let zlib = self.input[0] & 0x0F == 8 && (self.input[0] as u16 * 256 + self.input[1] as u16) % 31 == 0;
+                self.stream = Some(if zlib { InflateStream::from_zlib() } else { InflateStream::new() });
+            }
+
+            if self.stream.is_none() {
+                if self.eof && ! self.input.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "compressed data cut off"));
+                }
+                return Ok(());
+            }
+        }
+
+        if let Some(ref mut stream) = self.stream {
+            let mut start = 0;
+            while start < self.input.len() {
+                let (used, output) = stream.update(&self.input[start..]).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                self.output.extend_from_slice(output);
+                // Nothing used means the stream has ended, leaving only the trailer
+                if used == 0 {
+                    start = self.input.len();
+                } else {
+                    start += used;
+                }
+            }
+            self.input.clear();
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Inflate<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.output.len() {
+                let count = cmp::min(buf.len(), self.output.len() - self.position);
+                buf[..count].copy_from_slice(&self.output[self.position..self.position + count]);
+                self.position += count;
+                return Ok(count);
+            }
+            if self.eof {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+    }
+}
+
+/// The length of the gzip header at the start of data, or None if more data is needed
+fn gzip_header(data: &[u8]) -> Result<Option<usize>, String> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+
+    if data.len() < 10 {
+        return Ok(None);
+    }
+    if data[0] != 0x1F || data[1] != 0x8B || data[2] != 8 {
+        return Err("not gzip data".to_string());
+    }
+
+    let flags = data[3];
+    let mut len = 10;
+    if flags & FEXTRA != 0 {
+        if data.len() < len + 2 {
+            return Ok(None);
+        }
+        len += 2 + (data[len] as usize | (data[len + 1] as usize) << 8);
+    }
+    for &flag in [FNAME, FCOMMENT].iter() {
+        if flags & flag != 0 {
+            let end = if len < data.len() { data[len..].iter().position(|&b| b == 0) } else { None };
+            match end {
+                Some(end) => len += end + 1,
+                None => return Ok(None)
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        len += 2;
+    }
+
+    if len <= data.len() {
+        Ok(Some(len))
+    } else {
+        Ok(None)
+    }
+}
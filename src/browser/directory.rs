@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use url::Url;
+
+use about::escape_html;
+use cache::format_http_date;
+use download::format_size;
+
+struct Entry {
+    name: String,
+    url: Url,
+    dir: bool,
+    size: u64,
+    /// When the entry was last modified in seconds since the epoch
+    modified: Option<u64>,
+}
+
+/// Build an index page for a directory, with folders first and then files, each sorted by name
+pub fn directory_page(path: &Path) -> Result<String, String> {
+    let read_dir = fs::read_dir(path).map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+
+    let mut entries = Vec::new();
+    for entry_result in read_dir {
+        let entry = match entry_result {
+            Ok(entry) => entry,
+            Err(err) => {
+                println!("Failed to read entry of {}: {}", path.display(), err);
+                continue;
+            }
+        };
+
+        // Follow links, so that a link to a folder can be opened like one
+        let entry_path = entry.path();
+        let metadata = match fs::metadata(&entry_path).or_else(|_| entry.metadata()) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                println!("Failed to read metadata of {}: {}", entry_path.display(), err);
+                continue;
+            }
+        };
+
+        let dir = metadata.is_dir();
+        let url = if dir {
+            Url::from_directory_path(&entry_path)
+        } else {
+            Url::from_file_path(&entry_path)
+        };
+        let url = match url {
+            Ok(url) => url,
+            Err(()) => continue
+        };
+
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            url: url,
+            dir: dir,
+            size: metadata.len(),
+            modified: metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|duration| duration.as_secs()),
+        });
+    }
+    entries.sort_by(|a, b| (! a.dir, a.name.to_lowercase()).cmp(&(! b.dir, b.name.to_lowercase())));
+
+    let title = format!("Index of {}", path.display());
+    let mut html = format!("<html><head><title>{}</title></head><body>\n<h1>{}</h1>\n", escape_html(&title), escape_html(&title));
+    html.push_str("<table cellpadding=\"2\" cellspacing=\"0\">\n");
+    html.push_str("<tr><th align=\"left\">Name</th><th align=\"right\">Size</th><th align=\"left\">Modified</th></tr>\n");
+
+    if let Some(parent) = path.parent() {
+        if let Ok(url) = Url::from_directory_path(parent) {
+            html.push_str(&format!("<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>\n", escape_html(url.as_str())));
+        }
+    }
+
+    for entry in entries.iter() {
+        let (name, size) = if entry.dir {
+            (format!("{}/", entry.name), String::new())
+        } else {
+            (entry.name.clone(), format_size(entry.size))
+        };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td align=\"right\">{}</td><td>{}</td></tr>\n",
+            escape_html(entry.url.as_str()), escape_html(&name), size, entry.modified.map_or(String::new(), format_http_date)
+        ));
+    }
+
+    html.push_str("</table>\n</body></html>\n");
+    Ok(html)
+}
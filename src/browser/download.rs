@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use std::str;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::Headers;
+use url::Url;
+use url::percent_encoding::percent_decode;
+
+use about::escape_html;
+use layout::Page;
+use session::Session;
+use super::{html_parse, PROGRESS_INTERVAL};
+
+#[cfg(target_os = "redox")]
+static LAUNCH_COMMAND: &'static str = "/ui/bin/launcher";
+
+#[cfg(not(target_os = "redox"))]
+static LAUNCH_COMMAND: &'static str = "xdg-open";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DownloadState {
+    InProgress,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+/// A response being saved to disk
+#[derive(Clone, Debug)]
+pub struct Download {
+    pub url: String,
+    pub path: PathBuf,
+    /// Bytes written so far
+    pub received: u64,
+    /// The size from the Content-Length header, if known
+    pub total: Option<u64>,
+    pub state: DownloadState,
+}
+
+impl Download {
+    /// How far along the download is, such as "1.2 MB of 3.4 MB"
+    pub fn progress(&self) -> String {
+        match self.total {
+            Some(total) if total > 0 => format!("{} of {} ({}%)", format_size(self.received), format_size(total), self.received * 100 / total),
+            _ => format_size(self.received)
+        }
+    }
+}
+
+/// Every download of the session, in the order they were started
+pub struct Downloads {
+    downloads: Mutex<Vec<Download>>,
+    /// The directory files are saved to
+    dir: PathBuf,
+}
+
+impl Downloads {
+    pub fn new() -> Downloads {
+        let dir = match env::home_dir() {
+            Some(home) => {
+                let downloads = home.join("Downloads");
+                if downloads.is_dir() { downloads } else { home }
+            },
+            None => env::temp_dir()
+        };
+
+        Downloads {
+            downloads: Mutex::new(Vec::new()),
+            dir: dir,
+        }
+    }
+
+    /// Create the file for a new download, named so that no existing file is replaced
+    fn start(&self, url: &Url, filename: &str, total: Option<u64>) -> Result<(usize, File), String> {
+        let (stem, extension) = match filename.rfind('.') {
+            Some(i) if i > 0 => (&filename[..i], &filename[i..]),
+            _ => (filename, "")
+        };
+
+        let mut path = self.dir.join(filename);
+        let mut n = 1;
+        while path.exists() {
+            path = self.dir.join(format!("{} ({}){}", stem, n, extension));
+            n += 1;
+        }
+
+        let file = File::create(&path).map_err(|err| format!("Failed to create {}: {}", path.display(), err))?;
+
+        let mut downloads = self.downloads.lock().unwrap();
+        downloads.push(Download {
+            url: url.to_string(),
+            path: path,
+            received: 0,
+            total: total,
+            state: DownloadState::InProgress,
+        });
+        Ok((downloads.len() - 1, file))
+    }
+
+    /// Save data made by the browser itself, such as an exported page, as a finished download
+    pub fn save(&self, url: &Url, filename: &str, data: &[u8]) -> Result<PathBuf, String> {
+        let (id, mut file) = self.start(url, filename, Some(data.len() as u64))?;
+        let path = self.downloads.lock().unwrap()[id].path.clone();
+        match file.write_all(data) {
+            Ok(()) => {
+                self.update(id, data.len() as u64);
+                self.finish(id, DownloadState::Done);
+                Ok(path)
+            },
+            Err(err) => {
+                let err = format!("Failed to write {}: {}", path.display(), err);
+                self.finish(id, DownloadState::Failed(err.clone()));
+                Err(err)
+            }
+        }
+    }
+
+    /// Record how much of a download has been written, returning false if it has been cancelled
+    fn update(&self, id: usize, received: u64) -> bool {
+        let mut downloads = self.downloads.lock().unwrap();
+        downloads[id].received = received;
+        downloads[id].state == DownloadState::InProgress
+    }
+
+    fn finish(&self, id: usize, state: DownloadState) {
+        let mut downloads = self.downloads.lock().unwrap();
+        if downloads[id].state == DownloadState::InProgress {
+            downloads[id].state = state;
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<Download> {
+        self.downloads.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Download> {
+        self.downloads.lock().unwrap().clone()
+    }
+
+    /// Stop a download in progress, deleting the part written so far
+    pub fn cancel(&self, id: usize) {
+        if let Some(download) = self.downloads.lock().unwrap().get_mut(id) {
+            if download.state == DownloadState::InProgress {
+                download.state = DownloadState::Cancelled;
+            }
+        }
+    }
+
+    /// Open a finished download with the application registered for its type
+    pub fn open(&self, id: usize) -> Result<(), String> {
+        match self.get(id) {
+            Some(ref download) if download.state == DownloadState::Done => {
+                Command::new(LAUNCH_COMMAND).arg(&download.path).spawn()
+                    .map(|_| ())
+                    .map_err(|err| format!("Failed to open {}: {}", download.path.display(), err))
+            },
+            Some(_) => Err("The download has not finished".to_string()),
+            None => Err(format!("No download {}", id))
+        }
+    }
+}
+
+/// Does a response ask to be saved rather than shown
+pub fn is_attachment(headers: &Headers) -> bool {
+    content_disposition(headers).map_or(false, |disposition| {
+        disposition.split(';').next().unwrap_or("").trim().to_lowercase() == "attachment"
+    })
+}
+
+fn content_disposition(headers: &Headers) -> Option<&str> {
+    headers.get_raw("content-disposition").and_then(|x| str::from_utf8(x[0].as_slice()).ok())
+}
+
+/// The name to save a response as, from its Content-Disposition header or else its URL
+pub fn filename(headers: &Headers, url: &Url) -> String {
+    let mut name = None;
+    if let Some(disposition) = content_disposition(headers) {
+        for param in disposition.split(';').skip(1) {
+            let mut pair = param.splitn(2, '=');
+            let key = pair.next().unwrap_or("").trim().to_lowercase();
+            let value = pair.next().unwrap_or("").trim();
+            if key == "filename*" {
+                // RFC 5987: a charset, a language and then the percent encoded name
+                if let Some(i) = value.rfind('\'') {
+                    name = Some(percent_decode(value[i + 1..].as_bytes()).decode_utf8_lossy().into_owned());
+                    break;
+                }
+            } else if key == "filename" {
+                name = Some(value.trim_matches('"').to_string());
+            }
+        }
+    }
+
+    let name = name.unwrap_or_else(|| {
+        let segment = url.path_segments().and_then(|segments| segments.last()).unwrap_or("");
+        percent_decode(segment.as_bytes()).decode_utf8_lossy().into_owned()
+    });
+
+    // Only keep the last part of a path, so that a server cannot choose where the file goes
+    let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("");
+    let name: String = name.chars().filter(|c| ! c.is_control()).collect();
+    let name = name.trim().trim_left_matches('.');
+    if name.is_empty() {
+        "download".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Save a response to the downloads directory, showing a page with its progress. The download
+/// carries on when the tab goes to another page, until it is cancelled from about:downloads.
+pub fn download<R: Read + ?Sized>(headers: &Headers, r: &mut R, url: &Url, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
+    let encoded = headers.get_raw("content-encoding").is_some();
+    // The length of a compressed body says nothing about how much will be written
+    let total = if encoded {
+        None
+    } else {
+        headers.get_raw("content-length").and_then(|x| str::from_utf8(x[0].as_slice()).ok()).and_then(|length| length.trim().parse().ok())
+    };
+
+    let (id, mut file) = match session.downloads.start(url, &filename(headers, url), total) {
+        Ok(download) => download,
+        Err(err) => return download_page(None, Some(&err), url, session)
+    };
+    println!("Download {} to {}", url, session.downloads.list()[id].path.display());
+
+    let mut showing = true;
+    let mut received = 0;
+    let mut buf = [0; 16384];
+    let mut last_progress = Instant::now();
+    let state;
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) => {
+                state = DownloadState::Done;
+                break;
+            },
+            Ok(count) => {
+                if let Err(err) = file.write_all(&buf[..count]) {
+                    state = DownloadState::Failed(format!("Failed to write: {}", err));
+                    break;
+                }
+                received += count as u64;
+                if ! session.downloads.update(id, received) {
+                    state = DownloadState::Cancelled;
+                    break;
+                }
+
+                if showing && last_progress.elapsed() >= Duration::from_millis(PROGRESS_INTERVAL) {
+                    showing = progress(download_page(Some(id), None, url, session));
+                    last_progress = Instant::now();
+                }
+            },
+            Err(err) => {
+                state = DownloadState::Failed(format!("Failed to read: {}", err));
+                break;
+            }
+        }
+    }
+
+    if state == DownloadState::Cancelled {
+        if let Some(download) = session.downloads.get(id) {
+            let _ = fs::remove_file(&download.path);
+        }
+    }
+    session.downloads.finish(id, state);
+
+    download_page(Some(id), None, url, session)
+}
+
+/// The page shown in a tab while a response is saved
+fn download_page(id: Option<usize>, error: Option<&str>, url: &Url, session: &Session) -> Page {
+    let mut html = String::from("<html><head><title>Download</title></head><body>\n");
+    match id.and_then(|id| session.downloads.get(id)) {
+        Some(download) => {
+            let name = download.path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+            let status = match download.state {
+                DownloadState::InProgress => format!("Downloading: {}", download.progress()),
+                DownloadState::Done => format!("Saved {} to {}", format_size(download.received), download.path.display()),
+                DownloadState::Failed(ref err) => format!("Failed: {}", err),
+                DownloadState::Cancelled => "Cancelled".to_string(),
+            };
+            html.push_str(&format!("<h1>{}</h1>\n<p>{}</p>\n<p>{}</p>\n", escape_html(&name), escape_html(url.as_str()), escape_html(&status)));
+        },
+        None => html.push_str(&format!("<h1>Download failed</h1>\n<p>{}</p>\n", escape_html(error.unwrap_or("")))),
+    }
+    html.push_str("<p><a href=\"about:downloads\">Show all downloads</a></p>\n</body></html>\n");
+
+    html_parse(html.as_bytes(), "text/html; charset=utf-8", url, &mut BTreeMap::new(), session)
+}
+
+/// A size in bytes as people read it, such as 1.2 MB
+pub fn format_size(size: u64) -> String {
+    if size < 1024 {
+        format!("{} bytes", size)
+    } else if size < 1024 * 1024 {
+        format!("{:.1} KB", size as f64 / 1024.0)
+    } else if size < 1024 * 1024 * 1024 {
+        format!("{:.1} MB", size as f64 / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} GB", size as f64 / (1024.0 * 1024.0 * 1024.0))
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::io;
+
+use hyper;
+use url::Url;
+
+/// Why a page could not be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    /// The host name could not be resolved
+    Dns(String),
+    /// A secure connection could not be set up, most often because of the certificate
+    Tls(String),
+    /// The server did not answer in time
+    Timeout(String),
+    /// The connection was refused or dropped
+    Connection(String),
+    /// Redirects went on past the limit, most likely in a loop
+    TooManyRedirects(usize),
+    /// A redirect without a usable location
+    BadRedirect(String),
+    /// Anything else, such as a malformed response
+    Other(String),
+}
+
+impl LoadError {
+    /// Work out what went wrong from an error returned by hyper while loading a URL
+    pub fn from_hyper(err: hyper::Error, url: &Url) -> LoadError {
+        let host = url.host_str().unwrap_or("").to_string();
+        match err {
+            hyper::Error::Ssl(err) => LoadError::Tls(format!("{}", err)),
+            hyper::Error::Io(err) => LoadError::from_io(err, host),
+            err => LoadError::Other(format!("{}", err))
+        }
+    }
+
+    /// Work out what went wrong from an I/O error while talking to a host
+    pub fn from_io(err: io::Error, host: String) -> LoadError {
+        match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => return LoadError::Timeout(host),
+            io::ErrorKind::ConnectionRefused => return LoadError::Connection(format!("{} refused the connection", host)),
+            _ => ()
+        }
+
+        // Resolver and TLS failures have no error kind of their own, so look at the message
+        let message = format!("{:?}", err).to_lowercase();
+        if message.contains("lookup") || message.contains("not known") || message.contains("resolve") {
+            LoadError::Dns(host)
+        } else if message.contains("tls") || message.contains("webpki") || message.contains("certificate") || message.contains("handshake") || message.contains("alert") {
+            LoadError::Tls(format!("{}", err))
+        } else {
+            LoadError::Connection(format!("{}", err))
+        }
+    }
+
+    /// A short heading for the error page
+    pub fn title(&self) -> &'static str {
+        match *self {
+            LoadError::Dns(_) => "Server not found",
+            LoadError::Tls(_) => "Secure connection failed",
+            LoadError::Timeout(_) => "The connection timed out",
+            LoadError::Connection(_) => "Unable to connect",
+            LoadError::TooManyRedirects(_) => "Too many redirects",
+            LoadError::BadRedirect(_) => "Invalid redirect",
+            LoadError::Other(_) => "Failed to load page",
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Dns(ref host) => write!(f, "The address of {} could not be found", host),
+            LoadError::Tls(ref err) => write!(f, "The secure connection could not be set up: {}. A server with its own certificate authority can be trusted with ca_bundle, or a test server with self_signed, in ~/.config/browser/network.txt.", err),
+            LoadError::Timeout(ref host) => write!(f, "{} took too long to respond", host),
+            LoadError::Connection(ref err) => write!(f, "{}", err),
+            LoadError::TooManyRedirects(count) => write!(f, "The page redirected more than {} times, most likely in a loop", count),
+            LoadError::BadRedirect(ref location) => write!(f, "The page redirected to {}, which could not be followed", location),
+            LoadError::Other(ref err) => write!(f, "{}", err),
+        }
+    }
+}
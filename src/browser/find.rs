@@ -0,0 +1,197 @@
+use std::cmp::{self, Ordering};
+
+use orbclient::{Color, Renderer, Window};
+use orbfont::Font;
+
+use selection::{is_text, separator};
+use super::Block;
+
+/// Height of the find bar at the bottom of the window
+pub const FIND_HEIGHT: i32 = 28;
+
+const FONT_SIZE: f32 = 14.0;
+
+/// The bar opened with Ctrl+F, which highlights every match of its text on the page
+pub struct FindBar {
+    pub text: String,
+    pub open: bool,
+    /// Is the find bar taking key presses
+    pub focused: bool,
+    /// The first and last block of each match, in layout order
+    matches: Vec<(usize, usize)>,
+    /// The match scrolled to
+    current: usize,
+}
+
+impl FindBar {
+    pub fn new() -> FindBar {
+        FindBar {
+            text: String::new(),
+            open: false,
+            focused: false,
+            matches: Vec::new(),
+            current: 0,
+        }
+    }
+
+    /// Open and focus the bar, keeping the last search
+    pub fn show(&mut self) {
+        self.open = true;
+        self.focused = true;
+    }
+
+    pub fn hide(&mut self) {
+        self.open = false;
+        self.focused = false;
+        self.matches.clear();
+    }
+
+    /// Find every match of the text, ignoring case, in the words of a page
+    pub fn search(&mut self, blocks: &[Block]) {
+        self.matches.clear();
+
+        let query: Vec<char> = self.text.chars().map(fold_case).collect();
+        if ! self.open || query.is_empty() {
+            return;
+        }
+
+        // Words are joined into one string, remembering which block each character came from
+        let mut chars = Vec::new();
+        let mut owners = Vec::new();
+        let mut prev: Option<&Block> = None;
+        for (i, block) in blocks.iter().enumerate() {
+            if ! is_text(block) {
+                continue;
+            }
+
+            if let Some(prev) = prev {
+                if separator(prev, block).is_some() {
+                    chars.push(' ');
+                    owners.push(i);
+                }
+            }
+            for c in block.string.chars() {
+                chars.push(fold_case(c));
+                owners.push(i);
+            }
+            prev = Some(block);
+        }
+
+        let mut start = 0;
+        while start + query.len() <= chars.len() {
+            if chars[start..start + query.len()] == query[..] {
+                self.matches.push((owners[start], owners[start + query.len() - 1]));
+                start += query.len();
+            } else {
+                start += 1;
+            }
+        }
+
+        if self.current >= self.matches.len() {
+            self.current = 0;
+        }
+    }
+
+    /// Move to the next or previous match, wrapping around, returning its first block
+    pub fn step(&mut self, forward: bool) -> Option<usize> {
+        if self.matches.is_empty() {
+            return None;
+        }
+
+        self.current = if forward {
+            (self.current + 1) % self.matches.len()
+        } else {
+            (self.current + self.matches.len() - 1) % self.matches.len()
+        };
+        self.current_block()
+    }
+
+    /// Start from the first match, as when the text has changed
+    pub fn first(&mut self) -> Option<usize> {
+        self.current = 0;
+        self.current_block()
+    }
+
+    fn current_block(&self) -> Option<usize> {
+        self.matches.get(self.current).map(|&(start, _)| start)
+    }
+
+    /// The color to mark a block with, if it is part of a match
+    pub fn highlight(&self, i: usize) -> Option<Color> {
+        let found = self.matches.binary_search_by(|&(start, end)| {
+            if end < i {
+                Ordering::Less
+            } else if start > i {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        });
+
+        match found {
+            Ok(m) if m == self.current => Some(Color::rgb(255, 150, 50)),
+            Ok(_) => Some(Color::rgb(255, 255, 0)),
+            Err(_) => None
+        }
+    }
+
+    /// Draw the bar along the bottom of the window
+    pub fn draw(&self, window: &mut Window, font: &Font) {
+        let width = window.width() as i32;
+        let top = window.height() as i32 - FIND_HEIGHT;
+        window.rect(0, top, width as u32, FIND_HEIGHT as u32, Color::rgb(238, 238, 238));
+        window.rect(0, top, width as u32, 1, Color::rgb(160, 160, 160));
+
+        let label = font.render("Find:", FONT_SIZE);
+        label.draw(window, 8, top + (FIND_HEIGHT - label.height() as i32) / 2, Color::rgb(0, 0, 0));
+
+        let status = if self.text.is_empty() {
+            String::new()
+        } else if self.matches.is_empty() {
+            "No matches".to_string()
+        } else {
+            format!("{} of {}", self.current + 1, self.matches.len())
+        };
+        let status = font.render(&status, FONT_SIZE);
+
+        let box_x = 16 + label.width() as i32;
+        let box_w = cmp::max(8, width - box_x - status.width() as i32 - 24);
+        let border = if self.focused {
+            Color::rgb(0, 0, 255)
+        } else {
+            Color::rgb(118, 118, 118)
+        };
+        window.rect(box_x, top + 4, box_w as u32, (FIND_HEIGHT - 8) as u32, border);
+        window.rect(box_x + 1, top + 5, (box_w - 2) as u32, (FIND_HEIGHT - 10) as u32, Color::rgb(255, 255, 255));
+
+        // Keep the end of the text in view
+        let mut text = &self.text[..];
+        while ! text.is_empty() && font.render(text, FONT_SIZE).width() as i32 > box_w - 8 {
+            let mut chars = text.chars();
+            chars.next();
+            text = chars.as_str();
+        }
+
+        let mut cursor_x = box_x + 4;
+        if ! text.is_empty() {
+            let rendered = font.render(text, FONT_SIZE);
+            rendered.draw(window, box_x + 4, top + (FIND_HEIGHT - rendered.height() as i32) / 2, Color::rgb(0, 0, 0));
+            cursor_x += rendered.width() as i32;
+        }
+        if self.focused {
+            window.rect(cursor_x, top + 8, 1, (FIND_HEIGHT - 16) as u32, Color::rgb(0, 0, 0));
+        }
+
+        let status_color = if ! self.text.is_empty() && self.matches.is_empty() {
+            Color::rgb(192, 0, 0)
+        } else {
+            Color::rgb(0, 0, 0)
+        };
+        status.draw(window, box_x + box_w + 12, top + (FIND_HEIGHT - status.height() as i32) / 2, status_color);
+    }
+}
+
+/// Lowercase a character without changing the number of characters
+fn fold_case(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
@@ -0,0 +1,87 @@
+use orbfont::Font;
+
+use css::Style;
+
+/// The faces text is drawn with. Faces that are not installed fall back to the closest one
+/// that is.
+pub struct Fonts {
+    pub regular: Font,
+    pub bold: Font,
+    pub italic: Option<Font>,
+    pub bold_italic: Option<Font>,
+    pub mono: Option<Font>,
+    pub mono_bold: Option<Font>,
+}
+
+impl Fonts {
+    /// Load the installed faces, failing only if there is no regular or bold face at all
+    pub fn find() -> Result<Fonts, String> {
+        let regular = Font::find(Some("Sans"), None, None).or_else(|_| Font::find(None, None, None))?;
+        let bold = Font::find(Some("Sans"), None, Some("Bold")).or_else(|_| Font::find(None, None, Some("Bold")))?;
+
+        Ok(Fonts {
+            regular: regular,
+            bold: bold,
+            italic: Font::find(Some("Sans"), None, Some("Italic")).ok(),
+            bold_italic: Font::find(Some("Sans"), None, Some("BoldItalic")).ok(),
+            mono: Font::find(Some("Mono"), None, None).ok(),
+            mono_bold: Font::find(Some("Mono"), None, Some("Bold")).ok(),
+        })
+    }
+
+    /// The face for text in a style
+    pub fn select(&self, style: &Style) -> &Font {
+        let face = if style.monospace {
+            if style.bold { self.mono_bold.as_ref().or(self.mono.as_ref()) } else { self.mono.as_ref() }
+        } else if style.italic {
+            if style.bold { self.bold_italic.as_ref() } else { self.italic.as_ref() }
+        } else {
+            None
+        };
+
+        match face {
+            Some(font) => font,
+            None => if style.bold { &self.bold } else { &self.regular }
+        }
+    }
+}
+
+/// The byte offsets where each character of a string starts, and its end
+fn boundaries(string: &str) -> Vec<usize> {
+    let mut boundaries: Vec<usize> = string.char_indices().map(|(i, _)| i).collect();
+    boundaries.push(string.len());
+    boundaries
+}
+
+/// The longest start of a string that fits in a width, found by halving the range of lengths
+/// rather than measuring again after every character taken off
+pub fn fit_start<'s>(font: &Font, string: &'s str, size: f32, width: i32) -> &'s str {
+    let boundaries = boundaries(string);
+    // The number of characters known to fit, and the most that might
+    let (mut low, mut high) = (0, boundaries.len() - 1);
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        if font.render(&string[..boundaries[mid]], size).width() as i32 <= width {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    &string[..boundaries[low]]
+}
+
+/// The longest end of a string that fits in a width, like fit_start
+pub fn fit_end<'s>(font: &Font, string: &'s str, size: f32, width: i32) -> &'s str {
+    let boundaries = boundaries(string);
+    // The number of characters that have to be left out at least, and known to be enough
+    let (mut low, mut high) = (0, boundaries.len() - 1);
+    while low < high {
+        let mid = (low + high) / 2;
+        if font.render(&string[boundaries[mid]..], size).width() as i32 <= width {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    &string[boundaries[low]..]
+}
@@ -0,0 +1,194 @@
+use hyper::method::Method;
+use url::Url;
+use url::form_urlencoded;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlKind {
+    Text,
+    Password,
+    Hidden,
+    Checkbox,
+    Radio,
+    Submit,
+    Reset,
+    Button,
+    TextArea,
+    Select,
+}
+
+/// A form element, owning the controls that point back to it
+pub struct Form {
+    /// The action attribute, resolved against the page URL on submission
+    pub action: String,
+    /// GET or POST
+    pub method: Method,
+}
+
+/// The live state of an input, textarea, select or button
+pub struct Control {
+    pub kind: ControlKind,
+    /// The index of the owning form
+    pub form: Option<usize>,
+    pub name: String,
+    pub value: String,
+    pub default_value: String,
+    /// The text shown on a button
+    pub label: String,
+    pub checked: bool,
+    pub default_checked: bool,
+    /// The labels and values of the options of a select
+    pub options: Vec<(String, String)>,
+    pub selected: usize,
+    pub default_selected: usize,
+    /// The visible width in characters
+    pub size: usize,
+    /// The visible height in lines, for a textarea
+    pub rows: usize,
+}
+
+impl Control {
+    pub fn new(kind: ControlKind, form: Option<usize>) -> Control {
+        Control {
+            kind: kind,
+            form: form,
+            name: String::new(),
+            value: String::new(),
+            default_value: String::new(),
+            label: String::new(),
+            checked: false,
+            default_checked: false,
+            options: Vec::new(),
+            selected: 0,
+            default_selected: 0,
+            size: 20,
+            rows: 2,
+        }
+    }
+
+    /// Does this control accept typed text
+    pub fn editable(&self) -> bool {
+        match self.kind {
+            ControlKind::Text | ControlKind::Password | ControlKind::TextArea => true,
+            _ => false
+        }
+    }
+
+    /// The single line of text drawn inside the control
+    pub fn display_text(&self) -> String {
+        match self.kind {
+            ControlKind::Text => self.value.clone(),
+            ControlKind::Password => self.value.chars().map(|_| '*').collect(),
+            ControlKind::Submit => if self.label.is_empty() { "Submit".to_string() } else { self.label.clone() },
+            ControlKind::Reset => if self.label.is_empty() { "Reset".to_string() } else { self.label.clone() },
+            ControlKind::Button => self.label.clone(),
+            ControlKind::Select => match self.options.get(self.selected) {
+                Some(&(ref label, _)) => label.clone(),
+                None => String::new()
+            },
+            _ => String::new()
+        }
+    }
+}
+
+/// The forms and controls of a page
+pub struct Forms {
+    pub forms: Vec<Form>,
+    pub controls: Vec<Control>,
+}
+
+impl Forms {
+    pub fn new() -> Forms {
+        Forms {
+            forms: Vec::new(),
+            controls: Vec::new(),
+        }
+    }
+
+    /// Toggle a checkbox, or check a radio button and uncheck the rest of its group
+    pub fn toggle(&mut self, i: usize) {
+        match self.controls[i].kind {
+            ControlKind::Checkbox => self.controls[i].checked = ! self.controls[i].checked,
+            ControlKind::Radio => {
+                let form = self.controls[i].form;
+                let name = self.controls[i].name.clone();
+                for (j, control) in self.controls.iter_mut().enumerate() {
+                    if control.kind == ControlKind::Radio && control.form == form && control.name == name {
+                        control.checked = i == j;
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+
+    /// Move the selection of a select up or down by one option
+    pub fn select(&mut self, i: usize, forward: bool) {
+        let control = &mut self.controls[i];
+        if ! control.options.is_empty() {
+            if forward {
+                control.selected = (control.selected + 1) % control.options.len();
+            } else {
+                control.selected = (control.selected + control.options.len() - 1) % control.options.len();
+            }
+        }
+    }
+
+    /// Restore every control of a form to its initial state
+    pub fn reset(&mut self, form: usize) {
+        for control in self.controls.iter_mut() {
+            if control.form == Some(form) {
+                control.value = control.default_value.clone();
+                control.checked = control.default_checked;
+                control.selected = control.default_selected;
+            }
+        }
+    }
+
+    /// The submit button used when a form is submitted with Enter
+    pub fn default_button(&self, form: usize) -> Option<usize> {
+        self.controls.iter().position(|control| control.form == Some(form) && control.kind == ControlKind::Submit)
+    }
+
+    /// Encode a form as application/x-www-form-urlencoded, returning the URL to load and a body for POST
+    pub fn submit(&self, form: usize, submitter: Option<usize>, base: &Url) -> Result<(Url, Option<Vec<u8>>), String> {
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        for (i, control) in self.controls.iter().enumerate() {
+            if control.form != Some(form) || control.name.is_empty() {
+                continue;
+            }
+
+            match control.kind {
+                ControlKind::Text | ControlKind::Password | ControlKind::Hidden | ControlKind::TextArea => {
+                    // Line breaks are normalized to CRLF, as in every other browser
+                    let value = control.value.replace("\r\n", "\n").replace('\n', "\r\n");
+                    serializer.append_pair(&control.name, &value);
+                },
+                ControlKind::Checkbox | ControlKind::Radio => if control.checked {
+                    serializer.append_pair(&control.name, if control.value.is_empty() { "on" } else { &control.value });
+                },
+                ControlKind::Select => if let Some(&(_, ref value)) = control.options.get(control.selected) {
+                    serializer.append_pair(&control.name, value);
+                },
+                ControlKind::Submit => if submitter == Some(i) {
+                    serializer.append_pair(&control.name, &control.value);
+                },
+                ControlKind::Reset | ControlKind::Button => ()
+            }
+        }
+        let query = serializer.finish();
+
+        let action = &self.forms[form].action;
+        let mut url = if action.is_empty() {
+            base.clone()
+        } else {
+            base.join(action).map_err(|err| format!("Invalid form action {}: {}", action, err))?
+        };
+
+        if self.forms[form].method == Method::Post {
+            Ok((url, Some(query.into_bytes())))
+        } else {
+            url.set_query(Some(&query));
+            Ok((url, None))
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+use hyper::header::{ContentType, Headers};
+use url::Url;
+use url::form_urlencoded;
+use url::percent_encoding::{utf8_percent_encode, QUERY_ENCODE_SET};
+
+use about::{error_page, escape_html};
+use error::LoadError;
+use layout::Page;
+use session::Session;
+use super::{html_page, read_parse, MAX_REDIRECTS};
+
+const DEFAULT_PORT: u16 = 1965;
+
+/// Longest response header allowed by the protocol, a two digit status, a space and 1024 bytes
+const MAX_HEADER: usize = 1029;
+
+/// Load a gemini URL over TLS. A body posted from an input prompt holds the text to send as
+/// the query.
+pub fn gemini_parse(url: &Url, body: Option<&[u8]>, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
+    let mut url = url.clone();
+    if let Some(body) = body {
+        for (name, value) in form_urlencoded::parse(body) {
+            if name == "input" {
+                let query = utf8_percent_encode(&value, QUERY_ENCODE_SET).to_string();
+                url.set_query(Some(&query));
+            }
+        }
+    }
+
+    let mut redirects = 0;
+    loop {
+        let host = url.host_str().unwrap_or("").to_string();
+        let result = session.connect_tls(&url, DEFAULT_PORT).and_then(|mut stream| {
+            stream.write_all(format!("{}\r\n", url).as_bytes()).map_err(|err| LoadError::from_io(err, host.clone()))?;
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            (&mut reader).take(MAX_HEADER as u64 + 2).read_line(&mut line).map_err(|err| LoadError::from_io(err, host.clone()))?;
+            Ok((line, reader))
+        });
+
+        let (line, mut reader) = match result {
+            Ok(response) => response,
+            Err(err) => return html_page(&error_page(err.title(), &format!("{}", err), &url), &url, session, progress)
+        };
+
+        let line = line.trim_right_matches(|c| c == '\r' || c == '\n');
+        let (status, meta) = if line.len() >= 2 && line.is_char_boundary(2) {
+            (line[..2].parse::<u8>().unwrap_or(0), line[2..].trim())
+        } else {
+            (0, "")
+        };
+
+        // Pages carry the URL they came from after redirects, for the address bar
+        let page_url = url.clone();
+        let mut progress = |mut page: Page| -> bool {
+            page.url = Some(page_url.clone());
+            progress(page)
+        };
+
+        let mut page = match status / 10 {
+            1 => html_page(&input_page(meta, status == 11, &url), &url, session, &mut progress),
+            2 => {
+                let media_type = if meta.is_empty() { "text/gemini; charset=utf-8" } else { meta };
+                if media_type.starts_with("text/gemini") {
+                    let mut data = Vec::new();
+                    if let Err(err) = reader.read_to_end(&mut data) {
+                        let err = LoadError::from_io(err, host);
+                        return html_page(&error_page(err.title(), &format!("{}", err), &url), &url, session, &mut progress);
+                    }
+                    html_page(&gemtext_page(&String::from_utf8_lossy(&data), &url), &url, session, &mut progress)
+                } else {
+                    // Types that cannot be parsed are saved as downloads
+                    let mut headers = Headers::new();
+                    headers.set(ContentType(media_type.parse().unwrap_or_else(|_| "application/octet-stream".parse().unwrap())));
+                    read_parse(headers, &mut reader, &url, session, &mut progress)
+                }
+            },
+            3 => {
+                redirects += 1;
+                let location = url.join(meta);
+                match location {
+                    Ok(ref location) if redirects <= MAX_REDIRECTS && location.scheme() == "gemini" => {
+                        url = location.clone();
+                        continue;
+                    },
+                    _ => {
+                        let err = if redirects > MAX_REDIRECTS {
+                            LoadError::TooManyRedirects(MAX_REDIRECTS)
+                        } else {
+                            LoadError::BadRedirect(meta.to_string())
+                        };
+                        html_page(&error_page(err.title(), &format!("{}", err), &url), &url, session, &mut progress)
+                    }
+                }
+            },
+            4 | 5 => {
+                let message = if meta.is_empty() { "The server could not answer the request" } else { meta };
+                html_page(&error_page(&format!("Gemini error {}", status), message, &url), &url, session, &mut progress)
+            },
+            6 => html_page(&error_page("Certificate required", "The page needs a client certificate, which is not supported", &url), &url, session, &mut progress),
+            _ => html_page(&error_page("Invalid response", &format!("The server sent an invalid header: {}", line), &url), &url, session, &mut progress)
+        };
+
+        page.url = Some(url.clone());
+        return page;
+    }
+}
+
+/// A page asking for the input a gemini server has prompted for
+fn input_page(prompt: &str, sensitive: bool, url: &Url) -> String {
+    let mut action = url.clone();
+    action.set_query(None);
+    format!(
+        "<html><head><title>{}</title></head><body>\n<form method=\"post\" action=\"{}\"><p>{}</p>\
+        <p><input type=\"{}\" name=\"input\" size=\"40\"> <input type=\"submit\" value=\"Send\"></p></form>\n</body></html>\n",
+        escape_html(prompt), escape_html(action.as_str()), escape_html(prompt), if sensitive { "password" } else { "text" }
+    )
+}
+
+/// Turn a text/gemini document into HTML, one element per line
+fn gemtext_page(text: &str, url: &Url) -> String {
+    let mut title = None;
+    let mut body = String::new();
+    let mut preformatted = false;
+    let mut list = false;
+
+    for line in text.lines() {
+        if line.starts_with("```") {
+            body.push_str(if preformatted { "</pre>\n" } else { "<pre>" });
+            preformatted = ! preformatted;
+            continue;
+        }
+        if preformatted {
+            body.push_str(&escape_html(line));
+            body.push('\n');
+            continue;
+        }
+
+        if line.starts_with("* ") != list {
+            body.push_str(if list { "</ul>\n" } else { "<ul>\n" });
+            list = ! list;
+        }
+
+        if line.starts_with("=>") {
+            let rest = line[2..].trim();
+            let split = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (href, label) = (&rest[..split], rest[split..].trim());
+            let label = if label.is_empty() { href } else { label };
+            body.push_str(&format!("<p><a href=\"{}\">{}</a></p>\n", escape_html(href), escape_html(label)));
+        } else if line.starts_with("###") {
+            body.push_str(&format!("<h3>{}</h3>\n", escape_html(line[3..].trim())));
+        } else if line.starts_with("##") {
+            body.push_str(&format!("<h2>{}</h2>\n", escape_html(line[2..].trim())));
+        } else if line.starts_with('#') {
+            let heading = line[1..].trim();
+            if title.is_none() {
+                title = Some(heading.to_string());
+            }
+            body.push_str(&format!("<h1>{}</h1>\n", escape_html(heading)));
+        } else if line.starts_with("* ") {
+            body.push_str(&format!("<li>{}</li>\n", escape_html(line[2..].trim())));
+        } else if line.starts_with('>') {
+            body.push_str(&format!("<blockquote>{}</blockquote>\n", escape_html(line[1..].trim())));
+        } else if ! line.trim().is_empty() {
+            body.push_str(&format!("<p>{}</p>\n", escape_html(line)));
+        }
+    }
+    if preformatted {
+        body.push_str("</pre>\n");
+    }
+    if list {
+        body.push_str("</ul>\n");
+    }
+
+    format!(
+        "<html><head><title>{}</title></head><body>\n{}</body></html>\n",
+        escape_html(&title.unwrap_or(url.to_string())), body
+    )
+}
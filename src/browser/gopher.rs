@@ -0,0 +1,107 @@
+use std::io::{Read, Write};
+
+use hyper::header::{ContentType, Headers};
+use url::Url;
+use url::form_urlencoded;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, DEFAULT_ENCODE_SET};
+
+use about::{error_page, escape_html};
+use error::LoadError;
+use layout::Page;
+use session::Session;
+use super::{html_page, read_parse};
+
+const DEFAULT_PORT: u16 = 70;
+
+/// Load a gopher URL, whose path is an item type followed by a selector. A body posted to a
+/// search item holds the search terms.
+pub fn gopher_parse(url: &Url, body: Option<&[u8]>, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
+    let path = percent_decode(url.path().as_bytes()).decode_utf8_lossy().into_owned();
+    let mut chars = path.trim_left_matches('/').chars();
+    let item_type = chars.next().unwrap_or('1');
+    let mut selector = chars.as_str().to_string();
+
+    if let Some(body) = body {
+        for (name, value) in form_urlencoded::parse(body) {
+            if name == "query" {
+                selector.push('\t');
+                selector.push_str(&value);
+            }
+        }
+    }
+
+    let mut stream = match session.connect(url, DEFAULT_PORT) {
+        Ok(stream) => stream,
+        Err(err) => return html_page(&error_page(err.title(), &format!("{}", err), url), url, session, progress)
+    };
+    if let Err(err) = stream.write_all(format!("{}\r\n", selector).as_bytes()) {
+        let err = LoadError::from_io(err, url.host_str().unwrap_or("").to_string());
+        return html_page(&error_page(err.title(), &format!("{}", err), url), url, session, progress);
+    }
+
+    let media_type = match item_type {
+        '1' | '7' => {
+            let mut data = Vec::new();
+            if let Err(err) = stream.read_to_end(&mut data) {
+                let err = LoadError::from_io(err, url.host_str().unwrap_or("").to_string());
+                return html_page(&error_page(err.title(), &format!("{}", err), url), url, session, progress);
+            }
+            return html_page(&menu_page(&String::from_utf8_lossy(&data), url), url, session, progress);
+        },
+        '0' => "text/plain; charset=utf-8",
+        'h' => "text/html",
+        'g' => "image/gif",
+        'p' => "image/png",
+        // The format of other images is told from their data
+        'I' => "image/x-unknown",
+        // Anything else is saved as a download
+        _ => "application/octet-stream"
+    };
+
+    let mut headers = Headers::new();
+    headers.set(ContentType(media_type.parse().unwrap()));
+    read_parse(headers, &mut stream, url, session, progress)
+}
+
+/// Turn a gopher menu into a page of links, one line per item
+fn menu_page(menu: &str, url: &Url) -> String {
+    let mut html = format!("<html><head><title>{}</title></head><body>\n<pre>", escape_html(url.as_str()));
+
+    for line in menu.lines() {
+        if line == "." {
+            break;
+        }
+
+        let mut chars = line.chars();
+        let item_type = match chars.next() {
+            Some(item_type) => item_type,
+            None => continue
+        };
+        let fields: Vec<&str> = chars.as_str().split('\t').collect();
+        let display = escape_html(fields[0]);
+        let selector = fields.get(1).map_or("", |selector| *selector);
+        let host = fields.get(2).map_or("", |host| *host);
+        let port = fields.get(3).and_then(|port| port.trim().parse::<u16>().ok()).unwrap_or(DEFAULT_PORT);
+
+        let href = if item_type == 'h' && selector.starts_with("URL:") {
+            selector[4..].to_string()
+        } else {
+            format!("gopher://{}:{}/{}{}", host, port, item_type, utf8_percent_encode(selector, DEFAULT_ENCODE_SET))
+        };
+
+        match item_type {
+            'i' => html.push_str(&display),
+            '3' => html.push_str(&format!("Error: {}", display)),
+            '7' => html.push_str(&format!(
+                "<form method=\"post\" action=\"{}\">{} <input type=\"text\" name=\"query\"> <input type=\"submit\" value=\"Search\"></form>",
+                escape_html(&href), display
+            )),
+            '8' | 'T' => html.push_str(&format!("{} (telnet {}:{})", display, escape_html(host), port)),
+            _ => html.push_str(&format!("<a href=\"{}\">{}</a>", escape_html(&href), display))
+        }
+        html.push_str("<br>");
+    }
+
+    html.push_str("</pre>\n</body></html>\n");
+    html
+}
@@ -0,0 +1,140 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use orbclient::{Color, Renderer};
+use orbimage::Image;
+use url::Url;
+
+use fonts::Fonts;
+use image::encode_png;
+use pdf::{encode_pdf, Paper};
+use selection::blocks_text;
+use session::Session;
+use tab::Tab;
+use super::parse_address;
+
+/// Width pages are laid out at when none is given, the same as a new window
+const DEFAULT_WIDTH: i32 = 1024;
+
+/// Tallest screenshot taken, so that an endless page cannot use up all memory
+const MAX_HEIGHT: i32 = 32768;
+
+enum Output {
+    Text,
+    Screenshot(String),
+    Pdf(String),
+}
+
+/// Does the command line ask for a page to be rendered without a window
+pub fn is_headless(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == "--dump-text" || arg == "--screenshot" || arg == "--pdf")
+}
+
+/// Load a page without a window and print its text or save a picture or PDF of it, as asked by
+///
+///     browser --dump-text [--width N] URL
+///     browser --screenshot FILE.png [--width N] URL
+///     browser --pdf FILE.pdf [--paper a4|letter] [--width N] URL
+///
+/// PDFs are laid out to fit the paper unless a width is given.
+pub fn run(args: &[String], fonts: &Fonts) -> Result<(), String> {
+    let mut output = None;
+    let mut width = None;
+    let mut paper = Paper::A4;
+    let mut address = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--dump-text" => output = Some(Output::Text),
+            "--screenshot" => match iter.next() {
+                Some(path) => output = Some(Output::Screenshot(path.clone())),
+                None => return Err("--screenshot needs a file to write".to_string())
+            },
+            "--pdf" => match iter.next() {
+                Some(path) => output = Some(Output::Pdf(path.clone())),
+                None => return Err("--pdf needs a file to write".to_string())
+            },
+            "--paper" => match iter.next().and_then(|name| Paper::parse(name)) {
+                Some(size) => paper = size,
+                None => return Err("--paper needs a4 or letter".to_string())
+            },
+            "--width" => match iter.next().and_then(|width| width.parse::<i32>().ok()) {
+                Some(n) if n > 0 => width = Some(n),
+                _ => return Err("--width needs a number of pixels".to_string())
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => address = Some(arg.clone())
+        }
+    }
+
+    let output = output.ok_or_else(|| "One of --dump-text, --screenshot or --pdf is needed".to_string())?;
+    let address = address.ok_or_else(|| "No URL given".to_string())?;
+    let url = local_url(&address).or_else(|| parse_address(&address)).ok_or_else(|| format!("Invalid URL {}", address))?;
+
+    let session = Arc::new(Session::new());
+    let mut tab = Tab::new(url);
+    tab.load(&session);
+    while tab.loading() {
+        if ! tab.poll() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let width = match output {
+        Output::Pdf(_) => width.unwrap_or(paper.layout_width()),
+        _ => width.unwrap_or(DEFAULT_WIDTH)
+    };
+    // Zoom levels saved for the site are left out, so that the output only depends on the page
+    tab.layout(width, MAX_HEIGHT, 1.0, fonts);
+
+    match output {
+        Output::Text => {
+            let text = if tab.blocks.is_empty() {
+                String::new()
+            } else {
+                blocks_text(&tab.blocks, 0, tab.blocks.len() - 1)
+            };
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(text.as_bytes()).and_then(|_| stdout.write_all(b"\n")).map_err(|err| format!("Failed to write text: {}", err))
+        },
+        Output::Screenshot(path) => {
+            let mut height = 1;
+            for block in tab.blocks.iter() {
+                height = cmp::max(height, block.y + block.h);
+            }
+            let height = cmp::min(height, MAX_HEIGHT);
+
+            let mut image = Image::new(width as u32, height as u32);
+            image.set(tab.page.background().unwrap_or(Color::rgb(255, 255, 255)));
+            for block in tab.blocks.iter() {
+                block.draw(&mut image, (0, 0), None);
+            }
+
+            File::create(&path).and_then(|mut file| file.write_all(&encode_png(&image))).map_err(|err| format!("Failed to write {}: {}", path, err))
+        },
+        Output::Pdf(path) => {
+            let pdf = encode_pdf(&tab.blocks, &tab.anchors, width, &tab.url, &tab.title(), paper);
+            File::create(&path).and_then(|mut file| file.write_all(&pdf)).map_err(|err| format!("Failed to write {}: {}", path, err))
+        }
+    }
+}
+
+/// A file URL for an address naming a local file, so that test pages can be given by path
+fn local_url(address: &str) -> Option<Url> {
+    let path = Path::new(address);
+    if ! path.exists() {
+        return None;
+    }
+
+    fs::canonicalize(path).ok().and_then(|path| if path.is_dir() {
+        Url::from_directory_path(path).ok()
+    } else {
+        Url::from_file_path(path).ok()
+    })
+}
@@ -0,0 +1,191 @@
+use std::cmp;
+
+use orbclient::{Color, Renderer, Window};
+use orbfont::Font;
+
+use super::Block;
+
+/// Letters hint labels are made of, home row first
+const HINT_KEYS: &'static str = "asdfghjklqwertyuiopzxcvbnm";
+
+const FOCUS_COLOR: Color = Color { data: 0xFF0000FF };
+const FONT_SIZE: f32 = 12.0;
+
+/// A link or form control that keyboard focus moves to, made of the run of blocks it was laid
+/// out as
+pub struct Target {
+    pub first: usize,
+    pub last: usize,
+    pub link: Option<String>,
+    pub control: Option<usize>,
+}
+
+impl Target {
+    /// The boxes around the target's blocks, one for each line it wraps onto
+    fn rects(&self, blocks: &[Block]) -> Vec<(i32, i32, i32, i32)> {
+        let mut rects: Vec<(i32, i32, i32, i32)> = Vec::new();
+        for block in blocks[self.first..self.last + 1].iter() {
+            if let Some(rect) = rects.last_mut() {
+                if block.y < rect.1 + rect.3 && block.y + block.h > rect.1 {
+                    let right = cmp::max(rect.0 + rect.2, block.x + block.w);
+                    let bottom = cmp::max(rect.1 + rect.3, block.y + block.h);
+                    rect.0 = cmp::min(rect.0, block.x);
+                    rect.1 = cmp::min(rect.1, block.y);
+                    rect.2 = right - rect.0;
+                    rect.3 = bottom - rect.1;
+                    continue;
+                }
+            }
+            rects.push((block.x, block.y, block.w, block.h));
+        }
+        rects
+    }
+
+    /// Draw a ring around the target to show it has keyboard focus
+    pub fn draw_focus(&self, window: &mut Window, blocks: &[Block], offset: (i32, i32)) {
+        for &(x, y, w, h) in self.rects(blocks).iter() {
+            let (x, y, w, h) = (x - offset.0 - 2, y - offset.1 - 2, w + 4, h + 4);
+            window.rect(x, y, w as u32, 2, FOCUS_COLOR);
+            window.rect(x, y + h - 2, w as u32, 2, FOCUS_COLOR);
+            window.rect(x, y + 2, 2, (h - 4) as u32, FOCUS_COLOR);
+            window.rect(x + w - 2, y + 2, 2, (h - 4) as u32, FOCUS_COLOR);
+        }
+    }
+}
+
+/// The links and form controls of a page in reading order
+pub fn targets(blocks: &[Block]) -> Vec<Target> {
+    let mut targets: Vec<Target> = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        if block.link.is_none() && block.control.is_none() {
+            continue;
+        }
+
+        // The words of a link and the contents of a control follow each other
+        if let Some(target) = targets.last_mut() {
+            if target.last + 1 == i && target.link == block.link && target.control == block.control {
+                target.last = i;
+                continue;
+            }
+        }
+
+        targets.push(Target {
+            first: i,
+            last: i,
+            link: block.link.clone(),
+            control: block.control,
+        });
+    }
+    targets
+}
+
+/// Labels on the links in view, so that one can be followed by typing its label
+pub struct Hints {
+    pub open: bool,
+    /// Open the chosen link in a new tab
+    pub new_tab: bool,
+    typed: String,
+    /// The label of each link in view, and its target
+    labels: Vec<(String, usize)>,
+}
+
+impl Hints {
+    pub fn new() -> Hints {
+        Hints {
+            open: false,
+            new_tab: false,
+            typed: String::new(),
+            labels: Vec::new(),
+        }
+    }
+
+    /// Label the links inside a view of the page, returning false if there are none
+    pub fn show(&mut self, targets: &[Target], blocks: &[Block], offset: (i32, i32), width: i32, height: i32, new_tab: bool) -> bool {
+        let mut visible = Vec::new();
+        for (i, target) in targets.iter().enumerate() {
+            let block = &blocks[target.first];
+            if target.link.is_some()
+            && block.x + block.w > offset.0 && block.x < offset.0 + width
+            && block.y + block.h > offset.1 && block.y < offset.1 + height {
+                visible.push(i);
+            }
+        }
+
+        // Every label has the same length, so that none is the start of another
+        let keys: Vec<char> = HINT_KEYS.chars().collect();
+        let mut length = 1;
+        let mut count = keys.len();
+        while count < visible.len() {
+            length += 1;
+            count *= keys.len();
+        }
+
+        self.labels.clear();
+        for (n, &i) in visible.iter().enumerate() {
+            let mut label = String::new();
+            let mut rest = n;
+            for _ in 0..length {
+                label.insert(0, keys[rest % keys.len()]);
+                rest /= keys.len();
+            }
+            self.labels.push((label, i));
+        }
+
+        self.typed.clear();
+        self.new_tab = new_tab;
+        self.open = ! self.labels.is_empty();
+        self.open
+    }
+
+    pub fn hide(&mut self) {
+        self.open = false;
+        self.typed.clear();
+        self.labels.clear();
+    }
+
+    /// Take a typed letter, returning the chosen target once a whole label has been typed.
+    /// Letters no label goes on with are ignored.
+    pub fn key(&mut self, c: char) -> Option<usize> {
+        self.typed.extend(c.to_lowercase());
+
+        let mut matched = false;
+        for &(ref label, i) in self.labels.iter() {
+            if *label == self.typed {
+                self.hide();
+                return Some(i);
+            }
+            if label.starts_with(&self.typed) {
+                matched = true;
+            }
+        }
+
+        if ! matched {
+            self.typed.pop();
+        }
+        None
+    }
+
+    /// Take back the last letter typed
+    pub fn back(&mut self) {
+        self.typed.pop();
+    }
+
+    /// Draw the labels still matching what was typed at the start of their links
+    pub fn draw(&self, window: &mut Window, font: &Font, targets: &[Target], blocks: &[Block], offset: (i32, i32)) {
+        for &(ref label, i) in self.labels.iter() {
+            if ! label.starts_with(&self.typed) {
+                continue;
+            }
+
+            let block = &blocks[targets[i].first];
+            let text = font.render(&label[self.typed.len()..].to_uppercase(), FONT_SIZE);
+            let x = block.x - offset.0;
+            let y = block.y - offset.1;
+            let w = text.width() as i32 + 6;
+            let h = text.height() as i32 + 2;
+            window.rect(x, y, w as u32, h as u32, Color::rgb(196, 160, 0));
+            window.rect(x + 1, y + 1, (w - 2) as u32, (h - 2) as u32, Color::rgb(255, 221, 64));
+            text.draw(window, x + 3, y + 1, Color::rgb(0, 0, 0));
+        }
+    }
+}
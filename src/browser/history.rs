@@ -0,0 +1,235 @@
+use std::env;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use url::Url;
+
+use cache::now;
+
+/// Pages remembered before the least recently visited are forgotten
+const MAX_ENTRIES: usize = 10000;
+
+/// A page in the history, with when and how often it was visited
+#[derive(Clone, Debug)]
+pub struct Visit {
+    pub url: String,
+    pub title: String,
+    pub visits: u32,
+    /// When the page was last visited in seconds since the epoch
+    pub last: u64,
+}
+
+impl Visit {
+    /// A line of the history file: the last visit, the number of visits, the URL and the title,
+    /// separated by tabs
+    fn line(&self) -> String {
+        format!("{}\t{}\t{}\t{}\n", self.last, self.visits, self.url, self.title)
+    }
+
+    fn from_line(line: &str) -> Option<Visit> {
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        if fields.len() < 4 || fields[2].is_empty() {
+            return None;
+        }
+
+        Some(Visit {
+            url: fields[2].to_string(),
+            title: fields[3].to_string(),
+            visits: fields[1].parse().unwrap_or(1),
+            last: fields[0].parse().unwrap_or(0),
+        })
+    }
+
+    /// Does the page match every word of a search, in its URL or title
+    fn matches(&self, words: &[String]) -> bool {
+        let url = self.url.to_lowercase();
+        let title = self.title.to_lowercase();
+        words.iter().all(|word| url.contains(&word[..]) || title.contains(&word[..]))
+    }
+}
+
+/// Every page visited in any tab, saved between runs. Each visit adds a line to the end of the
+/// history file, where later lines for a page replace earlier ones, and the file is written out
+/// afresh with a line for each page when the browser starts.
+pub struct History {
+    /// Pages in the order they were first visited
+    visits: Mutex<Vec<Visit>>,
+    /// The file the history is saved to, if there is a home directory
+    path: Option<PathBuf>,
+}
+
+impl History {
+    pub fn new() -> History {
+        let path = env::home_dir().map(|home| home.join(".config").join("browser").join("history.txt"));
+
+        let mut visits: Vec<Visit> = Vec::new();
+        let mut lines = 0;
+        if let Some(ref path) = path {
+            let mut data = String::new();
+            if let Ok(mut file) = File::open(path) {
+                match file.read_to_string(&mut data) {
+                    Ok(_) => {
+                        let mut positions = HashMap::new();
+                        for line in data.lines() {
+                            lines += 1;
+                            if let Some(visit) = Visit::from_line(line) {
+                                if let Some(&i) = positions.get(&visit.url) {
+                                    visits[i] = visit;
+                                    continue;
+                                }
+                                positions.insert(visit.url.clone(), visits.len());
+                                visits.push(visit);
+                            }
+                        }
+                    },
+                    Err(err) => println!("Failed to read {}: {}", path.display(), err)
+                }
+            }
+        }
+
+        // Pages forgotten while the browser ran are still in the file
+        if visits.len() > MAX_ENTRIES {
+            let mut lasts: Vec<u64> = visits.iter().map(|visit| visit.last).collect();
+            lasts.sort();
+            let cutoff = lasts[visits.len() - MAX_ENTRIES];
+            visits.retain(|visit| visit.last >= cutoff);
+        }
+
+        let compact = lines > visits.len();
+        let history = History {
+            visits: Mutex::new(visits),
+            path: path,
+        };
+        if compact {
+            history.save();
+        }
+        history
+    }
+
+    /// Record a visit to a page, skipping the browser's own pages
+    pub fn visit(&self, url: &Url, title: Option<&str>) {
+        if url.scheme() == "about" {
+            return;
+        }
+
+        // Tabs and line breaks would break up the lines of the history file
+        let title: String = title.unwrap_or("").chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        let title = title.trim();
+
+        let mut visits = self.visits.lock().unwrap();
+        let existing = visits.iter().position(|visit| visit.url == url.as_str());
+        let i = match existing {
+            Some(i) => {
+                visits[i].visits += 1;
+                visits[i].last = now();
+                if ! title.is_empty() {
+                    visits[i].title = title.to_string();
+                }
+                i
+            },
+            None => {
+                visits.push(Visit {
+                    url: url.to_string(),
+                    title: title.to_string(),
+                    visits: 1,
+                    last: now(),
+                });
+                visits.len() - 1
+            }
+        };
+        // Appended while the lock is held, so that the lines of a page stay in order
+        self.append(&visits[i]);
+
+        if visits.len() > MAX_ENTRIES {
+            let oldest = visits.iter().enumerate().min_by_key(|&(_, visit)| visit.last).map(|(i, _)| i);
+            if let Some(i) = oldest {
+                visits.remove(i);
+            }
+        }
+    }
+
+    /// The pages matching every word of a search, most recently visited first
+    pub fn search(&self, query: &str) -> Vec<Visit> {
+        let words: Vec<String> = query.split_whitespace().map(|word| word.to_lowercase()).collect();
+        let mut visits: Vec<Visit> = self.visits.lock().unwrap().iter().filter(|visit| visit.matches(&words)).cloned().collect();
+        visits.sort_by(|a, b| b.last.cmp(&a.last));
+        visits
+    }
+
+    /// Suggestions for text typed into the address bar: pages whose address starts with it come
+    /// first, then pages containing it, each ordered by how often they were visited
+    pub fn suggest(&self, text: &str, limit: usize) -> Vec<Visit> {
+        let text = text.trim().to_lowercase();
+        if text.is_empty() {
+            return Vec::new();
+        }
+
+        let words = vec![text.clone()];
+        let mut suggestions: Vec<(bool, Visit)> = self.visits.lock().unwrap().iter().filter(|visit| visit.matches(&words)).map(|visit| {
+            (strip_address(&visit.url.to_lowercase()).starts_with(strip_address(&text)), visit.clone())
+        }).collect();
+        suggestions.sort_by(|&(a_prefix, ref a), &(b_prefix, ref b)| {
+            (b_prefix, b.visits, b.last).cmp(&(a_prefix, a.visits, a.last))
+        });
+        suggestions.into_iter().take(limit).map(|(_, visit)| visit).collect()
+    }
+
+    pub fn clear(&self) {
+        self.visits.lock().unwrap().clear();
+        self.save();
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return
+        };
+
+        let mut data = String::new();
+        for visit in self.visits.lock().unwrap().iter() {
+            data.push_str(&visit.line());
+        }
+
+        let result = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(())
+        }.and_then(|_| File::create(path)).and_then(|mut file| file.write_all(data.as_bytes()));
+        if let Err(err) = result {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+
+    /// Add the line of a visit to the end of the history file
+    fn append(&self, visit: &Visit) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return
+        };
+
+        let result = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(())
+        }.and_then(|_| OpenOptions::new().create(true).append(true).open(path)).and_then(|mut file| file.write_all(visit.line().as_bytes()));
+        if let Err(err) = result {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Remove the parts of an address people leave out when typing it
+fn strip_address(address: &str) -> &str {
+    let mut address = address;
+    for prefix in ["https://", "http://"].iter() {
+        if address.starts_with(*prefix) {
+            address = &address[prefix.len()..];
+            break;
+        }
+    }
+    if address.starts_with("www.") {
+        address = &address[4..];
+    }
+    address
+}
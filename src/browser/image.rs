@@ -0,0 +1,225 @@
+use gif::{self, SetParameter};
+use orbclient::Color;
+use orbimage::{self, Image, ResizeType};
+
+/// The image formats that can be shown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+}
+
+impl ImageFormat {
+    /// Recognize a format from the first bytes of the data
+    pub fn sniff(data: &[u8]) -> Option<ImageFormat> {
+        if data.starts_with(b"\x89PNG\r\n\x1A\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(b"\xFF\xD8\xFF") {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.starts_with(b"BM") {
+            Some(ImageFormat::Bmp)
+        } else {
+            None
+        }
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match *self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Bmp => "image/x-ms-bmp",
+        }
+    }
+
+    /// Recognize a format from a media type such as image/png
+    pub fn from_media_type(media_type: &str) -> Option<ImageFormat> {
+        match &media_type.trim().to_lowercase()[..] {
+            "image/png" => Some(ImageFormat::Png),
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(ImageFormat::Jpeg),
+            "image/gif" => Some(ImageFormat::Gif),
+            "image/bmp" | "image/x-bmp" | "image/x-ms-bmp" => Some(ImageFormat::Bmp),
+            _ => None
+        }
+    }
+}
+
+/// Decode an image, trusting the data over the media type when they disagree, since servers
+/// often label images wrongly
+pub fn decode_image(data: &[u8], media_type: Option<&str>) -> Result<Image, String> {
+    let format = ImageFormat::sniff(data).or_else(|| media_type.and_then(ImageFormat::from_media_type));
+    match format {
+        Some(ImageFormat::Png) => orbimage::parse_png(data),
+        Some(ImageFormat::Jpeg) => orbimage::parse_jpg(data),
+        Some(ImageFormat::Gif) => parse_gif(data),
+        Some(ImageFormat::Bmp) => orbimage::parse_bmp(data),
+        None => Err(format!("Unsupported image type {}", media_type.unwrap_or("unknown")))
+    }
+}
+
+/// Most pixels in an image decoded or scaled, since a few bytes of a GIF header or of a page can
+/// ask for gigabytes
+const MAX_IMAGE_PIXELS: u64 = 16 * 1024 * 1024;
+
+/// Largest width or height a page can give an image
+pub const MAX_IMAGE_SIDE: u32 = 16384;
+
+/// Decode the first frame of a GIF, drawn onto a canvas the size of the whole animation
+fn parse_gif(data: &[u8]) -> Result<Image, String> {
+    let mut decoder = gif::Decoder::new(data);
+    decoder.set(gif::ColorOutput::RGBA);
+    let mut reader = decoder.read_info().map_err(|err| format!("GIF data not readable: {}", err))?;
+
+    let width = reader.width() as u32;
+    let height = reader.height() as u32;
+    if width as u64 * height as u64 > MAX_IMAGE_PIXELS {
+        return Err(format!("GIF of {}x{} is too large", width, height));
+    }
+    let mut image = Image::from_color(width, height, Color::rgba(0, 0, 0, 0));
+
+    // Check the frame size before the decoder allocates a buffer for it
+    let (left, top, frame_width, frame_height) = match reader.next_frame_info().map_err(|err| format!("GIF frame not readable: {}", err))? {
+        Some(frame) => (frame.left as u32, frame.top as u32, frame.width as u32, frame.height as u32),
+        None => return Err("GIF has no frames".to_string())
+    };
+    if frame_width as u64 * frame_height as u64 > MAX_IMAGE_PIXELS {
+        return Err(format!("GIF frame of {}x{} is too large", frame_width, frame_height));
+    }
+    let mut buffer = vec![0; reader.buffer_size()];
+    reader.read_into_buffer(&mut buffer).map_err(|err| format!("GIF frame not readable: {}", err))?;
+
+    {
+        let data = image.data_mut();
+        for y in 0..frame_height {
+            for x in 0..frame_width {
+                let canvas_x = left + x;
+                let canvas_y = top + y;
+                if canvas_x >= width || canvas_y >= height {
+                    continue;
+                }
+
+                let i = ((y * frame_width + x) * 4) as usize;
+                if i + 4 <= buffer.len() {
+                    let pixel = &buffer[i..i + 4];
+                    data[(canvas_y * width + canvas_x) as usize] = Color::rgba(pixel[0], pixel[1], pixel[2], pixel[3]);
+                }
+            }
+        }
+    }
+
+    Ok(image)
+}
+
+/// Scale an image to the width and height given in the page, keeping the aspect ratio when
+/// only one of them is given
+pub fn scale_image(image: Image, width: Option<u32>, height: Option<u32>) -> Image {
+    let (w, h) = (image.width(), image.height());
+    if w == 0 || h == 0 {
+        return image;
+    }
+
+    let (new_w, new_h) = match (width, height) {
+        (Some(width), Some(height)) => (width as u64, height as u64),
+        (Some(width), None) => (width as u64, h as u64 * width as u64 / w as u64),
+        (None, Some(height)) => (w as u64 * height as u64 / h as u64, height as u64),
+        (None, None) => return image
+    };
+
+    // Checking each side first keeps the product from overflowing, and the sides inside a u32
+    if new_w == 0 || new_h == 0 || new_w > MAX_IMAGE_PIXELS || new_h > MAX_IMAGE_PIXELS || new_w * new_h > MAX_IMAGE_PIXELS {
+        return image;
+    }
+    let (new_w, new_h) = (new_w as u32, new_h as u32);
+    if (new_w, new_h) == (w, h) {
+        return image;
+    }
+
+    match image.resize(new_w, new_h, ResizeType::Triangle) {
+        Ok(scaled) => scaled,
+        Err(err) => {
+            println!("Failed to scale image to {}x{}: {}", new_w, new_h, err);
+            image
+        }
+    }
+}
+
+/// Encode an image as an uncompressed PNG, dropping the alpha channel. The image must not be
+/// empty.
+pub fn encode_png(image: &Image) -> Vec<u8> {
+    let (w, h) = (image.width(), image.height());
+
+    // Every row starts with a filter type, which is always none
+    let mut raw = Vec::with_capacity((w as usize * 3 + 1) * h as usize);
+    for row in image.data().chunks(w as usize) {
+        raw.push(0);
+        for color in row.iter() {
+            raw.push((color.data >> 16) as u8);
+            raw.push((color.data >> 8) as u8);
+            raw.push(color.data as u8);
+        }
+    }
+
+    // A zlib stream of stored deflate blocks, which hold up to 65535 bytes each
+    let mut zlib = vec![0x78, 0x01];
+    let chunks: Vec<&[u8]> = raw.chunks(65535).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let len = chunk.len() as u16;
+        zlib.push(if i + 1 == chunks.len() { 1 } else { 0 });
+        zlib.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        zlib.extend_from_slice(chunk);
+    }
+    zlib.extend_from_slice(&be_bytes(adler32(&raw)));
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&be_bytes(w));
+    header.extend_from_slice(&be_bytes(h));
+    // Eight bits per channel, truecolor, then the default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+    png_chunk(&mut png, b"IHDR", &header);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    png.extend_from_slice(&be_bytes(data.len() as u32));
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&be_bytes(crc));
+}
+
+fn be_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data.iter() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk.iter() {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
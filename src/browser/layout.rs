@@ -0,0 +1,1869 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
+
+use html5ever::Attribute;
+use html5ever::rcdom::{Document, Doctype, Text, Comment, Element, Handle};
+use hyper::method::Method;
+use orbclient::Color;
+use orbimage::Image;
+use url::Url;
+
+use css::{self, BorderStyle, Display, Edges, Length, ListStyle, Style, Stylesheet, TextAlign, VerticalAlign, WhiteSpace};
+use fonts::{fit_end, Fonts};
+use form::{Control, ControlKind, Form, Forms};
+use image::{scale_image, MAX_IMAGE_SIDE};
+use super::Block;
+
+/// Size of the box shown while an image without alt text is loading
+const PLACEHOLDER_SIZE: i32 = 16;
+
+/// Columns between tab stops in preformatted text
+const TAB_WIDTH: usize = 8;
+
+pub enum BoxKind {
+    /// A block container, laying out its children in normal flow
+    Block,
+    /// A block container with a marker such as a bullet or number drawn to its left
+    ListItem(String),
+    /// An inline container, such as a span or a link
+    Inline,
+    /// A run of text with its whitespace already collapsed
+    Text(String),
+    /// A replaced inline element, indexing the images of the page
+    Image(usize),
+    /// A forced line break
+    LineBreak,
+    /// A table, containing row groups, rows and captions
+    Table,
+    TableRowGroup,
+    TableRow,
+    /// A table cell with its column and row span
+    TableCell(usize, usize),
+    /// A form control, indexing the controls of the page
+    Control(usize),
+}
+
+/// A node of the layout tree, built once per page and laid out again on every resize
+pub struct LayoutBox {
+    pub kind: BoxKind,
+    pub style: Style,
+    pub link: Option<String>,
+    pub anchor: Option<String>,
+    pub children: Vec<LayoutBox>,
+}
+
+impl LayoutBox {
+    pub fn new(kind: BoxKind, style: Style) -> LayoutBox {
+        LayoutBox {
+            kind: kind,
+            style: style,
+            link: None,
+            anchor: None,
+            children: Vec::new(),
+        }
+    }
+
+    fn root() -> LayoutBox {
+        let mut style = Style::default();
+        style.display = Display::Block;
+        LayoutBox::new(BoxKind::Block, style)
+    }
+
+    /// A page showing a single bold message
+    pub fn message(string: &str) -> LayoutBox {
+        let mut root = LayoutBox::root();
+
+        let mut style = Style::default();
+        style.bold = true;
+        root.children.push(LayoutBox::new(BoxKind::Text(string.to_string()), style));
+
+        root
+    }
+
+    /// A page of plain text, keeping its line breaks and indentation
+    pub fn text(string: &str, style: &Style) -> LayoutBox {
+        let mut root = LayoutBox::root();
+
+        for line in string.lines() {
+            root.children.push(LayoutBox::new(BoxKind::Text(expand_tabs(line)), style.clone()));
+            root.children.push(LayoutBox::new(BoxKind::LineBreak, style.clone()));
+        }
+
+        root
+    }
+}
+
+/// An image used by a page, filled in once it has been downloaded
+pub struct PageImage {
+    pub url: Url,
+    pub image: Option<Image>,
+    /// Text to show while the image is missing
+    pub alt: Option<String>,
+    /// The size from the width and height attributes, which the image is scaled to
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub failed: bool,
+    /// The image scaled for the zoom level it was last laid out at, so that it is only scaled
+    /// again when the zoom changes
+    pub zoomed: RefCell<Option<(f32, Image)>>,
+}
+
+impl PageImage {
+    /// The size of the box kept in place until the image arrives
+    fn placeholder_size(&self) -> (i32, i32) {
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => (width as i32, height as i32),
+            _ => (PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)
+        }
+    }
+}
+
+/// A laid out document together with the state of its forms and images
+pub struct Page {
+    pub root: LayoutBox,
+    pub forms: Forms,
+    pub images: Vec<PageImage>,
+    /// The contents of the title element
+    pub title: Option<String>,
+    /// The URL the page was loaded from after following redirects, when known
+    pub url: Option<Url>,
+}
+
+impl Page {
+    pub fn new(root: LayoutBox) -> Page {
+        Page {
+            root: root,
+            forms: Forms::new(),
+            images: Vec::new(),
+            title: None,
+            url: None,
+        }
+    }
+
+    /// A page showing a single image
+    pub fn image(url: &Url, image: Image) -> Page {
+        let mut root = LayoutBox::root();
+        root.children.push(LayoutBox::new(BoxKind::Image(0), Style::default()));
+
+        let mut page = Page::new(root);
+        page.images.push(PageImage {
+            url: url.clone(),
+            image: Some(image),
+            alt: None,
+            width: None,
+            height: None,
+            failed: false,
+            zoomed: RefCell::new(None),
+        });
+        page
+    }
+
+    /// The images that still have to be downloaded, with the size to scale each to
+    pub fn pending_images(&self) -> Vec<(usize, Url, Option<u32>, Option<u32>)> {
+        let mut pending = Vec::new();
+        for (i, page_image) in self.images.iter().enumerate() {
+            if page_image.image.is_none() && ! page_image.failed {
+                pending.push((i, page_image.url.clone(), page_image.width, page_image.height));
+            }
+        }
+        pending
+    }
+
+    /// The colour of the canvas around the page, which as in CSS is the background of the
+    /// root element, or else of the body
+    pub fn background(&self) -> Option<Color> {
+        let mut layout_box = &self.root;
+        for _ in 0..3 {
+            if layout_box.style.background.is_some() {
+                return layout_box.style.background;
+            }
+            match layout_box.children.iter().find(|child| if let BoxKind::Block = child.kind { true } else { false }) {
+                Some(child) => layout_box = child,
+                None => break
+            }
+        }
+        None
+    }
+}
+
+/// Build the layout tree for a parsed document
+pub fn build(document: &Handle, url: &Url, stylesheet: &Stylesheet) -> Page {
+    let mut root = LayoutBox::root();
+    let mut builder = Builder {
+        url: url,
+        stylesheet: stylesheet,
+        whitespace: true,
+        counter: 1,
+        counter_step: 1,
+        forms: Forms::new(),
+        form: None,
+        images: Vec::new(),
+        title: None,
+    };
+    builder.node(document, &root.style, &None, &mut root.children);
+
+    Page {
+        root: root,
+        forms: builder.forms,
+        images: builder.images,
+        title: builder.title,
+        url: None,
+    }
+}
+
+/// Parse a width or height attribute of an image in pixels, ignoring percentages
+fn image_dimension(value: &str) -> Option<u32> {
+    match value.trim().trim_right_matches("px").parse::<u32>() {
+        Ok(0) | Err(_) => None,
+        Ok(size) => Some(cmp::min(size, MAX_IMAGE_SIDE))
+    }
+}
+
+/// Replace tabs with spaces up to the next tab stop
+fn expand_tabs(line: &str) -> String {
+    let mut string = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = TAB_WIDTH - column % TAB_WIDTH;
+            for _ in 0..spaces {
+                string.push(' ');
+            }
+            column += spaces;
+        } else {
+            string.push(c);
+            column += 1;
+        }
+    }
+    string
+}
+
+/// Format the marker of a list item, the bullet or the number with its dot
+fn list_marker(list_style: ListStyle, n: i32) -> String {
+    match list_style {
+        ListStyle::None => String::new(),
+        ListStyle::Disc => "\u{2022}".to_string(),
+        ListStyle::Circle => "\u{25E6}".to_string(),
+        ListStyle::Square => "\u{25AA}".to_string(),
+        ListStyle::Decimal => format!("{}.", n),
+        ListStyle::LowerAlpha | ListStyle::UpperAlpha if n > 0 => {
+            let mut letters = Vec::new();
+            let mut n = n;
+            while n > 0 {
+                letters.push((b'a' + ((n - 1) % 26) as u8) as char);
+                n = (n - 1) / 26;
+            }
+            let string: String = letters.into_iter().rev().collect();
+            if list_style == ListStyle::UpperAlpha {
+                format!("{}.", string.to_uppercase())
+            } else {
+                format!("{}.", string)
+            }
+        },
+        ListStyle::LowerRoman | ListStyle::UpperRoman if n > 0 && n < 4000 => {
+            let numerals = [
+                (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+                (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i")
+            ];
+            let mut string = String::new();
+            let mut n = n;
+            for &(value, numeral) in numerals.iter() {
+                while n >= value {
+                    string.push_str(numeral);
+                    n -= value;
+                }
+            }
+            if list_style == ListStyle::UpperRoman {
+                format!("{}.", string.to_uppercase())
+            } else {
+                format!("{}.", string)
+            }
+        },
+        // Numbers that cannot be written in letters or numerals
+        _ => format!("{}.", n)
+    }
+}
+
+/// Collect the text inside a node, for textareas, options and buttons
+fn text_content(handle: &Handle, string: &mut String) {
+    let node = handle.borrow();
+    match node.node {
+        Text(ref text) => string.push_str(text),
+        _ => for child in node.children.iter() {
+            text_content(child, string);
+        }
+    }
+}
+
+/// Collect the options of a select, including those inside optgroups
+fn select_options(handle: &Handle, control: &mut Control) {
+    let node = handle.borrow();
+    for child in node.children.iter() {
+        if let Element(ref name, _, ref attrs) = child.borrow().node {
+            match &*name.local {
+                "option" => {
+                    let mut label = String::new();
+                    text_content(child, &mut label);
+                    let label = label.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+                    let mut value = label.clone();
+                    for attr in attrs.iter() {
+                        match &*attr.name.local {
+                            "value" => value = attr.value.to_string(),
+                            "selected" => control.selected = control.options.len(),
+                            _ => ()
+                        }
+                    }
+
+                    control.options.push((label, value));
+                },
+                "optgroup" => select_options(child, control),
+                _ => ()
+            }
+        }
+    }
+}
+
+struct Builder<'b> {
+    url: &'b Url,
+    stylesheet: &'b Stylesheet,
+    /// Was the last character whitespace, or the start of a block
+    whitespace: bool,
+    /// The number of the next item of the list being built, and how it changes per item
+    counter: i32,
+    counter_step: i32,
+    forms: Forms,
+    /// The form that new controls belong to
+    form: Option<usize>,
+    images: Vec<PageImage>,
+    title: Option<String>,
+}
+
+impl<'b> Builder<'b> {
+    fn node(&mut self, handle: &Handle, parent_style: &Style, parent_link: &Option<String>, boxes: &mut Vec<LayoutBox>) {
+        let node = handle.borrow();
+
+        match node.node {
+            Document => for child in node.children.iter() {
+                self.node(child, parent_style, parent_link, boxes);
+            },
+
+            Doctype(..) | Comment(..) => (),
+
+            Text(ref text) => if parent_style.white_space == WhiteSpace::Pre {
+                // Every line is kept as written, with line breaks in between
+                for (line_i, line) in text.split('\n').enumerate() {
+                    if line_i > 0 {
+                        boxes.push(LayoutBox::new(BoxKind::LineBreak, parent_style.clone()));
+                    }
+
+                    let line = expand_tabs(line.trim_right_matches('\r'));
+                    if ! line.is_empty() {
+                        let mut text_box = LayoutBox::new(BoxKind::Text(line), parent_style.clone());
+                        text_box.link = parent_link.clone();
+                        boxes.push(text_box);
+                    }
+                }
+
+                self.whitespace = text.ends_with(char::is_whitespace);
+            } else {
+                let mut string = String::new();
+
+                for c in text.chars() {
+                    match c {
+                        ' ' | '\n' | '\r' | '\t' => if ! self.whitespace {
+                            self.whitespace = true;
+                            string.push(' ');
+                        },
+                        _ => {
+                            self.whitespace = false;
+                            string.push(c);
+                        }
+                    }
+                }
+
+                if ! string.is_empty() {
+                    let mut text_box = LayoutBox::new(BoxKind::Text(string), parent_style.clone());
+                    text_box.link = parent_link.clone();
+                    boxes.push(text_box);
+                }
+            },
+
+            Element(ref name, _, ref attrs) => {
+                if &*name.local == "title" && self.title.is_none() {
+                    let mut title = String::new();
+                    text_content(handle, &mut title);
+                    self.title = Some(title.split_whitespace().collect::<Vec<&str>>().join(" "));
+                }
+
+                let style = self.stylesheet.style(handle, parent_style);
+                if style.display == Display::None {
+                    return;
+                }
+
+                let mut style = style;
+                let mut link = parent_link.clone();
+                let mut anchor = None;
+                let mut src_opt = None;
+                let mut alt_opt = None;
+                let mut img_width = None;
+                let mut img_height = None;
+                let mut colspan = 1;
+                let mut rowspan = 1;
+                let mut table_border = None;
+                let mut cell_padding = None;
+                let mut list_start = None;
+                let mut list_reversed = false;
+                let mut item_value = None;
+                for attr in attrs.iter() {
+                    match &*attr.name.local {
+                        "id" => anchor = Some(attr.value.to_string()),
+                        "name" if &*name.local == "a" => anchor = Some(attr.value.to_string()),
+                        "href" if &*name.local == "a" => link = Some(attr.value.to_string()),
+                        "src" => src_opt = Some(attr.value.to_string()),
+                        "alt" => alt_opt = Some(attr.value.to_string()),
+                        "colspan" => colspan = cmp::max(1, cmp::min(1000, attr.value.trim().parse::<usize>().unwrap_or(1))),
+                        // A row span of zero extends the cell to the end of the table
+                        "rowspan" => rowspan = match attr.value.trim().parse::<usize>().unwrap_or(1) {
+                            0 => 65534,
+                            n => cmp::min(65534, n)
+                        },
+                        "border" if &*name.local == "table" => table_border = Some(attr.value.trim().parse::<i32>().unwrap_or(1)),
+                        "cellpadding" if &*name.local == "table" => cell_padding = attr.value.trim().parse::<f32>().ok(),
+                        "cellspacing" if &*name.local == "table" => if style.border_spacing == 2 {
+                            if let Ok(spacing) = attr.value.trim().parse::<i32>() {
+                                style.border_spacing = spacing;
+                            }
+                        },
+                        "bgcolor" => if style.background.is_none() {
+                            style.background = css::parse_color(&attr.value.to_lowercase());
+                        },
+                        "width" if &*name.local == "img" => img_width = image_dimension(&attr.value),
+                        "height" if &*name.local == "img" => img_height = image_dimension(&attr.value),
+                        "width" if style.width == Length::Auto && &*name.local != "img" => {
+                            style.width = css::parse_length(&attr.value, style.font_size).unwrap_or(Length::Auto);
+                        },
+                        "align" if &*name.local == "table" => if attr.value.to_lowercase() == "center" {
+                            style.margin.left = Length::Auto;
+                            style.margin.right = Length::Auto;
+                        },
+                        "start" if &*name.local == "ol" => list_start = attr.value.trim().parse::<i32>().ok(),
+                        "reversed" if &*name.local == "ol" => list_reversed = true,
+                        "value" if &*name.local == "li" => item_value = attr.value.trim().parse::<i32>().ok(),
+                        // Unlike most attributes, the case of a list type matters
+                        "type" if &*name.local == "ol" || &*name.local == "ul" || &*name.local == "li" => match attr.value.trim() {
+                            "1" => style.list_style = ListStyle::Decimal,
+                            "a" => style.list_style = ListStyle::LowerAlpha,
+                            "A" => style.list_style = ListStyle::UpperAlpha,
+                            "i" => style.list_style = ListStyle::LowerRoman,
+                            "I" => style.list_style = ListStyle::UpperRoman,
+                            value => match &*value.to_lowercase() {
+                                "disc" => style.list_style = ListStyle::Disc,
+                                "circle" => style.list_style = ListStyle::Circle,
+                                "square" => style.list_style = ListStyle::Square,
+                                _ => ()
+                            }
+                        },
+                        "align" if &*name.local == "td" || &*name.local == "th" => match &*attr.value.to_lowercase() {
+                            "left" => style.text_align = TextAlign::Left,
+                            "center" => style.text_align = TextAlign::Center,
+                            "right" => style.text_align = TextAlign::Right,
+                            _ => ()
+                        },
+                        _ => ()
+                    }
+                }
+
+                if let Some(border) = table_border {
+                    if style.border_style == BorderStyle::None && border > 0 {
+                        style.border_width = border;
+                        style.border_style = BorderStyle::Solid;
+                        style.border_color = Some(Color::rgb(128, 128, 128));
+                    }
+                }
+
+                match &*name.local {
+                    "br" => {
+                        self.whitespace = true;
+                        let mut break_box = LayoutBox::new(BoxKind::LineBreak, style);
+                        break_box.anchor = anchor;
+                        boxes.push(break_box);
+                        return;
+                    },
+                    "img" => {
+                        // Images are downloaded later, so only the URL is resolved here
+                        let kind = match src_opt.and_then(|src| image_url(&src, self.url)) {
+                            Some(image_url) => {
+                                self.images.push(PageImage {
+                                    url: image_url,
+                                    image: None,
+                                    alt: alt_opt,
+                                    width: img_width,
+                                    height: img_height,
+                                    failed: false,
+                                    zoomed: RefCell::new(None),
+                                });
+                                BoxKind::Image(self.images.len() - 1)
+                            },
+                            None => match alt_opt {
+                                Some(alt) => BoxKind::Text(alt),
+                                None => return
+                            }
+                        };
+
+                        self.whitespace = false;
+                        let mut image_box = LayoutBox::new(kind, style);
+                        image_box.link = link;
+                        image_box.anchor = anchor;
+                        boxes.push(image_box);
+                        return;
+                    },
+                    "input" | "textarea" | "select" | "button" => {
+                        if let Some(i) = self.control(handle, &*name.local, attrs) {
+                            self.whitespace = false;
+                            let mut control_box = LayoutBox::new(BoxKind::Control(i), style);
+                            control_box.anchor = anchor;
+                            boxes.push(control_box);
+                        }
+                        return;
+                    },
+                    _ => ()
+                }
+
+                let kind = match style.display {
+                    Display::Inline => BoxKind::Inline,
+                    Display::Table => BoxKind::Table,
+                    Display::TableRowGroup => BoxKind::TableRowGroup,
+                    Display::TableRow => BoxKind::TableRow,
+                    Display::TableCell => BoxKind::TableCell(colspan, rowspan),
+                    Display::ListItem => {
+                        if let Some(value) = item_value {
+                            self.counter = value;
+                        }
+                        let marker = list_marker(style.list_style, self.counter);
+                        self.counter += self.counter_step;
+                        BoxKind::ListItem(marker)
+                    },
+                    _ => BoxKind::Block
+                };
+
+                let block = style.display != Display::Inline;
+                if block {
+                    self.whitespace = true;
+                }
+
+                // Forms do not nest, so the outer one keeps its controls until it ends
+                let parent_form = self.form;
+                if &*name.local == "form" && parent_form.is_none() {
+                    let mut form = Form {
+                        action: String::new(),
+                        method: Method::Get,
+                    };
+                    for attr in attrs.iter() {
+                        match &*attr.name.local {
+                            "action" => form.action = attr.value.trim().to_string(),
+                            "method" => if attr.value.trim().to_lowercase() == "post" {
+                                form.method = Method::Post;
+                            },
+                            _ => ()
+                        }
+                    }
+                    self.forms.forms.push(form);
+                    self.form = Some(self.forms.forms.len() - 1);
+                }
+
+                // Lists number their own items, counting down from the number of items if reversed
+                let parent_counter = (self.counter, self.counter_step);
+                let list = match &*name.local {
+                    "ol" | "ul" | "menu" | "dir" => true,
+                    _ => false
+                };
+                if list {
+                    let items = node.children.iter().filter(|child| match child.borrow().node {
+                        Element(ref name, _, _) => &*name.local == "li",
+                        _ => false
+                    }).count() as i32;
+                    self.counter = list_start.unwrap_or(if list_reversed { items } else { 1 });
+                    self.counter_step = if list_reversed { -1 } else { 1 };
+                }
+
+                let mut layout_box = LayoutBox::new(kind, style);
+                layout_box.link = link;
+                layout_box.anchor = anchor;
+                for child in node.children.iter() {
+                    self.node(child, &layout_box.style, &layout_box.link, &mut layout_box.children);
+                }
+
+                self.form = parent_form;
+                if list {
+                    self.counter = parent_counter.0;
+                    self.counter_step = parent_counter.1;
+                }
+
+                if block {
+                    self.whitespace = true;
+                }
+
+                if let BoxKind::Table = layout_box.kind {
+                    table_hints(&mut layout_box, table_border.unwrap_or(0), cell_padding);
+                }
+
+                boxes.push(layout_box);
+            }
+        }
+    }
+
+    /// Create the state for a form control, returning its index if it should be drawn
+    fn control(&mut self, handle: &Handle, tag: &str, attrs: &[Attribute]) -> Option<usize> {
+        let mut kind = match tag {
+            "textarea" => ControlKind::TextArea,
+            "select" => ControlKind::Select,
+            "button" => ControlKind::Submit,
+            _ => ControlKind::Text
+        };
+
+        for attr in attrs.iter() {
+            if &*attr.name.local == "type" {
+                kind = match (tag, &*attr.value.to_lowercase()) {
+                    ("input", "password") => ControlKind::Password,
+                    ("input", "hidden") => ControlKind::Hidden,
+                    ("input", "checkbox") => ControlKind::Checkbox,
+                    ("input", "radio") => ControlKind::Radio,
+                    ("input", "submit") | ("input", "image") | ("button", "submit") => ControlKind::Submit,
+                    ("input", "reset") | ("button", "reset") => ControlKind::Reset,
+                    ("input", "button") | ("button", "button") => ControlKind::Button,
+                    _ => kind
+                };
+            }
+        }
+
+        let mut control = Control::new(kind, self.form);
+        for attr in attrs.iter() {
+            match &*attr.name.local {
+                "name" => control.name = attr.value.to_string(),
+                "value" => control.value = attr.value.to_string(),
+                "checked" => control.checked = true,
+                "size" | "cols" => if let Ok(size) = attr.value.trim().parse::<usize>() {
+                    control.size = cmp::max(1, cmp::min(1000, size));
+                },
+                "rows" => if let Ok(rows) = attr.value.trim().parse::<usize>() {
+                    control.rows = cmp::max(1, cmp::min(1000, rows));
+                },
+                _ => ()
+            }
+        }
+
+        match tag {
+            "textarea" => {
+                text_content(handle, &mut control.value);
+                // A single leading newline is dropped by the parser rules
+                if control.value.starts_with('\n') {
+                    control.value.remove(0);
+                }
+                if ! attrs.iter().any(|attr| &*attr.name.local == "cols") {
+                    control.size = 20;
+                }
+            },
+            "select" => select_options(handle, &mut control),
+            "button" => {
+                let mut label = String::new();
+                text_content(handle, &mut label);
+                control.label = label.split_whitespace().collect::<Vec<&str>>().join(" ");
+            },
+            _ => match kind {
+                ControlKind::Submit | ControlKind::Reset | ControlKind::Button => control.label = control.value.clone(),
+                _ => ()
+            }
+        }
+
+        control.default_value = control.value.clone();
+        control.default_checked = control.checked;
+        control.default_selected = control.selected;
+
+        let visible = kind != ControlKind::Hidden;
+        self.forms.controls.push(control);
+        if visible {
+            Some(self.forms.controls.len() - 1)
+        } else {
+            None
+        }
+    }
+}
+
+/// Apply the border and cellpadding attributes of a table to its cells
+fn table_hints(layout_box: &mut LayoutBox, border: i32, cell_padding: Option<f32>) {
+    for child in layout_box.children.iter_mut() {
+        match child.kind {
+            BoxKind::TableRowGroup | BoxKind::TableRow => table_hints(child, border, cell_padding),
+            BoxKind::TableCell(..) => {
+                if border > 0 && child.style.border_style == BorderStyle::None {
+                    child.style.border_width = 1;
+                    child.style.border_style = BorderStyle::Solid;
+                    child.style.border_color = Some(Color::rgb(128, 128, 128));
+                }
+
+                if let Some(padding) = cell_padding {
+                    let default_padding = Length::Px(1.0);
+                    if child.style.padding == (Edges { top: default_padding, right: default_padding, bottom: default_padding, left: default_padding }) {
+                        let padding = Length::Px(padding);
+                        child.style.padding = Edges { top: padding, right: padding, bottom: padding, left: padding };
+                    }
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Resolve the source of an image. The format is found once it has been downloaded.
+fn image_url(src: &str, url: &Url) -> Option<Url> {
+    match url.join(src) {
+        Ok(img_url) => Some(img_url),
+        Err(err) => {
+            println!("Invalid image URL {}: {}", src, err);
+            None
+        }
+    }
+}
+
+/// Lay out a layout tree at the given viewport width, producing the blocks to draw. Fonts,
+/// images and lengths in pixels are scaled by zoom.
+pub fn layout<'a>(page: &Page, focus: Option<usize>, width: i32, zoom: f32, fonts: &'a Fonts, anchors: &mut BTreeMap<String, i32>, blocks: &mut Vec<Block<'a>>) {
+    let mut context = Context {
+        fonts: fonts,
+        zoom: zoom,
+        page: page,
+        focus: focus,
+        anchors: anchors,
+        blocks: blocks,
+    };
+    context.block(&page.root, 0, 0, width);
+}
+
+/// A piece of a line box, positioned relative to the line
+struct Fragment<'a> {
+    block: Block<'a>,
+    ascent: i32,
+    /// Blocks drawn on top, positioned relative to the fragment
+    inner: Vec<Block<'a>>,
+}
+
+/// Normal flow inside a block container: block boxes stacked with line boxes in between
+struct Flow<'a> {
+    left: i32,
+    width: i32,
+    align: TextAlign,
+    /// Top of the next line or block
+    y: i32,
+    /// Bottom margin of the previous block, collapsed with the next top margin
+    pending_margin: i32,
+    /// Width of the current line so far
+    x: i32,
+    /// Whitespace to insert before the next fragment, dropped at line breaks
+    pending_space: i32,
+    fragments: Vec<Fragment<'a>>,
+    anchors: Vec<String>,
+}
+
+impl<'a> Flow<'a> {
+    fn new(left: i32, y: i32, width: i32, align: TextAlign) -> Flow<'a> {
+        Flow {
+            left: left,
+            width: width,
+            align: align,
+            y: y,
+            pending_margin: 0,
+            x: 0,
+            pending_space: 0,
+            fragments: Vec::new(),
+            anchors: Vec::new(),
+        }
+    }
+
+    fn fits(&self, w: i32) -> bool {
+        self.fragments.is_empty() || self.x + self.pending_space + w <= self.width
+    }
+}
+
+/// A table cell laid out with its top at 0
+struct LaidOutCell<'a> {
+    height: i32,
+    blocks: Vec<Block<'a>>,
+    anchors: BTreeMap<String, i32>,
+}
+
+struct Context<'a, 'c> {
+    fonts: &'a Fonts,
+    zoom: f32,
+    page: &'c Page,
+    /// The control with keyboard focus, drawn with a highlighted border
+    focus: Option<usize>,
+    anchors: &'c mut BTreeMap<String, i32>,
+    blocks: &'c mut Vec<Block<'a>>,
+}
+
+impl<'a, 'c> Context<'a, 'c> {
+    fn font_size(&self, style: &Style) -> f32 {
+        style.font_size * self.zoom
+    }
+
+    /// Resolve a length, where percentages refer to a reference that is already zoomed
+    fn resolve(&self, length: Length, reference: i32) -> i32 {
+        match length {
+            Length::Px(px) => (px * self.zoom).round() as i32,
+            _ => length.resolve(reference)
+        }
+    }
+
+    fn zoomed(&self, size: i32) -> i32 {
+        (size as f32 * self.zoom).round() as i32
+    }
+
+    fn border(&self, style: &Style) -> Option<(i32, Color)> {
+        style.border().map(|(width, color)| (cmp::max(1, self.zoomed(width)), color))
+    }
+
+    fn spacing(&self, style: &Style) -> i32 {
+        if style.border_collapse {
+            0
+        } else {
+            cmp::max(0, self.zoomed(style.border_spacing))
+        }
+    }
+
+    /// Horizontal margins, borders and padding of a box, with percentages ignored
+    fn horizontal_extra(&self, style: &Style) -> i32 {
+        let border_width = self.border(style).map_or(0, |border| border.0);
+        self.resolve(style.margin.left, 0) + self.resolve(style.margin.right, 0) +
+            self.resolve(style.padding.left, 0) + self.resolve(style.padding.right, 0) +
+            2 * border_width
+    }
+
+    /// Lay out a block box with its top border edge at y, returning its height without margins
+    fn block(&mut self, layout_box: &LayoutBox, containing_left: i32, y: i32, containing_width: i32) -> i32 {
+        let style = &layout_box.style;
+        let border = self.border(style);
+        let border_width = border.map_or(0, |border| border.0);
+        let padding_top = self.resolve(style.padding.top, containing_width);
+        let padding_right = self.resolve(style.padding.right, containing_width);
+        let padding_bottom = self.resolve(style.padding.bottom, containing_width);
+        let padding_left = self.resolve(style.padding.left, containing_width);
+        let mut margin_left = self.resolve(style.margin.left, containing_width);
+        let margin_right = self.resolve(style.margin.right, containing_width);
+
+        let auto_width = containing_width - margin_left - margin_right - padding_left - padding_right - 2 * border_width;
+        let mut width = match style.width {
+            Length::Auto => auto_width,
+            ref length => self.resolve(*length, containing_width)
+        };
+        if style.max_width != Length::Auto {
+            width = cmp::min(width, self.resolve(style.max_width, containing_width));
+        }
+
+        // Auto margins take up the space left over by an explicit width
+        if width < auto_width {
+            match (style.margin.left, style.margin.right) {
+                (Length::Auto, Length::Auto) => margin_left += (auto_width - width) / 2,
+                (Length::Auto, _) => margin_left += auto_width - width,
+                _ => ()
+            }
+        }
+        let width = cmp::max(0, width);
+
+        let border_left = containing_left + margin_left;
+        let background_i = if style.background.is_some() || border.is_some() {
+            self.blocks.push(Block {
+                x: border_left,
+                y: y,
+                w: 2 * border_width + padding_left + width + padding_right,
+                h: 0,
+                color: style.color,
+                background: style.background,
+                border: border,
+                string: String::new(),
+                link: None,
+                control: None,
+                image: None,
+                text: None
+            });
+            Some(self.blocks.len() - 1)
+        } else {
+            None
+        };
+
+        let content_left = border_left + border_width + padding_left;
+        let content_top = y + border_width + padding_top;
+        let first_block = self.blocks.len();
+        let content_height = self.flow_children(layout_box, content_left, content_top, width);
+
+        if let BoxKind::ListItem(ref marker) = layout_box.kind {
+            self.marker(marker, style, content_left, content_top, first_block);
+        }
+
+        let height = 2 * border_width + padding_top + content_height + padding_bottom;
+        if let Some(i) = background_i {
+            self.blocks[i].h = height;
+        }
+        height
+    }
+
+    /// Lay out the children of a box in normal flow, returning the height of the content
+    fn flow_children(&mut self, layout_box: &LayoutBox, left: i32, top: i32, width: i32) -> i32 {
+        let mut flow = Flow::new(left, top, width, layout_box.style.text_align);
+        for child in layout_box.children.iter() {
+            self.flow_box(child, &mut flow);
+        }
+        self.finish_line(&mut flow, 0);
+
+        flow.y + flow.pending_margin - top
+    }
+
+    /// Lay out a table with its top border edge at y, returning its height without margins
+    fn table(&mut self, layout_box: &LayoutBox, containing_left: i32, y: i32, containing_width: i32) -> i32 {
+        let style = &layout_box.style;
+        let grid = table_grid(layout_box);
+        let border = self.border(style);
+        let border_width = border.map_or(0, |border| border.0);
+        let spacing = self.spacing(style);
+        let padding_top = self.resolve(style.padding.top, containing_width);
+        let padding_right = self.resolve(style.padding.right, containing_width);
+        let padding_bottom = self.resolve(style.padding.bottom, containing_width);
+        let padding_left = self.resolve(style.padding.left, containing_width);
+        let mut margin_left = self.resolve(style.margin.left, containing_width);
+        let margin_right = self.resolve(style.margin.right, containing_width);
+
+        let (mins, maxs) = self.column_widths(&grid, spacing);
+        let min_sum = mins.iter().fold(0, |sum, w| sum + w);
+        let max_sum = maxs.iter().fold(0, |sum, w| sum + w);
+
+        // Borders, padding and the spacing around each column
+        let chrome = 2 * border_width + padding_left + padding_right + spacing * (grid.columns as i32 + 1);
+        let target = match style.width {
+            Length::Auto => cmp::min(containing_width - margin_left - margin_right - chrome, max_sum),
+            ref length => self.resolve(*length, containing_width) - chrome
+        };
+        let target = cmp::max(target, min_sum);
+
+        let mut widths = mins.clone();
+        if target >= max_sum {
+            for (i, width) in widths.iter_mut().enumerate() {
+                *width = maxs[i];
+                if max_sum > 0 {
+                    *width += (target - max_sum) * maxs[i] / max_sum;
+                }
+            }
+        } else if max_sum > min_sum {
+            for (i, width) in widths.iter_mut().enumerate() {
+                *width += (maxs[i] - mins[i]) * (target - min_sum) / (max_sum - min_sum);
+            }
+        }
+        // Rounding leftovers go to the last column
+        let sum = widths.iter().fold(0, |sum, w| sum + w);
+        if let Some(last) = widths.last_mut() {
+            *last += target - sum;
+        }
+
+        let outer_width = if grid.columns > 0 { target + chrome } else { 2 * border_width + padding_left + padding_right };
+        if let (Length::Auto, Length::Auto) = (style.margin.left, style.margin.right) {
+            margin_left = cmp::max(0, (containing_width - outer_width) / 2);
+        }
+        let table_left = containing_left + margin_left;
+
+        // Captions sit above the table box
+        let mut top = y;
+        if ! grid.captions.is_empty() {
+            let mut flow = Flow::new(table_left, top, outer_width, style.text_align);
+            for caption in grid.captions.iter() {
+                self.flow_box(caption, &mut flow);
+            }
+            self.finish_line(&mut flow, 0);
+            top = flow.y + flow.pending_margin;
+        }
+
+        let background_i = if style.background.is_some() || border.is_some() {
+            self.blocks.push(Block {
+                x: table_left,
+                y: top,
+                w: outer_width,
+                h: 0,
+                color: style.color,
+                background: style.background,
+                border: border,
+                string: String::new(),
+                link: None,
+                control: None,
+                image: None,
+                text: None
+            });
+            Some(self.blocks.len() - 1)
+        } else {
+            None
+        };
+
+        let mut column_x = Vec::with_capacity(grid.columns + 1);
+        let mut x = table_left + border_width + padding_left + spacing;
+        for width in widths.iter() {
+            column_x.push(x);
+            x += width + spacing;
+        }
+        column_x.push(x);
+
+        let span_width = |col: usize, colspan: usize| column_x[col + colspan] - column_x[col] - spacing;
+
+        // Lay out every cell first, so that rows know their heights, and move the cells down into
+        // their rows afterwards. Laying out each cell only once keeps nested tables from taking
+        // time exponential in their depth.
+        let mut cells = Vec::with_capacity(grid.cells.len());
+        for cell in grid.cells.iter() {
+            cells.push(self.layout_cell(cell.layout_box, column_x[cell.col], span_width(cell.col, cell.colspan)));
+        }
+
+        let mut heights = vec![0; grid.rows.len()];
+        for (i, cell) in grid.cells.iter().enumerate() {
+            if cell.rowspan == 1 {
+                heights[cell.row] = cmp::max(heights[cell.row], cells[i].height);
+            }
+        }
+        for (i, cell) in grid.cells.iter().enumerate() {
+            if cell.rowspan > 1 {
+                let last = cell.row + cell.rowspan - 1;
+                let current = heights[cell.row .. last + 1].iter().fold(0, |sum, h| sum + h) + spacing * (cell.rowspan as i32 - 1);
+                if cells[i].height > current {
+                    heights[last] += cells[i].height - current;
+                }
+            }
+        }
+
+        let mut row_y = Vec::with_capacity(grid.rows.len() + 1);
+        let mut row_top = top + border_width + padding_top + spacing;
+        for height in heights.iter() {
+            row_y.push(row_top);
+            row_top += height + spacing;
+        }
+        row_y.push(row_top);
+
+        let span_height = |row: usize, rowspan: usize| row_y[row + rowspan] - row_y[row] - spacing;
+
+        for (row, row_box_opt) in grid.rows.iter().enumerate() {
+            if let Some(row_box) = *row_box_opt {
+                if let Some(ref anchor) = row_box.anchor {
+                    self.anchors.insert(anchor.clone(), row_y[row]);
+                }
+
+                if let (Some(background), true) = (row_box.style.background, grid.columns > 0) {
+                    self.blocks.push(Block {
+                        x: column_x[0],
+                        y: row_y[row],
+                        w: span_width(0, grid.columns),
+                        h: heights[row],
+                        color: row_box.style.color,
+                        background: Some(background),
+                        border: None,
+                        string: String::new(),
+                        link: None,
+                        control: None,
+                        image: None,
+                        text: None
+                    });
+                }
+            }
+        }
+
+        for (cell, laid_out) in grid.cells.iter().zip(cells.into_iter()) {
+            let cell_style = &cell.layout_box.style;
+            let cell_x = column_x[cell.col];
+            let cell_y = row_y[cell.row];
+            let cell_w = span_width(cell.col, cell.colspan);
+            let cell_h = span_height(cell.row, cell.rowspan);
+
+            if let Some(ref anchor) = cell.layout_box.anchor {
+                self.anchors.insert(anchor.clone(), cell_y);
+            }
+
+            let cell_border = self.border(cell_style);
+            if cell_style.background.is_some() || cell_border.is_some() {
+                self.blocks.push(Block {
+                    x: cell_x,
+                    y: cell_y,
+                    w: cell_w,
+                    h: cell_h,
+                    color: cell_style.color,
+                    background: cell_style.background,
+                    border: cell_border,
+                    string: String::new(),
+                    link: None,
+                    control: None,
+                    image: None,
+                    text: None
+                });
+            }
+
+            // Cell contents are vertically centered in the rows they span
+            let content_y = cell_y + cmp::max(0, (cell_h - laid_out.height) / 2);
+            for mut block in laid_out.blocks.into_iter() {
+                block.y += content_y;
+                self.blocks.push(block);
+            }
+            for (anchor, anchor_y) in laid_out.anchors.into_iter() {
+                self.anchors.insert(anchor, content_y + anchor_y);
+            }
+        }
+
+        let inner_height = if grid.rows.is_empty() { 0 } else { row_y[grid.rows.len()] - row_y[0] + spacing };
+        let table_height = 2 * border_width + padding_top + inner_height + padding_bottom;
+        if let Some(i) = background_i {
+            self.blocks[i].h = table_height;
+        }
+
+        top - y + table_height
+    }
+
+    /// Lay out the contents of a table cell inside its border box, returning the height it needs
+    fn cell(&mut self, layout_box: &LayoutBox, left: i32, top: i32, width: i32) -> i32 {
+        let style = &layout_box.style;
+        let border_width = self.border(style).map_or(0, |border| border.0);
+        let padding_top = self.resolve(style.padding.top, width);
+        let padding_right = self.resolve(style.padding.right, width);
+        let padding_bottom = self.resolve(style.padding.bottom, width);
+        let padding_left = self.resolve(style.padding.left, width);
+
+        let content_width = cmp::max(0, width - 2 * border_width - padding_left - padding_right);
+        let content_height = self.flow_children(layout_box, left + border_width + padding_left, top + border_width + padding_top, content_width);
+
+        2 * border_width + padding_top + content_height + padding_bottom
+    }
+
+    /// Lay out a cell on its own with its top at 0, ready to be moved down into its row
+    fn layout_cell(&mut self, layout_box: &LayoutBox, left: i32, width: i32) -> LaidOutCell<'a> {
+        let mut anchors = BTreeMap::new();
+        let mut blocks = Vec::new();
+        let height = {
+            let mut context = Context {
+                fonts: self.fonts,
+                zoom: self.zoom,
+                page: self.page,
+                focus: self.focus,
+                anchors: &mut anchors,
+                blocks: &mut blocks,
+            };
+            context.cell(layout_box, left, 0, width)
+        };
+
+        LaidOutCell {
+            height: height,
+            blocks: blocks,
+            anchors: anchors,
+        }
+    }
+
+    /// The minimum and maximum widths of every column in a table grid
+    fn column_widths(&self, grid: &Grid, spacing: i32) -> (Vec<i32>, Vec<i32>) {
+        let mut mins = vec![0; grid.columns];
+        let mut maxs = vec![0; grid.columns];
+
+        for cell in grid.cells.iter() {
+            if cell.colspan == 1 {
+                let (min, max) = self.box_widths(cell.layout_box);
+                mins[cell.col] = cmp::max(mins[cell.col], min);
+                maxs[cell.col] = cmp::max(maxs[cell.col], max);
+            }
+        }
+
+        // Spanning cells share out whatever they need beyond the columns they cover
+        for cell in grid.cells.iter() {
+            if cell.colspan > 1 {
+                let (min, max) = self.box_widths(cell.layout_box);
+                let columns = cell.col .. cell.col + cell.colspan;
+                let inner_spacing = spacing * (cell.colspan as i32 - 1);
+                let span = cell.colspan as i32;
+
+                let current_min = mins[columns.clone()].iter().fold(inner_spacing, |sum, w| sum + w);
+                if min > current_min {
+                    for (i, col) in columns.clone().enumerate() {
+                        mins[col] += (min - current_min) / span + if (i as i32) < (min - current_min) % span { 1 } else { 0 };
+                    }
+                }
+
+                let current_max = maxs[columns.clone()].iter().fold(inner_spacing, |sum, w| sum + w);
+                if max > current_max {
+                    for (i, col) in columns.enumerate() {
+                        maxs[col] += (max - current_max) / span + if (i as i32) < (max - current_max) % span { 1 } else { 0 };
+                    }
+                }
+            }
+        }
+
+        for (i, max) in maxs.iter_mut().enumerate() {
+            *max = cmp::max(*max, mins[i]);
+        }
+
+        (mins, maxs)
+    }
+
+    /// The minimum and maximum widths of a box, including its margins, borders and padding
+    fn box_widths(&self, layout_box: &LayoutBox) -> (i32, i32) {
+        if let BoxKind::Table = layout_box.kind {
+            return self.table_widths(layout_box);
+        }
+
+        let style = &layout_box.style;
+        let mut measure = Measure::new();
+        for child in layout_box.children.iter() {
+            self.measure_box(child, &mut measure);
+        }
+        measure.finish_line();
+
+        let (mut min, mut max) = (measure.min, measure.max);
+        if let Length::Px(px) = style.width {
+            min = cmp::max(min, (px * self.zoom).round() as i32);
+            max = min;
+        }
+
+        let extra = self.horizontal_extra(style);
+        (min + extra, max + extra)
+    }
+
+    fn table_widths(&self, layout_box: &LayoutBox) -> (i32, i32) {
+        let style = &layout_box.style;
+        let grid = table_grid(layout_box);
+        let spacing = self.spacing(style);
+        let (mins, maxs) = self.column_widths(&grid, spacing);
+
+        let chrome = self.horizontal_extra(style) + spacing * (grid.columns as i32 + 1);
+        let mut min = mins.iter().fold(chrome, |sum, w| sum + w);
+        let mut max = maxs.iter().fold(chrome, |sum, w| sum + w);
+
+        for caption in grid.captions.iter() {
+            let (caption_min, _) = self.box_widths(caption);
+            min = cmp::max(min, caption_min);
+        }
+        max = cmp::max(max, min);
+
+        if let Length::Px(px) = style.width {
+            min = cmp::max(min, (px * self.zoom).round() as i32);
+            max = min;
+        }
+
+        (min, max)
+    }
+
+    /// Accumulate the widths of a box in normal flow
+    fn measure_box(&self, layout_box: &LayoutBox, measure: &mut Measure) {
+        match layout_box.kind {
+            BoxKind::Inline => for child in layout_box.children.iter() {
+                self.measure_box(child, measure);
+            },
+            BoxKind::Text(ref string) => {
+                let style = &layout_box.style;
+                let space = cmp::max(1, (self.font_size(style) / 2.0).round() as i32);
+
+                if style.white_space != WhiteSpace::Normal {
+                    // Text that does not wrap cannot be narrower than the whole run
+                    measure.word(self.word_width(string, style));
+                } else {
+                    for (word_i, word) in string.split(' ').enumerate() {
+                        if word_i > 0 {
+                            measure.pending_space += space;
+                        }
+
+                        if ! word.is_empty() {
+                            measure.word(self.word_width(word, style));
+                        }
+                    }
+                }
+            },
+            BoxKind::Image(i) => {
+                let page_image = &self.page.images[i];
+                match page_image.image {
+                    Some(ref image) => measure.word(self.zoomed(image.width() as i32)),
+                    None => match page_image.alt {
+                        Some(ref alt) => measure.word(self.word_width(alt, &layout_box.style)),
+                        None => measure.word(self.zoomed(page_image.placeholder_size().0))
+                    }
+                }
+            },
+            BoxKind::Control(i) => measure.word(self.control_size(&self.page.forms.controls[i], &layout_box.style).0),
+            BoxKind::LineBreak => measure.finish_line(),
+            _ => {
+                measure.finish_line();
+                let (min, max) = self.box_widths(layout_box);
+                measure.min = cmp::max(measure.min, min);
+                measure.max = cmp::max(measure.max, max);
+            }
+        }
+    }
+
+    fn flow_box(&mut self, layout_box: &LayoutBox, flow: &mut Flow<'a>) {
+        match layout_box.kind {
+            // Table parts outside of a table are treated as plain blocks
+            BoxKind::Block | BoxKind::ListItem(..) | BoxKind::Table | BoxKind::TableRowGroup | BoxKind::TableRow | BoxKind::TableCell(..) => {
+                self.finish_line(flow, 0);
+
+                let margin_top = self.resolve(layout_box.style.margin.top, flow.width);
+                flow.y += cmp::max(flow.pending_margin, margin_top);
+                flow.pending_margin = 0;
+
+                if let Some(ref anchor) = layout_box.anchor {
+                    self.anchors.insert(anchor.clone(), flow.y);
+                }
+
+                let (left, width) = (flow.left, flow.width);
+                flow.y += if let BoxKind::Table = layout_box.kind {
+                    self.table(layout_box, left, flow.y, width)
+                } else {
+                    self.block(layout_box, left, flow.y, width)
+                };
+                flow.pending_margin = self.resolve(layout_box.style.margin.bottom, flow.width);
+            },
+            BoxKind::Inline => {
+                if let Some(ref anchor) = layout_box.anchor {
+                    flow.anchors.push(anchor.clone());
+                }
+
+                for child in layout_box.children.iter() {
+                    self.flow_box(child, flow);
+                }
+            },
+            BoxKind::Text(ref string) => {
+                if let Some(ref anchor) = layout_box.anchor {
+                    flow.anchors.push(anchor.clone());
+                }
+
+                self.text(string, layout_box, flow);
+            },
+            BoxKind::Image(i) => {
+                if let Some(ref anchor) = layout_box.anchor {
+                    flow.anchors.push(anchor.clone());
+                }
+
+                let page = self.page;
+                let page_image = &page.images[i];
+                if let Some(ref image) = page_image.image {
+                    self.image(image, &page_image.zoomed, layout_box, flow);
+                } else if let Some(ref alt) = page_image.alt {
+                    self.text(alt, layout_box, flow);
+                } else if ! page_image.failed {
+                    // Keep a box in place until the image arrives
+                    let (w, h) = page_image.placeholder_size();
+                    let (w, h) = (self.zoomed(w), self.zoomed(h));
+                    if ! flow.fits(w) {
+                        self.finish_line(flow, 0);
+                    }
+
+                    self.place(flow, Block {
+                        x: 0,
+                        y: 0,
+                        w: w,
+                        h: h,
+                        color: layout_box.style.color,
+                        background: Some(Color::rgb(238, 238, 238)),
+                        border: Some((1, Color::rgb(160, 160, 160))),
+                        string: String::new(),
+                        link: layout_box.link.clone(),
+                        control: None,
+                        image: None,
+                        text: None
+                    }, h, Vec::new());
+                }
+            },
+            BoxKind::Control(i) => {
+                if let Some(ref anchor) = layout_box.anchor {
+                    flow.anchors.push(anchor.clone());
+                }
+
+                self.control(i, layout_box, flow);
+            },
+            BoxKind::LineBreak => {
+                if let Some(ref anchor) = layout_box.anchor {
+                    flow.anchors.push(anchor.clone());
+                }
+
+                let height = self.font_size(&layout_box.style).ceil() as i32;
+                self.finish_line(flow, height);
+            }
+        }
+    }
+
+    fn image(&mut self, image: &Image, zoomed: &RefCell<Option<(f32, Image)>>, layout_box: &LayoutBox, flow: &mut Flow<'a>) {
+        let image = if self.zoom == 1.0 {
+            image.clone()
+        } else {
+            let mut zoomed = zoomed.borrow_mut();
+            match *zoomed {
+                Some((zoom, ref scaled)) if zoom == self.zoom => scaled.clone(),
+                _ => {
+                    let w = cmp::max(1, self.zoomed(image.width() as i32)) as u32;
+                    let scaled = scale_image(image.clone(), Some(w), None);
+                    *zoomed = Some((self.zoom, scaled.clone()));
+                    scaled
+                }
+            }
+        };
+        let w = image.width() as i32;
+        let h = image.height() as i32;
+        if ! flow.fits(w) {
+            self.finish_line(flow, 0);
+        }
+
+        self.place(flow, Block {
+            x: 0,
+            y: 0,
+            w: w,
+            h: h,
+            color: layout_box.style.color,
+            background: None,
+            border: None,
+            string: String::new(),
+            link: layout_box.link.clone(),
+            control: None,
+            image: Some(image),
+            text: None
+        }, h, Vec::new());
+    }
+
+    fn text(&mut self, string: &str, layout_box: &LayoutBox, flow: &mut Flow<'a>) {
+        let style = &layout_box.style;
+        let space = cmp::max(1, (self.font_size(style) / 2.0).round() as i32);
+        let fonts = self.fonts;
+        let font = fonts.select(style);
+
+        // Preformatted text goes in a line at a time with its spaces, apart from trailing ones
+        // which rendering leaves out
+        let (words, trailing) = if style.white_space == WhiteSpace::Pre {
+            let line = string.trim_right_matches(' ');
+            (vec![line], string.len() - line.len())
+        } else {
+            (string.split(' ').collect::<Vec<&str>>(), 0)
+        };
+
+        for (word_i, word) in words.iter().enumerate() {
+            if word_i > 0 {
+                flow.pending_space += space;
+            }
+
+            if word.is_empty() {
+                continue;
+            }
+
+            let text = font.render(word, self.font_size(style));
+
+            let w = text.width() as i32;
+            let h = text.height() as i32;
+            if style.white_space == WhiteSpace::Normal && ! flow.fits(w) {
+                self.finish_line(flow, 0);
+            }
+
+            let baseline = cmp::min(h, (self.font_size(style) * 0.8).round() as i32);
+            let ascent = match style.vertical_align {
+                VerticalAlign::Baseline => baseline,
+                VerticalAlign::Sub => baseline - (self.font_size(style) * 0.3).round() as i32,
+                VerticalAlign::Super => baseline + (self.font_size(style) * 0.5).round() as i32,
+            };
+
+            // Decorations also cover the space before a word, when it is on the same line
+            let gap = if word_i > 0 && ! flow.fragments.is_empty() { flow.pending_space } else { 0 };
+            let thickness = cmp::max(1, (self.font_size(style) / 14.0).round() as i32);
+            let mut inner = Vec::new();
+            if style.underline {
+                inner.push(self.rule(-gap, baseline + thickness, w + gap, thickness, layout_box));
+            }
+            if style.line_through {
+                inner.push(self.rule(-gap, baseline - (self.font_size(style) * 0.3).round() as i32, w + gap, thickness, layout_box));
+            }
+
+            self.place(flow, Block {
+                x: 0,
+                y: 0,
+                w: w,
+                h: h,
+                color: style.color,
+                background: style.background,
+                border: None,
+                string: word.to_string(),
+                link: layout_box.link.clone(),
+                control: None,
+                image: None,
+                text: Some(text)
+            }, ascent, inner);
+        }
+
+        if trailing > 0 {
+            flow.pending_space += trailing as i32 * self.space_width(style);
+        }
+    }
+
+    /// A line drawn with a text, such as an underline
+    fn rule(&self, x: i32, y: i32, w: i32, h: i32, layout_box: &LayoutBox) -> Block<'a> {
+        Block {
+            x: x,
+            y: y,
+            w: w,
+            h: h,
+            color: layout_box.style.color,
+            background: Some(layout_box.style.color),
+            border: None,
+            string: String::new(),
+            link: layout_box.link.clone(),
+            control: None,
+            image: None,
+            text: None
+        }
+    }
+
+    /// The advance of a space, found from the difference it makes between two letters
+    fn space_width(&self, style: &Style) -> i32 {
+        cmp::max(1, self.word_width("x x", style) - self.word_width("xx", style))
+    }
+
+    /// Draw the marker of a list item to the left of its content, on the baseline of the first
+    /// line of text laid out since first_block
+    fn marker(&mut self, marker: &str, style: &Style, left: i32, top: i32, first_block: usize) {
+        if marker.is_empty() {
+            return;
+        }
+
+        let text = self.fonts.select(style).render(marker, self.font_size(style));
+        let w = text.width() as i32;
+        let h = text.height() as i32;
+        let gap = cmp::max(1, (self.font_size(style) / 2.0).round() as i32);
+        let y = match self.blocks[first_block..].iter().find(|block| block.text.is_some()) {
+            Some(block) => block.y + block.h - h,
+            None => top
+        };
+
+        // Go before the content, so that the marker comes first in reading order
+        self.blocks.insert(first_block, Block {
+            x: left - gap - w,
+            y: y,
+            w: w,
+            h: h,
+            color: style.color,
+            background: None,
+            border: None,
+            string: marker.to_string(),
+            link: None,
+            control: None,
+            image: None,
+            text: Some(text)
+        });
+    }
+
+    /// The size of a form control, including its border
+    fn control_size(&self, control: &Control, style: &Style) -> (i32, i32) {
+        let char_w = cmp::max(1, (self.font_size(style) / 2.0).round() as i32);
+        let line_h = (self.font_size(style) * 1.2).ceil() as i32;
+        match control.kind {
+            ControlKind::Text | ControlKind::Password => (control.size as i32 * char_w + 8, line_h + 8),
+            ControlKind::TextArea => (control.size as i32 * char_w + 8, control.rows as i32 * line_h + 8),
+            ControlKind::Checkbox | ControlKind::Radio => {
+                let size = cmp::max(8, (self.font_size(style) * 0.8).round() as i32);
+                (size, size)
+            },
+            ControlKind::Select => {
+                let mut w = 0;
+                for &(ref label, _) in control.options.iter() {
+                    w = cmp::max(w, self.word_width(label, style));
+                }
+                (w + 24, line_h + 8)
+            },
+            ControlKind::Submit | ControlKind::Reset | ControlKind::Button => (self.word_width(&control.display_text(), style) + 16, line_h + 8),
+            ControlKind::Hidden => (0, 0)
+        }
+    }
+
+    fn word_width(&self, string: &str, style: &Style) -> i32 {
+        if string.is_empty() {
+            0
+        } else {
+            self.fonts.select(style).render(string, self.font_size(style)).width() as i32
+        }
+    }
+
+    /// Place a form control as an inline block, with its contents drawn on top
+    fn control(&mut self, i: usize, layout_box: &LayoutBox, flow: &mut Flow<'a>) {
+        let page = self.page;
+        let control = &page.forms.controls[i];
+        let style = &layout_box.style;
+        let (w, h) = self.control_size(control, style);
+        if ! flow.fits(w) {
+            self.finish_line(flow, 0);
+        }
+
+        let border = if self.focus == Some(i) {
+            (2, Color::rgb(0, 0, 255))
+        } else {
+            (1, Color::rgb(118, 118, 118))
+        };
+        let background = match control.kind {
+            ControlKind::Submit | ControlKind::Reset | ControlKind::Button | ControlKind::Select => Color::rgb(221, 221, 221),
+            _ => Color::rgb(255, 255, 255)
+        };
+
+        let line_h = (self.font_size(style) * 1.2).ceil() as i32;
+        let mut inner = Vec::new();
+        let mut lines = Vec::new();
+        match control.kind {
+            ControlKind::Checkbox | ControlKind::Radio => if control.checked {
+                inner.push(Block {
+                    x: 3,
+                    y: 3,
+                    w: w - 6,
+                    h: h - 6,
+                    color: style.color,
+                    background: Some(style.color),
+                    border: None,
+                    string: String::new(),
+                    link: None,
+                    control: Some(i),
+                    image: None,
+                    text: None
+                });
+            },
+            ControlKind::TextArea => for line in control.value.split('\n').take(control.rows) {
+                lines.push(line.to_string());
+            },
+            _ => lines.push(control.display_text())
+        }
+
+        for (line_i, line) in lines.iter().enumerate() {
+            // Keep the end of long values in view, where the text is typed
+            let line = fit_end(self.fonts.select(style), line, self.font_size(style), w - 8);
+
+            if ! line.is_empty() {
+                let text = self.fonts.select(style).render(line, self.font_size(style));
+                let text_x = match control.kind {
+                    ControlKind::Submit | ControlKind::Reset | ControlKind::Button => (w - text.width() as i32) / 2,
+                    _ => 4
+                };
+                inner.push(Block {
+                    x: text_x,
+                    y: 4 + line_i as i32 * line_h,
+                    w: text.width() as i32,
+                    h: text.height() as i32,
+                    color: style.color,
+                    background: None,
+                    border: None,
+                    string: line.to_string(),
+                    link: None,
+                    control: Some(i),
+                    image: None,
+                    text: Some(text)
+                });
+            }
+        }
+
+        let ascent = match control.kind {
+            ControlKind::Checkbox | ControlKind::Radio | ControlKind::TextArea => h,
+            _ => 4 + (self.font_size(style) * 0.8).round() as i32
+        };
+
+        self.place(flow, Block {
+            x: 0,
+            y: 0,
+            w: w,
+            h: h,
+            color: style.color,
+            background: Some(background),
+            border: Some(border),
+            string: String::new(),
+            link: None,
+            control: Some(i),
+            image: None,
+            text: None
+        }, ascent, inner);
+    }
+
+    fn place(&mut self, flow: &mut Flow<'a>, mut block: Block<'a>, ascent: i32, inner: Vec<Block<'a>>) {
+        flow.x += flow.pending_space;
+        flow.pending_space = 0;
+
+        block.x = flow.x;
+        flow.x += block.w;
+
+        flow.fragments.push(Fragment {
+            block: block,
+            ascent: ascent,
+            inner: inner,
+        });
+    }
+
+    /// Close the current line box, aligning its fragments on a common baseline
+    fn finish_line(&mut self, flow: &mut Flow<'a>, min_height: i32) {
+        flow.pending_space = 0;
+
+        if flow.fragments.is_empty() && min_height == 0 {
+            for anchor in flow.anchors.drain(..) {
+                self.anchors.insert(anchor, flow.y);
+            }
+            return;
+        }
+
+        flow.y += flow.pending_margin;
+        flow.pending_margin = 0;
+
+        let mut ascent = 0;
+        let mut descent = 0;
+        for fragment in flow.fragments.iter() {
+            ascent = cmp::max(ascent, fragment.ascent);
+            descent = cmp::max(descent, fragment.block.h - fragment.ascent);
+        }
+
+        let shift = cmp::max(0, match flow.align {
+            TextAlign::Left => 0,
+            TextAlign::Center => (flow.width - flow.x) / 2,
+            TextAlign::Right => flow.width - flow.x,
+        });
+
+        for fragment in flow.fragments.drain(..) {
+            let mut block = fragment.block;
+            block.x += flow.left + shift;
+            block.y = flow.y + ascent - fragment.ascent;
+            let (x, y) = (block.x, block.y);
+            self.blocks.push(block);
+
+            for mut inner in fragment.inner.into_iter() {
+                inner.x += x;
+                inner.y += y;
+                self.blocks.push(inner);
+            }
+        }
+
+        for anchor in flow.anchors.drain(..) {
+            self.anchors.insert(anchor, flow.y);
+        }
+
+        flow.y += cmp::max(ascent + descent, min_height);
+        flow.x = 0;
+    }
+}
+
+/// Widths of the content of a box, for sizing table columns
+struct Measure {
+    /// The widest word or block that cannot be broken
+    min: i32,
+    /// The widest line without any wrapping
+    max: i32,
+    line: i32,
+    pending_space: i32,
+}
+
+impl Measure {
+    fn new() -> Measure {
+        Measure {
+            min: 0,
+            max: 0,
+            line: 0,
+            pending_space: 0,
+        }
+    }
+
+    fn word(&mut self, w: i32) {
+        self.min = cmp::max(self.min, w);
+        if self.line > 0 {
+            self.line += self.pending_space;
+        }
+        self.line += w;
+        self.pending_space = 0;
+    }
+
+    fn finish_line(&mut self) {
+        self.max = cmp::max(self.max, self.line);
+        self.line = 0;
+        self.pending_space = 0;
+    }
+}
+
+/// A cell placed in the table grid
+struct GridCell<'b> {
+    layout_box: &'b LayoutBox,
+    row: usize,
+    col: usize,
+    colspan: usize,
+    rowspan: usize,
+}
+
+/// The rows and cells of a table, with spans resolved
+struct Grid<'b> {
+    /// Row boxes, or None for cells that were not inside a row
+    rows: Vec<Option<&'b LayoutBox>>,
+    cells: Vec<GridCell<'b>>,
+    columns: usize,
+    captions: Vec<&'b LayoutBox>,
+}
+
+fn push_row<'b>(row: &'b LayoutBox, rows: &mut Vec<(Option<&'b LayoutBox>, Vec<&'b LayoutBox>)>) {
+    match row.kind {
+        BoxKind::TableRow => {
+            let mut cells = Vec::new();
+            for cell in row.children.iter() {
+                if let BoxKind::TableCell(..) = cell.kind {
+                    cells.push(cell);
+                }
+            }
+            rows.push((Some(row), cells));
+        },
+        BoxKind::TableCell(..) => rows.push((None, vec![row])),
+        _ => ()
+    }
+}
+
+fn table_grid<'b>(table: &'b LayoutBox) -> Grid<'b> {
+    let mut rows = Vec::new();
+    let mut captions = Vec::new();
+    for child in table.children.iter() {
+        match child.kind {
+            BoxKind::TableRowGroup => for row in child.children.iter() {
+                push_row(row, &mut rows);
+            },
+            BoxKind::TableRow | BoxKind::TableCell(..) => push_row(child, &mut rows),
+            _ => captions.push(child)
+        }
+    }
+
+    // Mark the slots taken by row spans from earlier rows
+    let mut occupied: Vec<Vec<bool>> = vec![Vec::new(); rows.len()];
+    let mut cells = Vec::new();
+    let mut columns = 0;
+    for (row, &(_, ref row_cells)) in rows.iter().enumerate() {
+        let mut col = 0;
+        for cell in row_cells.iter() {
+            let (colspan, rowspan) = match cell.kind {
+                BoxKind::TableCell(colspan, rowspan) => (colspan, rowspan),
+                _ => (1, 1)
+            };
+            let rowspan = cmp::min(rowspan, rows.len() - row);
+
+            while occupied[row].get(col).cloned().unwrap_or(false) {
+                col += 1;
+            }
+
+            for slots in occupied[row .. row + rowspan].iter_mut() {
+                if slots.len() < col + colspan {
+                    slots.resize(col + colspan, false);
+                }
+                for slot in slots[col .. col + colspan].iter_mut() {
+                    *slot = true;
+                }
+            }
+
+            cells.push(GridCell {
+                layout_box: *cell,
+                row: row,
+                col: col,
+                colspan: colspan,
+                rowspan: rowspan,
+            });
+
+            col += colspan;
+            columns = cmp::max(columns, col);
+        }
+    }
+
+    Grid {
+        rows: rows.into_iter().map(|(row_box, _)| row_box).collect(),
+        cells: cells,
+        columns: columns,
+        captions: captions,
+    }
+}
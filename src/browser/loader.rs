@@ -0,0 +1,123 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::{str, thread};
+
+use orbimage::Image;
+use url::Url;
+
+use session::Session;
+use image::{decode_image, scale_image};
+use layout::Page;
+use super::{url_download, url_parse};
+
+/// Number of images downloaded at the same time
+const IMAGE_THREADS: usize = 4;
+
+pub enum LoadEvent {
+    /// A new version of the page, built from what has arrived so far
+    Page(Page),
+    /// An image of the last page sent, by index
+    Image(usize, Result<Image, String>),
+    /// Everything has been loaded
+    Done,
+}
+
+/// A page loading on a worker thread
+pub struct Loader {
+    receiver: Receiver<LoadEvent>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Loader {
+    /// Start loading a URL with the shared session, posting body to it if given
+    pub fn new(url: Url, body: Option<Vec<u8>>, session: Arc<Session>) -> Loader {
+        let (sender, receiver) = channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let thread_cancel = cancel.clone();
+        thread::spawn(move || {
+            let pending = {
+                let mut progress = |page: Page| -> bool {
+                    ! thread_cancel.load(Ordering::SeqCst) && sender.send(LoadEvent::Page(page)).is_ok()
+                };
+                let page = url_parse(&url, body.as_ref().map(|body| body.as_slice()), &session, &mut progress);
+
+                let pending = page.pending_images();
+                if ! progress(page) {
+                    return;
+                }
+                pending
+            };
+
+            load_images(pending, &sender, &thread_cancel, &session);
+
+            let _ = sender.send(LoadEvent::Done);
+        });
+
+        Loader {
+            receiver: receiver,
+            cancel: cancel,
+        }
+    }
+
+    /// Stop loading. Requests already sent finish in the background, but nothing more is reported.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    /// Get the next event without blocking, or None if there is nothing yet
+    pub fn poll(&self) -> Option<LoadEvent> {
+        match self.receiver.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty) => None,
+            // The worker went away without finishing, most likely from a panic
+            Err(TryRecvError::Disconnected) => Some(LoadEvent::Done)
+        }
+    }
+}
+
+impl Drop for Loader {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Download images on a few threads at once, reporting each as it finishes
+fn load_images(mut requests: Vec<(usize, Url, Option<u32>, Option<u32>)>, sender: &Sender<LoadEvent>, cancel: &Arc<AtomicBool>, session: &Arc<Session>) {
+    // Workers pop from the end, so reverse to load in document order
+    requests.reverse();
+    let queue = Arc::new(Mutex::new(requests));
+
+    let mut workers = Vec::new();
+    for _ in 0..IMAGE_THREADS {
+        let queue = queue.clone();
+        let sender = sender.clone();
+        let cancel = cancel.clone();
+        let session = session.clone();
+        workers.push(thread::spawn(move || {
+            while ! cancel.load(Ordering::SeqCst) {
+                let request = queue.lock().unwrap().pop();
+                match request {
+                    Some((i, url, width, height)) => {
+                        let result = load_image(&url, &session).map(|image| scale_image(image, width, height));
+                        if cancel.load(Ordering::SeqCst) || sender.send(LoadEvent::Image(i, result)).is_err() {
+                            break;
+                        }
+                    },
+                    None => break
+                }
+            }
+        }));
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+fn load_image(url: &Url, session: &Session) -> Result<Image, String> {
+    let (headers, data) = url_download(url, session)?;
+    let content_type = headers.get_raw("content-type").and_then(|x| str::from_utf8(x[0].as_slice()).ok());
+    decode_image(&data, content_type.and_then(|content_type| content_type.split(';').next()))
+}
@@ -2,7 +2,8 @@
 
 extern crate html5ever_atoms;
 extern crate html5ever;
-//extern crate mime_guess;
+extern crate gif;
+extern crate mime_guess;
 extern crate orbclient;
 extern crate orbfont;
 extern crate orbimage;
@@ -10,35 +11,115 @@ extern crate tendril;
 extern crate url;
 extern crate hyper;
 extern crate hyper_rustls;
+extern crate rustls;
+extern crate inflate;
 
 
-use std::{cmp, env, str};
+use std::{cmp, env, process, str};
 use std::collections::BTreeMap;
 use std::default::Default;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{stderr, Read, Write};
+use std::io::{stderr, Cursor, Read, Write};
 use std::string::String;
-use std::time::Duration;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use html5ever::parse_document;
-use html5ever::rcdom::{Document, Doctype, Text, Comment, Element, RcDom, Handle};
-use orbclient::{Color, EventOption, Renderer, Window, WindowFlag, K_BKSP, K_ESC, K_LEFT, K_RIGHT, K_DOWN, K_PGDN, K_UP, K_PGUP};
-use orbfont::Font;
+use html5ever::rcdom::{Text, Element, RcDom, Handle};
+use orbclient::{Color, EventOption, Renderer, Window, WindowFlag, K_0, K_ALT, K_B, K_BKSP, K_C, K_CTRL, K_D, K_ENTER, K_EQUALS, K_ESC, K_F, K_F3, K_F5, K_H, K_L, K_LEFT, K_LEFT_SHIFT, K_MINUS, K_P, K_R, K_RIGHT, K_RIGHT_SHIFT, K_DOWN, K_PGDN, K_SPACE, K_T, K_TAB, K_UP, K_PGUP, K_V, K_W};
 use tendril::TendrilSink;
 use url::Url;
 use hyper::header::{self, Headers};
-use hyper::Client;
-use hyper::net::HttpsConnector;
-
-struct Block<'a> {
+use hyper::client::Response;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+
+use about::{about_page, error_page, escape_html};
+use cache::Lookup;
+use css::{Origin, Style, Stylesheet, WhiteSpace};
+use decode::{content_decoder, detect_charset};
+use directory::directory_page;
+use download::{download, format_size, is_attachment};
+use error::LoadError;
+use find::{FindBar, FIND_HEIGHT};
+use fonts::Fonts;
+use form::ControlKind;
+use gemini::gemini_parse;
+use gopher::gopher_parse;
+use hint::Hints;
+use image::{decode_image, ImageFormat};
+use layout::{LayoutBox, Page};
+use pdf::{encode_pdf, pdf_filename, Paper};
+use reader::{reader_source, reader_url};
+use selection::{is_text, text_block_at};
+use session::Session;
+use tab::{draw_tabs, tab_click, Tab, TabAction, TAB_HEIGHT};
+use toolbar::{Toolbar, ToolbarAction, TOOLBAR_HEIGHT};
+
+mod about;
+mod bookmark;
+mod cache;
+mod cookie;
+mod css;
+mod decode;
+mod directory;
+mod download;
+mod error;
+mod find;
+mod fonts;
+mod form;
+mod gemini;
+mod gopher;
+mod headless;
+mod hint;
+mod history;
+mod image;
+mod layout;
+mod loader;
+mod network;
+mod pdf;
+mod reader;
+mod selection;
+mod session;
+mod tab;
+mod theme;
+mod toolbar;
+mod zoom;
+
+/// Height of the tab strip and toolbar above the page
+const CHROME_HEIGHT: i32 = TAB_HEIGHT + TOOLBAR_HEIGHT;
+
+/// Background of selected text
+const SELECTION_COLOR: Color = Color { data: 0xFFB3D7FF };
+
+/// Distance the mouse moves with the button down before it selects text instead of clicking
+const DRAG_THRESHOLD: i32 = 4;
+
+/// History entries suggested while typing an address
+const MAX_SUGGESTIONS: usize = 6;
+
+/// Redirects followed before giving up on a page
+const MAX_REDIRECTS: usize = 10;
+
+/// Milliseconds between reparses of a page that is still downloading
+const PROGRESS_INTERVAL: u64 = 250;
+
+/// The schemes url_parse can load
+const SCHEMES: [&'static str; 6] = ["about", "file", "gemini", "gopher", "http", "https"];
+
+pub struct Block<'a> {
     x: i32,
     y: i32,
     w: i32,
     h: i32,
     color: Color,
+    background: Option<Color>,
+    border: Option<(i32, Color)>,
     string: String,
     link: Option<String>,
+    control: Option<usize>,
     image: Option<orbimage::Image>,
     text: Option<orbfont::Text<'a>>,
 }
@@ -51,631 +132,922 @@ impl<'a> Block<'a> {
         m_x >= x && m_x < x + self.w && m_y >= y && m_y < y + self.h
     }
 
-    fn draw(&self, window: &mut Window, offset: (i32, i32)) {
+    /// Draw the block to a window, or to an image when there is no window
+    fn draw<R: Renderer + ?Sized>(&self, renderer: &mut R, offset: (i32, i32), highlight: Option<Color>) {
         let x = self.x - offset.0;
         let y = self.y - offset.1;
-        if x + self.w > 0 && x < window.width() as i32 && y + self.h > 0 && y < window.height() as i32 {
+        if x + self.w > 0 && x < renderer.width() as i32 && y + self.h > 0 && y < renderer.height() as i32 {
+            if let Some(background) = self.background {
+                renderer.rect(x, y, self.w as u32, self.h as u32, background);
+            }
+
+            if let Some((width, color)) = self.border {
+                let width = cmp::min(width, cmp::min(self.w, self.h) / 2);
+                if width > 0 {
+                    renderer.rect(x, y, self.w as u32, width as u32, color);
+                    renderer.rect(x, y + self.h - width, self.w as u32, width as u32, color);
+                    renderer.rect(x, y + width, width as u32, (self.h - 2 * width) as u32, color);
+                    renderer.rect(x + self.w - width, y + width, width as u32, (self.h - 2 * width) as u32, color);
+                }
+            }
+
+            if let Some(highlight) = highlight {
+                renderer.rect(x, y, self.w as u32, self.h as u32, highlight);
+            }
+
             if let Some(ref image) = self.image {
-                image.draw(window, x, y);
+                image.draw(renderer, x, y);
             }
 
             if let Some(ref text) = self.text {
-                text.draw(window, x, y, self.color);
+                text.draw(renderer, x, y, self.color);
             }
         }
     }
 }
 
-fn text_block<'a>(string: &str, x: &mut i32, y: &mut i32, size: f32, bold: bool, color: Color, link: Option<String>, font: &'a Font, font_bold: &'a Font, window: &Window, blocks: &mut Vec<Block<'a>>) {
-    let trimmed_left = string.trim_left();
-    let left_margin = string.len() as i32 - trimmed_left.len() as i32;
-    let trimmed_right = trimmed_left.trim_right();
-    let right_margin = trimmed_left.len() as i32 - trimmed_right.len() as i32;
-
-    //let escaped_text = escape_default(&trimmed_right);
-    //println!("#text: block {} at {}, {}: '{}'", blocks.len(), *x, *y, escaped_text);
-
-    *x += left_margin * 8;
+/// Gather the author stylesheets of a document from style and link elements
+/// Stylesheets already downloaded are kept in sheets, so that reparsing a page does not fetch them again
+fn collect_styles(handle: &Handle, url: &Url, stylesheet: &mut Stylesheet, sheets: &mut BTreeMap<String, String>, session: &Session) {
+    let node = handle.borrow();
 
-    for (word_i, word) in trimmed_right.split(' ').enumerate() {
-        if word_i > 0 {
-            *x += 8;
+    if let Element(ref name, _, ref attrs) = node.node {
+        let mut rel = String::new();
+        let mut href = None;
+        let mut media = String::new();
+        for attr in attrs.iter() {
+            match &*attr.name.local {
+                "rel" => rel = attr.value.to_lowercase(),
+                "href" => href = Some(attr.value.to_string()),
+                "media" => media = attr.value.to_lowercase(),
+                _ => ()
+            }
         }
 
-        let text = if bold {
-            font_bold.render(word, size)
-        } else {
-            font.render(word, size)
-        };
-
-        let w = text.width() as i32;
-        let h = text.height() as i32;
+        let screen = media.is_empty() || media.contains("screen") || media.contains("all");
 
-        if *x + w >= window.width() as i32 && *x > 0 {
-            *x = 0;
-            *y += size.ceil() as i32;
+        match &*name.local {
+            "style" => if screen {
+                let mut css = String::new();
+                for child in node.children.iter() {
+                    if let Text(ref text) = child.borrow().node {
+                        css.push_str(text);
+                    }
+                }
+                stylesheet.add(&css, Origin::Author);
+            },
+            "link" => if screen && rel.split_whitespace().any(|rel| rel == "stylesheet") {
+                if let Some(href) = href {
+                    match url.join(&href) {
+                        Ok(css_url) => {
+                            let key = css_url.to_string();
+                            if ! sheets.contains_key(&key) {
+                                match url_download(&css_url, session) {
+                                    Ok((_headers, data)) => {
+                                        sheets.insert(key.clone(), String::from_utf8_lossy(&data).into_owned());
+                                    },
+                                    Err(err) => {
+                                        println!("Failed to load stylesheet {}: {}", css_url, err);
+                                        sheets.insert(key.clone(), String::new());
+                                    }
+                                }
+                            }
+                            stylesheet.add(&sheets[&key], Origin::Author);
+                        },
+                        Err(err) => println!("Invalid stylesheet URL {}: {}", href, err)
+                    }
+                }
+            },
+            _ => ()
         }
-
-        blocks.push(Block {
-            x: *x,
-            y: *y,
-            w: w,
-            h: h,
-            color: color,
-            string: word.to_string(),
-            link: link.clone(),
-            image: None,
-            text: Some(text)
-        });
-
-        *x += w;
     }
 
-    *x += right_margin * 8;
+    for child in node.children.iter() {
+        collect_styles(child, url, stylesheet, sheets, session);
+    }
 }
 
-fn walk<'a>(handle: Handle, indent: usize, x: &mut i32, y: &mut i32, mut size: f32, mut bold: bool, mut color: Color, mut ignore: bool, whitespace: &mut bool, mut link: Option<String>, url: &Url, font: &'a Font, font_bold: &'a Font, window: &Window, anchors: &mut BTreeMap<String, i32>, blocks: &mut Vec<Block<'a>>) {
-    let node = handle.borrow();
+// FIXME: Copy of str::escape_default from std, which is currently unstable
+pub fn escape_default(s: &str) -> String {
+    s.chars().flat_map(|c| c.escape_default()).collect()
+}
 
-    let mut new_line = false;
+/// Send a request with extra headers and an optional form encoded body, returning the response for reading.
+/// Cookies for the URL are sent along, and any the response sets are stored.
+fn http_request(url: &Url, method: Method, mut headers: Headers, body: Option<&[u8]>, session: &Session) -> Result<Response, LoadError> {
+    write!(stderr(), "* Requesting {} {}\n", method, url).map_err(|err| LoadError::Other(format!("{}", err)))?;
 
-    //print!("{}", repeat(" ").take(indent).collect::<String>());
-    match node.node {
-        Document
-            => {
-                //println!("#Document")
-            },
+    headers.set_raw("Accept-Encoding", vec![b"gzip, deflate".to_vec()]);
+    if let Some(cookie) = session.cookies.header(url) {
+        headers.set_raw("Cookie", vec![cookie.into_bytes()]);
+    }
 
-        Doctype(ref _name, ref _public, ref _system)
-            => {
-                //println!("<!DOCTYPE {} \"{}\" \"{}\">", *name, *public, *system);
-            },
+    let mut request = session.client(url).request(method, url.clone()).headers(headers);
+    if let Some(body) = body {
+        request = request.header(header::ContentType::form_url_encoded()).body(body);
+    }
+    let res = request.send().map_err(|err| LoadError::from_hyper(err, url))?;
 
-        Text(ref text)
-            => {
-                let mut string = String::new();
+    if let Some(set_cookie) = res.headers.get_raw("set-cookie") {
+        session.cookies.set(url, set_cookie);
+    }
 
-                for c in text.chars() {
-                    match c {
-                        ' ' | '\n' | '\r' => if *whitespace {
-                            // Ignore
-                        } else {
-                            // Set whitespace
-                            *whitespace = true;
-                            string.push(' ');
-                        },
-                        _ => {
-                            if *whitespace {
-                                *whitespace = false;
-                            }
-                            string.push(c);
-                        }
-                    }
-                }
+    Ok(res)
+}
 
-                if ! string.is_empty() {
-                    if ignore {
-                        //println!("#text: ignored");
-                    } else {
-                        text_block(&string, x, y, size, bold, color, link.clone(), font, font_bold, window, blocks);
-                    }
-                } else {
-                    //println!("#text: empty");
-                }
-            },
+/// A response ready to be read, from either the network or the cache
+struct Fetched<'c> {
+    /// The URL the response came from, after following redirects
+    url: Url,
+    status: StatusCode,
+    headers: Headers,
+    body: Box<Read + 'c>,
+}
 
-        Comment(ref _text)
-            => {
-                //println!("<!-- {} -->", escape_default(text))
-            },
+/// Send a request, following redirects up to MAX_REDIRECTS
+fn http_fetch<'c>(url: &Url, body: Option<&[u8]>, session: &'c Session) -> Result<Fetched<'c>, LoadError> {
+    let mut url = url.clone();
+    let mut body = body;
+    for _ in 0..MAX_REDIRECTS + 1 {
+        let fetched = http_fetch_once(&url, body, session)?;
+
+        let status = fetched.status.to_u16();
+        let redirect = status == 301 || status == 302 || status == 303 || status == 307 || status == 308;
+        let location = fetched.headers.get_raw("location").and_then(|x| str::from_utf8(x[0].as_slice()).ok()).map(|location| location.trim().to_string());
+        let location = match location {
+            Some(location) => if redirect { location } else { return decompress(fetched) },
+            None => return decompress(fetched)
+        };
+        let mut next = match url.join(&location) {
+            Ok(next) => next,
+            Err(_) => return Err(LoadError::BadRedirect(location))
+        };
+        if next.scheme() != "http" && next.scheme() != "https" {
+            return Err(LoadError::BadRedirect(location));
+        }
+        // The fragment carries over unless the redirect gives its own
+        if next.fragment().is_none() {
+            next.set_fragment(url.fragment());
+        }
 
-        Element(ref name, _, ref attrs) => {
-            /*
-            assert!(name.ns == ns!(html));
-            //print!("<{}", name.local);
-            for attr in attrs.iter() {
-                assert!(attr.name.ns == ns!());
-                //print!(" {}=\"{}\"", attr.name.local, attr.value);
-            }
-            //println!(">");
-            */
-
-            match &*name.local {
-                "a" => {
-                    color = Color::rgb(0, 0, 255);
-                    for attr in attrs.iter() {
-                        match &*attr.name.local {
-                            "name" => {
-                                anchors.insert(attr.value.to_string(), *y);
-                            },
-                            "href" => {
-                                link = Some(attr.value.to_string());
-                            },
-                            _ => ()
-                        }
-                    }
-                },
-                "b" => {
-                    bold = true;
-                },
-                "br" => {
-                    ignore = true;
-                    new_line = true;
-                },
-                "div" => {
-                    new_line = true;
-                },
-                "h1" => {
-                    size = 32.0;
-                    bold = true;
-                    new_line = true;
-                },
-                "h2" => {
-                    size = 24.0;
-                    bold = true;
-                    new_line = true;
-                },
-                "h3" => {
-                    size = 18.0;
-                    bold = true;
-                    new_line = true;
-                }
-                "h4" => {
-                    size = 16.0;
-                    bold = true;
-                    new_line = true;
-                }
-                "h5" => {
-                    size = 14.0;
-                    bold = true;
-                    new_line = true;
-                }
-                "h6" => {
-                    size = 10.0;
-                    bold = true;
-                    new_line = true;
-                },
-                "hr" => {
-                    new_line = true;
-                },
-                "img" => {
-                    if ! ignore {
-                        let mut src_opt = None;
-                        let mut alt_opt = None;
-                        for attr in attrs.iter() {
-                            match &*attr.name.local {
-                                "src" => src_opt = Some(attr.value.to_string()),
-                                "alt" => alt_opt = Some(attr.value.to_string()),
-                                _ => ()
-                            }
-                        }
+        write!(stderr(), "* Redirected to {}\n", next).map_err(|err| LoadError::Other(format!("{}", err)))?;
 
-                        let mut use_alt = true;
-                        if let Some(src) = src_opt {
-                            if src.ends_with(".jpg") || src.ends_with(".jpeg") {
-                                let img_url = url.join(&src).unwrap();
-                                if let Ok((_img_headers, img_data)) = http_download(&img_url) {
-                                    if let Ok(img) = orbimage::parse_jpg(&img_data) {
-                                        use_alt = false;
-
-                                        let w = img.width() as i32;
-                                        let h = img.height() as i32;
-
-                                        blocks.push(Block {
-                                            x: *x,
-                                            y: *y,
-                                            w: w,
-                                            h: h,
-                                            color: color,
-                                            string: String::new(),
-                                            link: link.clone(),
-                                            image: Some(img),
-                                            text: None
-                                        });
-
-                                        *y += h;
-                                    }
-                                }
-                            } else if src.ends_with(".png") {
-                                let img_url = url.join(&src).unwrap();
-                                if let Ok((_img_headers, img_data)) = http_download(&img_url) {
-                                    if let Ok(img) = orbimage::parse_png(&img_data) {
-                                        use_alt = false;
-
-                                        let w = img.width() as i32;
-                                        let h = img.height() as i32;
-
-                                        blocks.push(Block {
-                                            x: *x,
-                                            y: *y,
-                                            w: w,
-                                            h: h,
-                                            color: color,
-                                            string: String::new(),
-                                            link: link.clone(),
-                                            image: Some(img),
-                                            text: None
-                                        });
-
-                                        *y += h;
-                                    }
-                                }
-                            }
-                        }
+        // Like every other browser, only 307 and 308 repeat a POST
+        if status != 307 && status != 308 {
+            body = None;
+        }
+        url = next;
+    }
 
-                        if use_alt {
-                            if let Some(alt) = alt_opt {
-                                text_block(&alt, x, y, size, bold, color, link.clone(), font, font_bold, window, blocks);
-                            }
-                        }
-                    }
+    Err(LoadError::TooManyRedirects(MAX_REDIRECTS))
+}
 
-                    ignore = true;
-                    new_line = true;
-                },
-                "li" => {
-                    new_line = true;
-                },
-                "p" => {
-                    new_line = true;
-                },
-                "tr" => {
-                    new_line = true;
-                }
+/// Undo any Content-Encoding of a response body
+fn decompress(mut fetched: Fetched) -> Result<Fetched, LoadError> {
+    let encoding = fetched.headers.get_raw("content-encoding").and_then(|x| str::from_utf8(x[0].as_slice()).ok()).unwrap_or("").to_string();
+    fetched.body = content_decoder(&encoding, fetched.body).map_err(LoadError::Other)?;
+    Ok(fetched)
+}
 
-                "head" => ignore = true,
-                "title" => ignore = true, //TODO: Grab title
-                "link" => ignore = true,
-                "meta" => ignore = true,
-                "script" => ignore = true,
-                "style" => ignore = true,
-                _ => ()
-            }
-        }
+/// Send a single request, answering GET requests from the cache when it has a copy that is fresh or
+/// still valid, and storing new responses as they are read
+fn http_fetch_once<'c>(url: &Url, body: Option<&[u8]>, session: &'c Session) -> Result<Fetched<'c>, LoadError> {
+    if body.is_some() {
+        let res = http_request(url, Method::Post, Headers::new(), body, session)?;
+        return Ok(Fetched {
+            url: url.clone(),
+            status: res.status,
+            headers: res.headers.clone(),
+            body: Box::new(res),
+        });
     }
 
-    for child in node.children.iter() {
-        walk(child.clone(), indent + 4, x, y, size, bold, color, ignore, whitespace, link.clone(), url, font, font_bold, window, anchors, blocks);
-    }
+    let stale = match session.cache.get(url) {
+        Lookup::Fresh(entry) => {
+            write!(stderr(), "* Cached {}\n", url).map_err(|err| LoadError::Other(format!("{}", err)))?;
+            return Ok(Fetched {
+                url: url.clone(),
+                status: StatusCode::Ok,
+                headers: entry.response_headers(),
+                body: Box::new(Cursor::new(entry.data.clone())),
+            });
+        },
+        Lookup::Stale(entry) => Some(entry),
+        Lookup::Miss => None
+    };
 
-    if new_line {
-        *whitespace = true;
-        *x = 0;
-        *y += size.ceil() as i32;
+    let validators = stale.as_ref().map_or(Headers::new(), |entry| entry.validators());
+    let res = http_request(url, Method::Get, validators, None, session)?;
+    if res.status == StatusCode::NotModified {
+        if let Some(entry) = stale {
+            write!(stderr(), "* Not modified {}\n", url).map_err(|err| LoadError::Other(format!("{}", err)))?;
+            let entry = session.cache.revalidate(url, &entry, &res.headers);
+            return Ok(Fetched {
+                url: url.clone(),
+                status: StatusCode::Ok,
+                headers: entry.response_headers(),
+                body: Box::new(Cursor::new(entry.data.clone())),
+            });
+        }
     }
-}
 
-// FIXME: Copy of str::escape_default from std, which is currently unstable
-pub fn escape_default(s: &str) -> String {
-    s.chars().flat_map(|c| c.escape_default()).collect()
+    let status = res.status;
+    let headers = res.headers.clone();
+    let body: Box<Read + 'c> = if status == StatusCode::Ok {
+        Box::new(session.cache.store(url, &headers, res))
+    } else {
+        Box::new(res)
+    };
+    Ok(Fetched {
+        url: url.clone(),
+        status: status,
+        headers: headers,
+        body: body,
+    })
 }
 
-fn http_download(url: &Url) -> Result<(Headers, Vec<u8>), String> {
-    write!(stderr(), "* Requesting {}\n", url).map_err(|err| format!("{}", err))?;
+/// Download a subresource such as a stylesheet or image, from either http(s) or file URLs,
+/// returning the response headers along with the data
+fn url_download(url: &Url, session: &Session) -> Result<(Headers, Vec<u8>), String> {
+    if url.scheme() == "http" || url.scheme() == "https" {
+        let mut fetched = http_fetch(url, None, session).map_err(|err| format!("{}", err))?;
+        if ! fetched.status.is_success() {
+            return Err(format!("{}", fetched.status));
+        }
+
+        let mut data = Vec::new();
+        fetched.body.read_to_end(&mut data).map_err(|err| format!("Failed to read response: {}", err))?;
+
+        write!(stderr(), "* Received {} bytes\n", data.len()).map_err(|err| format!("{}", err))?;
 
-    let mut client = Client::with_connector(HttpsConnector::new(hyper_rustls::TlsClient::new()));
-    client.set_read_timeout(Some(Duration::new(5, 0)));
-    client.set_write_timeout(Some(Duration::new(5, 0)));
-    let mut res = client.get(url.clone()).send().map_err(|err| format!("Failed to send request: {}", err))?;
-    let mut data = Vec::new();
-    res.read_to_end(&mut data).map_err(|err| format!("Failed to read response: {}", err))?;
+        Ok((fetched.headers, data))
+    } else if url.scheme() == "file" {
+        let path = url.to_file_path().map_err(|_| format!("{} is not a valid path", url))?;
+        let mut file = File::open(&path).map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Ok((Headers::new(), data))
+    } else {
+        Err(format!("{} scheme not found", url.scheme()))
+    }
+}
 
-    write!(stderr(), "* Received {} bytes\n", data.len()).map_err(|err| format!("{}", err))?;
+/// Parse HTML in the charset given by its Content-Type or found in the data, along with the
+/// stylesheets it links to
+fn html_parse(data: &[u8], content_type: &str, url: &Url, sheets: &mut BTreeMap<String, String>, session: &Session) -> Page {
+    let text = detect_charset(content_type, data, true).decode(data);
+    let dom = parse_document(RcDom::default(), Default::default()).one(text);
+
+    let mut stylesheet = session.theme.stylesheet();
+    collect_styles(&dom.document, url, &mut stylesheet, sheets, session);
+    session.theme.apply(&mut stylesheet);
+
+    if !dom.errors.is_empty() {
+        /*
+        println!("\nParse errors:");
+        for err in dom.errors.into_iter() {
+            println!("    {}", err);
+        }
+        */
+    }
 
-    Ok((res.headers.clone(), data))
+    layout::build(&dom.document, url, &stylesheet)
 }
 
-fn read_parse<'a, R: Read>(headers: Headers, r: &mut R, url: &Url, font: &'a Font, font_bold: &'a Font, window: &Window, anchors: &mut BTreeMap<String, i32>, blocks: &mut Vec<Block<'a>>) {
+/// Build a page from a response. HTML is reparsed as it arrives and passed to progress,
+/// which returns false to stop reading.
+fn read_parse<R: Read + ?Sized>(headers: Headers, r: &mut R, url: &Url, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
     let content_type = headers.get_raw("content-type").and_then(|x| str::from_utf8(x[0].as_slice()).ok()).unwrap_or("text/plain");
     let media_type = content_type.split(";").next().unwrap_or("");
 
+    if is_attachment(&headers) {
+        return download(&headers, r, url, session, progress);
+    }
+
     match media_type {
-        "text/plain" => {
-            let mut string = String::new();
-            match r.read_to_string(&mut string) {
-                Ok(_) => {
-                    let mut y = 0;
-                    for line in string.lines() {
-                        text_block(line, &mut 0, &mut y, 12.0, false, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                        y += 12;
-                    }
-                },
-                Err(err) => {
-                    let error = format!("Text data not readable: {}", err);
-                    text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                }
-            }
-        },
-        "text/html" => {
-            match parse_document(RcDom::default(), Default::default()).from_utf8().read_from(r) {
-                Ok(dom) => {
-                    let mut x = 0;
-                    let mut y = 0;
-                    let mut whitespace = false;
-                    walk(dom.document, 0, &mut x, &mut y, 16.0, false, Color::rgb(0, 0, 0), false, &mut whitespace, None, url, font, font_bold, window, anchors, blocks);
-
-                    if !dom.errors.is_empty() {
-                        /*
-                        println!("\nParse errors:");
-                        for err in dom.errors.into_iter() {
-                            println!("    {}", err);
-                        }
-                        */
-                    }
-                },
-                Err(err) => {
-                    let error = format!("HTML data not readable: {}", err);
-                    text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                }
-            }
-        },
-        "image/jpeg" => {
+        media_type if is_plain_text(media_type) => {
             let mut data = Vec::new();
             match r.read_to_end(&mut data) {
-                Ok(_) => match orbimage::parse_jpg(&data) {
-                    Ok(img) => {
-                        blocks.push(Block {
-                            x: 0,
-                            y: 0,
-                            w: img.width() as i32,
-                            h: img.height() as i32,
-                            color: Color::rgb(0, 0, 0),
-                            string: String::new(),
-                            link: None,
-                            image: Some(img),
-                            text: None
-                        });
-                    },
-                    Err(err) => {
-                        let error = format!("JPG data not readable: {}", err);
-                        text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                    }
+                Ok(_) => {
+                    let string = detect_charset(content_type, &data, false).decode(&data);
+                    let mut style = Style::default();
+                    style.font_size = 12.0;
+                    style.monospace = true;
+                    style.white_space = WhiteSpace::Pre;
+                    Page::new(LayoutBox::text(&string, &style))
                 },
-                Err(err) => {
-                    let error = format!("JPG stream not readable: {}", err);
-                    text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                }
+                Err(err) => Page::new(LayoutBox::message(&format!("Text data not readable: {}", err)))
             }
         },
-        "image/png" => {
+        "text/html" | "application/xhtml+xml" => {
+            let mut sheets = BTreeMap::new();
             let mut data = Vec::new();
-            match r.read_to_end(&mut data){
-                Ok(_) => match orbimage::parse_png(&data) {
-                    Ok(img) => {
-                        blocks.push(Block {
-                            x: 0,
-                            y: 0,
-                            w: img.width() as i32,
-                            h: img.height() as i32,
-                            color: Color::rgb(0, 0, 0),
-                            string: String::new(),
-                            link: None,
-                            image: Some(img),
-                            text: None
-                        });
+            let mut buf = [0; 16384];
+            let mut last_progress = Instant::now();
+            loop {
+                match r.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(count) => {
+                        data.extend_from_slice(&buf[..count]);
+
+                        if last_progress.elapsed() >= Duration::from_millis(PROGRESS_INTERVAL) {
+                            if ! progress(html_parse(&data, content_type, url, &mut sheets, session)) {
+                                return Page::new(LayoutBox::message("Stopped"));
+                            }
+                            last_progress = Instant::now();
+                        }
                     },
-                    Err(err) => {
-                        let error = format!("PNG data not readable: {}", err);
-                        text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                    }
-                },
-                Err(err) => {
-                    let error = format!("PNG stream not readable: {}", err);
-                    text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
+                    Err(err) => return Page::new(LayoutBox::message(&format!("HTML stream not readable: {}", err)))
                 }
             }
+
+            html_parse(&data, content_type, url, &mut sheets, session)
         },
-        "image/x-ms-bmp" => {
+        media_type if media_type.starts_with("image/") => {
             let mut data = Vec::new();
             match r.read_to_end(&mut data) {
-                Ok(_) => match orbimage::parse_bmp(&data) {
-                    Ok(img) => {
-                        blocks.push(Block {
-                            x: 0,
-                            y: 0,
-                            w: img.width() as i32,
-                            h: img.height() as i32,
-                            color: Color::rgb(0, 0, 0),
-                            string: String::new(),
-                            link: None,
-                            image: Some(img),
-                            text: None
-                        });
-                    },
-                    Err(err) => {
-                        let error = format!("BMP data not readable: {}", err);
-                        text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                    }
+                Ok(_) => match decode_image(&data, Some(media_type)) {
+                    Ok(img) => Page::image(url, img),
+                    Err(err) => Page::new(LayoutBox::message(&format!("Image data not readable: {}", err)))
                 },
-                Err(err) => {
-                    let error = format!("BMP stream not readable: {}", err);
-                    text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-                }
+                Err(err) => Page::new(LayoutBox::message(&format!("Image stream not readable: {}", err)))
             }
         },
-        _ => {
-            let error = format!("Unsupported content type: {}", content_type);
-            text_block(&error, &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, window, blocks);
-        }
+        _ => download(&headers, r, url, session, progress)
     }
 }
 
-fn file_parse<'a>(url: &Url, font: &'a Font, font_bold: &'a Font, window: &Window, anchors: &mut BTreeMap<String, i32>, blocks: &mut Vec<Block<'a>>) {
+/// Can a media type be shown as plain text, such as source code and data formats
+fn is_plain_text(media_type: &str) -> bool {
+    (media_type.starts_with("text/") && media_type != "text/html")
+        || media_type.ends_with("+xml") || media_type.ends_with("+json")
+        || media_type == "application/json" || media_type == "application/javascript" || media_type == "application/xml"
+}
+
+fn file_parse(url: &Url, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
     if let Ok(path) = url.to_file_path() {
+        if path.is_dir() {
+            return match directory_page(&path) {
+                Ok(html) => html_page(&html, url, session, progress),
+                Err(err) => Page::new(LayoutBox::message(&err))
+            };
+        }
+
         if let Ok(mut file) = File::open(&path) {
             let mut headers = Headers::new();
 
-            let mime_type = match path.extension().unwrap_or(OsStr::new("")).to_str().unwrap_or("") {
-                "html" => "text/html",
-                "jpg" | "jpeg" => "image/jpeg",
-                "png" => "image/png",
-                "bmp" => "image/x-ms-bmp",
-                _ => "text/plain",
+            // Files without a known extension may still be images, which can be told by their first bytes
+            let mut head = [0; 512];
+            let head_len = match file.read(&mut head) {
+                Ok(count) => count,
+                Err(err) => return Page::new(LayoutBox::message(&format!("Failed to read {}: {}", path.display(), err)))
             };
 
-            /* TODO {
-                let extension = path.extension().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
-                let mime_type = mime_guess::get_mime_type_str(extension).unwrap_or("application/octet-stream");
-                println!("{:?}", mime_type);
-            } */
+            let extension = path.extension().unwrap_or(OsStr::new("")).to_str().unwrap_or("").to_lowercase();
+            let mime_type = match mime_guess::get_mime_type_str(&extension) {
+                Some(mime_type) => mime_type,
+                None => match ImageFormat::sniff(&head[..head_len]) {
+                    Some(format) => format.media_type(),
+                    // Files without NUL bytes near the start are most likely text
+                    None if ! head[..head_len].contains(&0) => "text/plain",
+                    None => "application/octet-stream"
+                }
+            };
+
+            if ! is_plain_text(mime_type) && ! mime_type.starts_with("image/") && mime_type != "text/html" && mime_type != "application/xhtml+xml" {
+                let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                let html = format!(
+                    "<html><head><title>{}</title></head><body>\n<h1>{}</h1>\n<p>{} file, {}</p>\n<p>This file cannot be shown in the browser.</p>\n</body></html>\n",
+                    escape_html(&path.display().to_string()), escape_html(&path.display().to_string()), escape_html(mime_type), format_size(size)
+                );
+                return html_page(&html, url, session, progress);
+            }
 
             headers.set(header::ContentType(mime_type.parse().unwrap()));
 
-            read_parse(headers, &mut file, url, font, font_bold, window, anchors, blocks);
+            read_parse(headers, &mut (&head[..head_len]).chain(file), url, session, progress)
         } else {
-            println!("{} not found", path.display());
+            Page::new(LayoutBox::message(&format!("{} not found", path.display())))
         }
+    } else {
+        Page::new(LayoutBox::message(&format!("{} is not a valid path", url)))
     }
 }
 
-fn http_parse<'a>(url: &Url, font: &'a Font, font_bold: &'a Font, window: &Window, anchors: &mut BTreeMap<String, i32>, blocks: &mut Vec<Block<'a>>) {
-    match http_download(url) {
-        Ok((headers, response)) => {
-            read_parse(headers, &mut response.as_slice(), url, font, font_bold, window, anchors, blocks);
-        },
+fn http_parse(url: &Url, body: Option<&[u8]>, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
+    let mut fetched = match http_fetch(url, body, session) {
+        Ok(fetched) => fetched,
         Err(err) => {
-            let mut headers = Headers::new();
-            headers.set(header::ContentType("text/plain".parse().unwrap()));
-            let response = format!("{}", err).into_bytes();
-            read_parse(headers, &mut response.as_slice(), url, font, font_bold, window, anchors, blocks);
+            let html = error_page(err.title(), &format!("{}", err), url);
+            return html_page(&html, url, session, progress);
         }
-    }
+    };
+
+    // Pages carry the URL they came from after redirects, for the address bar
+    let url = fetched.url.clone();
+    let mut progress = |mut page: Page| -> bool {
+        page.url = Some(url.clone());
+        progress(page)
+    };
+
+    let mut page = if fetched.status.is_client_error() || fetched.status.is_server_error() {
+        let status = format!("{} {}", fetched.status.to_u16(), fetched.status.canonical_reason().unwrap_or("Error"));
+        let _ = write!(stderr(), "* Status {}\n", status);
+
+        // Show the page the server sent if there is one, otherwise one of our own
+        let html = fetched.headers.get_raw("content-type").and_then(|x| str::from_utf8(x[0].as_slice()).ok()).map_or(false, |content_type| content_type.starts_with("text/html"));
+        let mut data = Vec::new();
+        let _ = fetched.body.read_to_end(&mut data);
+        let mut page = if html && ! data.is_empty() {
+            read_parse(fetched.headers, &mut data.as_slice(), &url, session, &mut progress)
+        } else {
+            let message = format!("The server answered {} with {}", url, status);
+            html_page(&error_page(&status, &message, &url), &url, session, &mut progress)
+        };
+        if page.title.is_none() {
+            page.title = Some(status);
+        }
+        page
+    } else {
+        read_parse(fetched.headers, &mut *fetched.body, &url, session, &mut progress)
+    };
+    page.url = Some(url.clone());
+    page
 }
 
-fn url_parse<'a>(url: &Url, font: &'a Font, font_bold: &'a Font, window: &Window, anchors: &mut BTreeMap<String, i32>, blocks: &mut Vec<Block<'a>>) {
+/// Build a page from HTML generated by the browser
+fn html_page(html: &str, url: &Url, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
+    let mut headers = Headers::new();
+    headers.set(header::ContentType::html());
+    read_parse(headers, &mut html.as_bytes(), url, session, progress)
+}
+
+/// Load a URL, posting body to it when it is a form submission
+fn url_parse(url: &Url, body: Option<&[u8]>, session: &Session, progress: &mut FnMut(Page) -> bool) -> Page {
     if url.scheme() == "http" || url.scheme() == "https" {
-        http_parse(url, font, font_bold, window, anchors, blocks)
+        http_parse(url, body, session, progress)
     } else if url.scheme() == "file" {
-        file_parse(url, font, font_bold, window, anchors, blocks)
+        file_parse(url, session, progress)
+    } else if url.scheme() == "gopher" {
+        gopher_parse(url, body, session, progress)
+    } else if url.scheme() == "gemini" {
+        gemini_parse(url, body, session, progress)
+    } else if url.as_str() == "about:blank" {
+        Page::new(LayoutBox::text("", &Style::default()))
+    } else if url.scheme() == "about" {
+        match about_page(url, body, session) {
+            Some(html) => html_page(&html, url, session, progress),
+            None => Page::new(LayoutBox::message(&format!("{} not found", url)))
+        }
     } else {
-        println!("{} scheme not found", url.scheme());
+        Page::new(LayoutBox::message(&format!("{} scheme not found", url.scheme())))
     }
 }
 
-fn main_window(arg: &str, font: &Font, font_bold: &Font) {
-    let mut history = vec![];
+/// Turn what was typed in the address bar into a URL, assuming http when no scheme is given
+fn parse_address(text: &str) -> Option<Url> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
 
-    let mut url = Url::parse(arg).unwrap();
+    // A host and port such as localhost:8080 also parses, with the host as its scheme
+    match Url::parse(text) {
+        Ok(ref url) if text.contains("://") || SCHEMES.contains(&url.scheme()) => Some(url.clone()),
+        _ => Url::parse(&format!("http://{}", text)).ok()
+    }
+}
 
+fn main_window(arg: &str, fonts: &Fonts) {
+    // The browser's own controls use the regular face
+    let font = &fonts.regular;
     let (display_width, display_height) = orbclient::get_display_size().expect("viewer: failed to get display size");
-    let (window_w, window_h) = (cmp::min(1024, display_width * 4/5) as i32, cmp::min(768, display_height * 4/5) as i32);
+    let (mut window_w, mut window_h) = (cmp::min(1024, display_width * 4/5) as i32, cmp::min(768, display_height * 4/5) as i32);
 
     let mut window = Window::new_flags(
-        -1, -1, window_w as u32, window_h as u32,  "Browser", &[WindowFlag::Resizable]
+        -1, -1, window_w as u32, window_h as u32,  "Browser", &[WindowFlag::Async, WindowFlag::Resizable]
     ).unwrap();
 
-    let mut anchors = BTreeMap::new();
-    let mut blocks = Vec::new();
-
-    let mut offset = (0, 0);
-    let mut max_offset = (0, 0);
+    let session = Arc::new(Session::new());
+    let mut toolbar = Toolbar::new();
+    let mut find = FindBar::new();
+    let mut hints = Hints::new();
+    let mut tabs = vec![Tab::new(Url::parse(arg).unwrap())];
+    let mut current = 0;
 
     let mut mouse_x = 0;
     let mut mouse_y = 0;
     let mut mouse_down = false;
+    let mut mouse_middle = false;
+    // Where a left press started on the page, and has it been dragged far enough to select
+    let mut drag_start: Option<(i32, i32)> = None;
+    let mut selecting = false;
+    let mut ctrl = false;
+    let mut alt = false;
+    let mut shift = false;
 
-    let mut reload = true;
     let mut redraw = true;
     loop {
-        if reload {
-            reload = false;
-
-            window.set_title(&format!("{} - Browser", url));
+        let mut idle = true;
 
-            anchors.clear();
-            blocks.clear();
-            text_block("Loading...", &mut 0, &mut 0, 16.0, true, Color::rgb(0, 0, 0), None, font, font_bold, &window, &mut blocks);
-
-            {
-                window.set(Color::rgb(255, 255, 255));
+        if tabs[current].reload {
+            if ! toolbar.focused {
+                toolbar.text = tabs[current].url.to_string();
+            }
+        }
 
-                for block in blocks.iter() {
-                    block.draw(&mut window, (0, 0));
+        // Every tab loads in the background, but only the current one is laid out
+        for (i, tab) in tabs.iter_mut().enumerate() {
+            if tab.reload {
+                tab.load(&session);
+                if i == current {
+                    window.set_title(&format!("{} - Browser", tab.url));
                 }
-
-                window.sync();
+                redraw = true;
             }
 
-            anchors.clear();
-            blocks.clear();
-            url_parse(&url, &font, &font_bold, &window, &mut anchors, &mut blocks);
-
-            offset = (0, 0);
-            max_offset = (0, 0);
-            for block in blocks.iter() {
-                if block.x + block.w > max_offset.0 {
-                    max_offset.0 = block.x + block.w;
+            let was_loading = tab.loading();
+            if tab.poll() {
+                idle = false;
+                if was_loading && ! tab.loading() {
+                    session.history.visit(&tab.url, tab.page.title.as_ref().map(|title| &title[..]));
                 }
-                if block.y + block.h > max_offset.1 {
-                    max_offset.1 = block.y + block.h;
+                if i == current {
+                    window.set_title(&format!("{} - Browser", tab.title()));
+                    // The URL changes when a redirect is followed
+                    if ! toolbar.focused {
+                        toolbar.text = tab.url.to_string();
+                    }
                 }
+                redraw = true;
             }
+        }
+
+        let view_h = window_h - CHROME_HEIGHT - if find.open { FIND_HEIGHT } else { 0 };
 
+        if tabs[current].relayout {
+            let zoom = session.zoom.get(&tabs[current].url);
+            tabs[current].layout(window_w, view_h, zoom, fonts);
+            find.search(&tabs[current].blocks);
+            // The links may have moved from under their labels
+            hints.hide();
             redraw = true;
         }
 
         if redraw {
             redraw = false;
 
-            window.set(Color::rgb(255, 255, 255));
+            window.set(tabs[current].page.background().unwrap_or(Color::rgb(255, 255, 255)));
+
+            {
+                let tab = &tabs[current];
+                for (i, block) in tab.blocks.iter().enumerate() {
+                    let highlight = if tab.selected(i) && is_text(block) {
+                        Some(SELECTION_COLOR)
+                    } else {
+                        find.highlight(i)
+                    };
+                    block.draw(&mut window, (tab.offset.0, tab.offset.1 - CHROME_HEIGHT), highlight);
+                }
+
+                if let Some(target) = tab.link_focus.and_then(|i| tab.targets.get(i)) {
+                    target.draw_focus(&mut window, &tab.blocks, (tab.offset.0, tab.offset.1 - CHROME_HEIGHT));
+                }
+                if hints.open {
+                    hints.draw(&mut window, font, &tab.targets, &tab.blocks, (tab.offset.0, tab.offset.1 - CHROME_HEIGHT));
+                }
+            }
 
-            for block in blocks.iter() {
-                block.draw(&mut window, offset);
+            if find.open {
+                find.draw(&mut window, font);
             }
 
+            draw_tabs(&mut window, font, &mut tabs, current);
+            toolbar.draw(&mut window, TAB_HEIGHT, font, ! tabs[current].history.is_empty(), ! tabs[current].forward.is_empty(), tabs[current].loading(), session.bookmarks.contains(tabs[current].url.as_str()), session.accepts_self_signed(&tabs[current].url));
+            toolbar.draw_suggestions(&mut window, CHROME_HEIGHT, font);
+
             window.sync();
         }
 
+        let mut submit_opt = None;
+        let mut action_opt = None;
+        let mut tab_action_opt = None;
+        let mut new_tab_opt = None;
+        // A link to follow, and should it open in a new tab
+        let mut follow_opt = None;
+        // Zoom in, out, or back to normal with None
+        let mut zoom_opt = None;
+        let mut resized = false;
+        // Save the current page as a PDF for this paper size
+        let mut print_opt = None;
+        // Pages are styled as they are parsed, so a change of theme reloads them
+        let mut restyled = false;
+        let tabs_len = tabs.len();
         for event in window.events() {
+            idle = false;
+            let tab = &mut tabs[current];
             match event.to_option() {
-                EventOption::Key(key_event) => if key_event.pressed {
-                    match key_event.scancode {
-                        K_ESC => return,
-                        K_LEFT => {
-                            redraw = true;
-                            offset.0 = cmp::max(0, offset.0 - 60);
-                        },
-                        K_RIGHT => {
-                            redraw = true;
-                            offset.0 = cmp::min(cmp::max(0, max_offset.0 - window_w), offset.0 + 60);
-                        },
-                        K_UP => {
-                            redraw = true;
-                            offset.1 = cmp::max(0, offset.1 - 60);
-                        },
-                        K_PGUP => {
+                EventOption::Key(key_event) => match key_event.scancode {
+                    K_CTRL => ctrl = key_event.pressed,
+                    K_ALT => alt = key_event.pressed,
+                    K_LEFT_SHIFT | K_RIGHT_SHIFT => shift = key_event.pressed,
+                    _ => if key_event.pressed {
+                        if ctrl && key_event.scancode == K_T {
+                            tab_action_opt = Some(TabAction::New);
+                        } else if ctrl && key_event.scancode == K_W {
+                            tab_action_opt = Some(TabAction::Close(current));
+                        } else if ctrl && key_event.scancode == K_TAB {
+                            tab_action_opt = Some(TabAction::Select((current + 1) % tabs_len));
+                        } else if toolbar.focused {
+                            match key_event.scancode {
+                                K_ESC => {
+                                    toolbar.focused = false;
+                                    toolbar.text = tab.url.to_string();
+                                },
+                                K_ENTER => {
+                                    toolbar.focused = false;
+                                    match parse_address(toolbar.address()) {
+                                        Some(address) => tab.navigate(address, None),
+                                        None => toolbar.text = tab.url.to_string()
+                                    }
+                                },
+                                K_UP => toolbar.select(false),
+                                K_DOWN => toolbar.select(true),
+                                K_BKSP => {
+                                    toolbar.text.pop();
+                                    toolbar.suggest(session.history.suggest(&toolbar.text, MAX_SUGGESTIONS));
+                                },
+                                K_V if ctrl => {
+                                    toolbar.text.push_str(window.clipboard().trim());
+                                    toolbar.suggest(session.history.suggest(&toolbar.text, MAX_SUGGESTIONS));
+                                },
+                                _ => if key_event.character != '\0' && ! key_event.character.is_control() && ! ctrl {
+                                    toolbar.text.push(key_event.character);
+                                    toolbar.suggest(session.history.suggest(&toolbar.text, MAX_SUGGESTIONS));
+                                }
+                            }
                             redraw = true;
-                            offset.1 = cmp::max(0, offset.1 - 600);
-                        },
-                        K_DOWN => {
+                        } else if hints.open && ! ctrl && ! alt {
+                            match key_event.scancode {
+                                K_ESC => hints.hide(),
+                                K_BKSP => hints.back(),
+                                _ => if key_event.character != '\0' && ! key_event.character.is_control() {
+                                    if let Some(i) = hints.key(key_event.character) {
+                                        if let Some(ref link) = tab.targets[i].link {
+                                            follow_opt = Some((link.clone(), hints.new_tab));
+                                        }
+                                    }
+                                }
+                            }
                             redraw = true;
-                            offset.1 = cmp::min(cmp::max(0, max_offset.1 - window_h), offset.1 + 60);
-                        },
-                        K_PGDN => {
+                        } else if find.focused && (! ctrl || key_event.scancode == K_V) {
+                            // Other Ctrl shortcuts still work while typing in the find bar
+                            let mut changed = false;
+                            match key_event.scancode {
+                                K_ESC => find.hide(),
+                                K_ENTER | K_F3 => if let Some(i) = find.step(! shift) {
+                                    tab.scroll_to_block(i, window_w, view_h);
+                                },
+                                K_BKSP => changed = find.text.pop().is_some(),
+                                K_V => {
+                                    find.text.push_str(window.clipboard().trim());
+                                    changed = true;
+                                },
+                                _ => if key_event.character != '\0' && ! key_event.character.is_control() {
+                                    find.text.push(key_event.character);
+                                    changed = true;
+                                }
+                            }
+                            if changed {
+                                find.search(&tab.blocks);
+                                if let Some(i) = find.first() {
+                                    tab.scroll_to_block(i, window_w, view_h);
+                                }
+                            }
+                            // Opening or closing the bar changes the height of the page view
+                            tab.scroll(0, 0, window_w, window_h - CHROME_HEIGHT - if find.open { FIND_HEIGHT } else { 0 });
                             redraw = true;
-                            offset.1 = cmp::min(cmp::max(0, max_offset.1 - window_h), offset.1 + 600);
-                        },
-                        K_BKSP => if let Some(last_url) = history.pop() {
-                            url = last_url;
-                            reload = true;
-                        },
-                        _ => ()
+                        } else if ctrl || alt {
+                            match key_event.scancode {
+                                K_L if ctrl => {
+                                    find.focused = false;
+                                    toolbar.focus();
+                                    redraw = true;
+                                },
+                                K_F if ctrl => {
+                                    find.show();
+                                    find.search(&tab.blocks);
+                                    redraw = true;
+                                },
+                                K_C if ctrl => if let Some(text) = tab.selected_text() {
+                                    window.set_clipboard(&text);
+                                },
+                                K_D if ctrl && alt => {
+                                    let dark = session.theme.toggle_dark();
+                                    println!("Dark mode {}", if dark { "on" } else { "off" });
+                                    restyled = true;
+                                },
+                                K_D if ctrl => {
+                                    if session.bookmarks.add(tab.url.as_str(), &tab.title()) {
+                                        println!("Bookmarked {}", tab.url);
+                                    }
+                                    redraw = true;
+                                },
+                                K_B if ctrl => tab.navigate(Url::parse("about:bookmarks").unwrap(), None),
+                                K_H if ctrl => tab.navigate(Url::parse("about:history").unwrap(), None),
+                                K_P if ctrl => print_opt = Some(if shift { Paper::Letter } else { Paper::A4 }),
+                                K_R if ctrl && alt => match reader_source(&tab.url) {
+                                    Some(source) => tab.navigate(source, None),
+                                    None => tab.navigate(reader_url(&tab.url), None)
+                                },
+                                K_R if ctrl => action_opt = Some(ToolbarAction::Reload),
+                                K_EQUALS if ctrl => zoom_opt = Some(Some(true)),
+                                K_MINUS if ctrl => zoom_opt = Some(Some(false)),
+                                K_0 if ctrl => zoom_opt = Some(None),
+                                K_LEFT if alt => action_opt = Some(ToolbarAction::Back),
+                                K_RIGHT if alt => action_opt = Some(ToolbarAction::Forward),
+                                _ => ()
+                            }
+                        } else {
+                            // Keys go to the focused form control first
+                            let mut handled = true;
+                            match (tab.focus, key_event.scancode) {
+                                (Some(_), K_ESC) => tab.focus = None,
+                                (None, K_ESC) if tab.link_focus.is_some() => tab.link_focus = None,
+                                (_, K_TAB) => tab.step_focus(! shift, window_w, view_h),
+                                (None, K_ENTER) if tab.link_focus.is_some() => follow_opt = tab.focused_link().map(|link| (link, false)),
+                                (Some(i), K_ENTER) => match tab.page.forms.controls[i].kind {
+                                    ControlKind::TextArea => tab.page.forms.controls[i].value.push('\n'),
+                                    ControlKind::Checkbox | ControlKind::Radio => tab.page.forms.toggle(i),
+                                    ControlKind::Reset => if let Some(form) = tab.page.forms.controls[i].form {
+                                        tab.page.forms.reset(form);
+                                    },
+                                    ControlKind::Button => (),
+                                    _ => submit_opt = Some(i)
+                                },
+                                (Some(i), K_SPACE) if ! tab.page.forms.controls[i].editable() => match tab.page.forms.controls[i].kind {
+                                    ControlKind::Checkbox | ControlKind::Radio => tab.page.forms.toggle(i),
+                                    ControlKind::Select => tab.page.forms.select(i, true),
+                                    ControlKind::Submit => submit_opt = Some(i),
+                                    ControlKind::Reset => if let Some(form) = tab.page.forms.controls[i].form {
+                                        tab.page.forms.reset(form);
+                                    },
+                                    _ => ()
+                                },
+                                (Some(i), K_UP) if tab.page.forms.controls[i].kind == ControlKind::Select => tab.page.forms.select(i, false),
+                                (Some(i), K_DOWN) if tab.page.forms.controls[i].kind == ControlKind::Select => tab.page.forms.select(i, true),
+                                (Some(i), K_BKSP) if tab.page.forms.controls[i].editable() => {
+                                    tab.page.forms.controls[i].value.pop();
+                                },
+                                (Some(i), _) if tab.page.forms.controls[i].editable() && key_event.character != '\0' && ! key_event.character.is_control() => {
+                                    tab.page.forms.controls[i].value.push(key_event.character);
+                                },
+                                _ => handled = false
+                            }
+
+                            if handled {
+                                tab.relayout = true;
+                            } else {
+                                match key_event.scancode {
+                                    K_ESC => if find.open {
+                                        find.hide();
+                                        tab.scroll(0, 0, window_w, window_h - CHROME_HEIGHT);
+                                        redraw = true;
+                                    } else if tab.loading() {
+                                        tab.stop();
+                                        redraw = true;
+                                    } else {
+                                        return;
+                                    },
+                                    K_LEFT => {
+                                        redraw = true;
+                                        tab.scroll(-60, 0, window_w, view_h);
+                                    },
+                                    K_RIGHT => {
+                                        redraw = true;
+                                        tab.scroll(60, 0, window_w, view_h);
+                                    },
+                                    K_UP => {
+                                        redraw = true;
+                                        tab.scroll(0, -60, window_w, view_h);
+                                    },
+                                    K_PGUP => {
+                                        redraw = true;
+                                        tab.scroll(0, -600, window_w, view_h);
+                                    },
+                                    K_DOWN => {
+                                        redraw = true;
+                                        tab.scroll(0, 60, window_w, view_h);
+                                    },
+                                    K_PGDN => {
+                                        redraw = true;
+                                        tab.scroll(0, 600, window_w, view_h);
+                                    },
+                                    K_BKSP => action_opt = Some(ToolbarAction::Back),
+                                    K_F3 => if let Some(i) = find.step(! shift) {
+                                        tab.scroll_to_block(i, window_w, view_h);
+                                        redraw = true;
+                                    },
+                                    K_F5 => action_opt = Some(ToolbarAction::Reload),
+                                    // Label the links in view, with Shift to open the chosen one in a new tab
+                                    K_F => if ! hints.show(&tab.targets, &tab.blocks, tab.offset, window_w, view_h, shift) {
+                                        println!("No links in view");
+                                    } else {
+                                        redraw = true;
+                                    },
+                                    _ => ()
+                                }
+                            }
+                        }
                     }
                 },
                 EventOption::Mouse(mouse_event) => {
                     mouse_x = mouse_event.x;
                     mouse_y = mouse_event.y;
+
+                    if let Some((start_x, start_y)) = drag_start {
+                        let x = mouse_x + tab.offset.0;
+                        let y = mouse_y - CHROME_HEIGHT + tab.offset.1;
+                        if ! selecting && ((x - start_x).abs() > DRAG_THRESHOLD || (y - start_y).abs() > DRAG_THRESHOLD) {
+                            selecting = true;
+                        }
+                        if selecting {
+                            let anchor = text_block_at(&tab.blocks, start_x, start_y);
+                            let focus = text_block_at(&tab.blocks, x, y);
+                            tab.selection = match (anchor, focus) {
+                                (Some(anchor), Some(focus)) => Some((anchor, focus)),
+                                _ => None
+                            };
+                            redraw = true;
+                        }
+                    }
                 },
                 EventOption::Button(button_event) => {
-                    if button_event.left {
+                    if button_event.left || button_event.middle {
+                        if ! mouse_down && button_event.left && mouse_y >= CHROME_HEIGHT && mouse_y < CHROME_HEIGHT + view_h {
+                            drag_start = Some((mouse_x + tab.offset.0, mouse_y - CHROME_HEIGHT + tab.offset.1));
+                            selecting = false;
+                        }
                         mouse_down = true;
+                        mouse_middle = button_event.middle;
                     } else if mouse_down {
                         mouse_down = false;
+                        drag_start = None;
+
+                        // Letting go after a drag finishes a selection rather than clicking
+                        if selecting {
+                            selecting = false;
+                            continue;
+                        }
+
+                        if mouse_y < TAB_HEIGHT {
+                            tab_action_opt = tab_click(mouse_x, mouse_y, tabs_len, window_w);
+                            continue;
+                        }
+
+                        if let Some(i) = toolbar.suggestion_click(mouse_x, mouse_y - CHROME_HEIGHT) {
+                            toolbar.focused = false;
+                            match Url::parse(&toolbar.suggestions[i].url) {
+                                Ok(url) => tab.navigate(url, None),
+                                Err(err) => println!("Invalid history entry {}: {}", toolbar.suggestions[i].url, err)
+                            }
+                            continue;
+                        }
+
+                        if mouse_y < CHROME_HEIGHT {
+                            find.focused = false;
+                            action_opt = toolbar.click(mouse_x, mouse_y - TAB_HEIGHT);
+                            continue;
+                        }
+
+                        if toolbar.focused {
+                            toolbar.focused = false;
+                            toolbar.text = tab.url.to_string();
+                            redraw = true;
+                        }
+
+                        if mouse_y >= CHROME_HEIGHT + view_h {
+                            find.focused = true;
+                            redraw = true;
+                            continue;
+                        }
+
+                        if find.focused || tab.selection.is_some() || hints.open {
+                            find.focused = false;
+                            tab.selection = None;
+                            hints.hide();
+                            redraw = true;
+                        }
 
                         let mut link_opt = None;
-                        for block in blocks.iter() {
-                            if block.contains(mouse_x, mouse_y, offset) {
+                        let mut control_opt = None;
+                        for block in tab.blocks.iter() {
+                            if block.contains(mouse_x, mouse_y, (tab.offset.0, tab.offset.1 - CHROME_HEIGHT)) {
                                 println!("Click {}", block.string);
+                                if let Some(i) = block.control {
+                                    control_opt = Some(i);
+                                    break;
+                                }
                                 if let Some(ref link) = block.link {
                                     link_opt = Some(link.clone());
                                     break;
@@ -683,45 +1055,204 @@ fn main_window(arg: &str, font: &Font, font_bold: &Font) {
                             }
                         }
 
-                        if let Some(link) = link_opt {
-                            if link.starts_with('#') {
-                                if let Some(anchor) = anchors.get(&link[1..]) {
-                                    println!("Anchor {}: {}", link, *anchor);
-                                    offset.0 = 0;
-                                    offset.1 = *anchor;
-                                    redraw = true;
-                                } else {
-                                    println!("Anchor {} not found", link);
-                                }
-                            } else {
-                                history.push(url.clone());
-
-                                url = url.join(&link).unwrap();
+                        if mouse_middle {
+                            // Middle clicking a link opens it in a new tab in the background
+                            if let Some(link) = link_opt {
+                                follow_opt = Some((link, true));
+                            }
+                            continue;
+                        }
 
-                                println!("Navigate {}: {:#?}", link, url);
+                        if tab.link_focus.is_some() {
+                            tab.link_focus = None;
+                            redraw = true;
+                        }
+                        if tab.focus != control_opt {
+                            tab.focus = control_opt;
+                            tab.relayout = true;
+                        }
 
-                                reload = true;
+                        if let Some(i) = control_opt {
+                            match tab.page.forms.controls[i].kind {
+                                ControlKind::Checkbox | ControlKind::Radio => tab.page.forms.toggle(i),
+                                ControlKind::Select => tab.page.forms.select(i, true),
+                                ControlKind::Submit => submit_opt = Some(i),
+                                ControlKind::Reset => if let Some(form) = tab.page.forms.controls[i].form {
+                                    tab.page.forms.reset(form);
+                                },
+                                _ => ()
                             }
+                            tab.relayout = true;
+                        } else if let Some(link) = link_opt {
+                            follow_opt = Some((link, false));
                         }
                     }
                 },
                 EventOption::Scroll(scroll_event) => {
-                    offset.0 = cmp::max(0, cmp::min(cmp::max(0, max_offset.0 - window_w), offset.0 - scroll_event.x * 48));
-                    offset.1 = cmp::max(0, cmp::min(cmp::max(0, max_offset.1 - window_h), offset.1 - scroll_event.y * 48));
-
+                    tab.scroll(-scroll_event.x * 48, -scroll_event.y * 48, window_w, view_h);
                     redraw = true;
                 },
-                EventOption::Resize(_) => {
-                    redraw = true;
+                EventOption::Resize(resize_event) => {
+                    window_w = resize_event.width as i32;
+                    window_h = resize_event.height as i32;
+                    resized = true;
                 },
                 EventOption::Quit(_) => return,
                 _ => ()
             }
         }
+
+        if let Some(step) = zoom_opt {
+            let level = session.zoom.change(&tabs[current].url, step);
+            println!("Zoom {} to {}%", tabs[current].url, (level * 100.0).round());
+        }
+
+        if let Some(paper) = print_opt {
+            // The page is laid out to fit the paper, and back to fit the window afterwards
+            let tab = &mut tabs[current];
+            let width = paper.layout_width();
+            tab.layout(width, view_h, 1.0, fonts);
+            let pdf = encode_pdf(&tab.blocks, &tab.anchors, width, &tab.url, &tab.title(), paper);
+            match session.downloads.save(&tab.url, &pdf_filename(&tab.title()), &pdf) {
+                Ok(path) => println!("Saved {} to {}", tab.url, path.display()),
+                Err(err) => println!("{}", err)
+            }
+            tab.relayout = true;
+        }
+
+        if restyled {
+            for tab in tabs.iter_mut() {
+                tab.reload = true;
+            }
+        }
+
+        // Other tabs are laid out again when they are next shown
+        if resized || zoom_opt.is_some() {
+            for tab in tabs.iter_mut() {
+                tab.relayout = true;
+            }
+        }
+
+        match action_opt {
+            Some(ToolbarAction::Back) => tabs[current].back(),
+            Some(ToolbarAction::Forward) => tabs[current].forward(),
+            Some(ToolbarAction::Reload) => tabs[current].reload = true,
+            Some(ToolbarAction::Stop) => {
+                tabs[current].stop();
+                redraw = true;
+            },
+            Some(ToolbarAction::Bookmark) => {
+                let tab = &tabs[current];
+                if session.bookmarks.contains(tab.url.as_str()) {
+                    session.bookmarks.remove(tab.url.as_str());
+                } else {
+                    session.bookmarks.add(tab.url.as_str(), &tab.title());
+                }
+                redraw = true;
+            },
+            Some(ToolbarAction::UrlBar) => {
+                find.focused = false;
+                toolbar.focus();
+                redraw = true;
+            },
+            None => ()
+        }
+
+        if let Some(i) = submit_opt {
+            let tab = &mut tabs[current];
+            if let Some(form) = tab.page.forms.controls[i].form {
+                // Submitting from a text field acts as if the first submit button was pressed
+                let submitter = if tab.page.forms.controls[i].kind == ControlKind::Submit {
+                    Some(i)
+                } else {
+                    tab.page.forms.default_button(form)
+                };
+
+                match tab.page.forms.submit(form, submitter, &tab.url) {
+                    // Pages of the browser itself act on what is posted to them, such as opening a download,
+                    // so each only takes posts from itself. The reader view is an about: page too, but shows
+                    // what a web page wrote.
+                    Ok((ref action, Some(_))) if action.scheme() == "about" && (tab.url.scheme() != "about" || tab.url.path() != action.path()) => {
+                        println!("Refused to submit {} from {}", action, tab.url);
+                    },
+                    Ok((action, body)) => {
+                        println!("Submit {}", action);
+                        tab.navigate(action, body);
+                    },
+                    Err(err) => println!("{}", err)
+                }
+            }
+        }
+
+        if let Some((link, new_tab)) = follow_opt {
+            let tab = &mut tabs[current];
+            if new_tab {
+                match tab.url.join(&link) {
+                    Ok(link_url) => new_tab_opt = Some(link_url),
+                    Err(err) => println!("Invalid link {}: {}", link, err)
+                }
+            } else {
+                tab.follow(&link);
+            }
+            redraw = true;
+        }
+
+        if let Some(link_url) = new_tab_opt {
+            tabs.push(Tab::new(link_url));
+            redraw = true;
+        }
+
+        let mut switched = false;
+        match tab_action_opt {
+            Some(TabAction::Select(i)) => if i < tabs.len() && i != current {
+                current = i;
+                switched = true;
+            },
+            Some(TabAction::Close(i)) => if i < tabs.len() {
+                tabs.remove(i);
+                if tabs.is_empty() {
+                    return;
+                }
+                if current >= i && current > 0 {
+                    current -= 1;
+                }
+                switched = true;
+            },
+            Some(TabAction::New) => {
+                tabs.push(Tab::new(Url::parse("about:blank").unwrap()));
+                current = tabs.len() - 1;
+                toolbar.focus();
+                redraw = true;
+            },
+            None => ()
+        }
+
+        if switched {
+            find.search(&tabs[current].blocks);
+            hints.hide();
+            toolbar.focused = false;
+            toolbar.text = tabs[current].url.to_string();
+            window.set_title(&format!("{} - Browser", tabs[current].title()));
+            redraw = true;
+        }
+
+        // The window does not block for events, so wait a little when there is nothing to do
+        if idle && ! redraw {
+            thread::sleep(Duration::from_millis(10));
+        }
     }
 }
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if headless::is_headless(&args) {
+        if let Err(err) = Fonts::find().and_then(|fonts| headless::run(&args, &fonts)) {
+            let _ = writeln!(stderr(), "browser: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
     let err_window = |msg: &str| {
         let mut window = Window::new(-1, -1, 320, 32, "Browser").unwrap();
 
@@ -749,11 +1280,8 @@ fn main() {
         }
     };
 
-    match Font::find(None, None, None) {
-        Ok(font) => match Font::find(None, None, Some("Bold")) {
-            Ok(font_bold) => main_window(&env::args().nth(1).unwrap_or("https://www.redox-os.org".to_string()), &font, &font_bold),
-            Err(err) => err_window(&format!("{}", err))
-        },
+    match Fonts::find() {
+        Ok(fonts) => main_window(&args.get(0).cloned().unwrap_or("https://www.redox-os.org".to_string()), &fonts),
         Err(err) => err_window(&format!("{}", err))
     }
 }
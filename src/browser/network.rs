@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{stderr, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{HttpStream, SslClient};
+use hyper_rustls::{TlsClient, TlsStream};
+use rustls::{Certificate, ClientConfig, ClientSession, Session};
+use url::Url;
+
+use session::TIMEOUT;
+
+/// How connections are made, from the usual proxy environment variables and then
+/// ~/.config/browser/network.txt, which holds lines such as:
+///
+///     https_proxy http://proxy.example.com:3128
+///     no_proxy localhost, .internal.example.com
+///     ca_bundle /etc/ssl/internal-ca.pem
+///     self_signed localhost
+pub struct NetworkConfig {
+    /// The host and port of the proxy for each scheme
+    pub http_proxy: Option<(String, u16)>,
+    pub https_proxy: Option<(String, u16)>,
+    /// Hosts reached without the proxy, where a leading dot also matches subdomains
+    pub no_proxy: Vec<String>,
+    /// A PEM file of certificate authorities to trust besides the built in ones
+    pub ca_bundle: Option<PathBuf>,
+    /// Hosts whose certificates are accepted without being checked, for local test servers
+    pub self_signed: Vec<String>,
+}
+
+impl NetworkConfig {
+    pub fn load() -> NetworkConfig {
+        let var = |name: &str| env::var(name).or_else(|_| env::var(name.to_uppercase())).ok();
+
+        let mut config = NetworkConfig {
+            http_proxy: var("http_proxy").and_then(|value| parse_proxy(&value)),
+            https_proxy: var("https_proxy").and_then(|value| parse_proxy(&value)),
+            no_proxy: Vec::new(),
+            ca_bundle: None,
+            self_signed: Vec::new(),
+        };
+        if let Some(value) = var("no_proxy") {
+            push_hosts(&mut config.no_proxy, &value);
+        }
+
+        let path = match env::home_dir() {
+            Some(home) => home.join(".config").join("browser").join("network.txt"),
+            None => return config
+        };
+        let mut data = String::new();
+        if let Ok(mut file) = File::open(&path) {
+            if let Err(err) = file.read_to_string(&mut data) {
+                println!("Failed to read {}: {}", path.display(), err);
+            }
+        }
+
+        for line in data.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "http_proxy" => config.http_proxy = parse_proxy(value),
+                "https_proxy" => config.https_proxy = parse_proxy(value),
+                "no_proxy" => push_hosts(&mut config.no_proxy, value),
+                "ca_bundle" => config.ca_bundle = Some(PathBuf::from(value)),
+                "self_signed" => push_hosts(&mut config.self_signed, value),
+                _ => println!("Unknown setting {} in {}", key, path.display())
+            }
+        }
+
+        config
+    }
+
+    /// Is a host reached directly even when a proxy is set
+    pub fn bypasses_proxy(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.no_proxy.iter().any(|entry| {
+            let domain = entry.trim_left_matches('.');
+            entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+        })
+    }
+}
+
+/// Parse a proxy such as http://proxy:3128 or proxy:3128 into its host and port
+fn parse_proxy(value: &str) -> Option<(String, u16)> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    let url = if value.contains("://") {
+        Url::parse(value)
+    } else {
+        Url::parse(&format!("http://{}", value))
+    };
+    match url {
+        Ok(url) => match url.host_str() {
+            Some(host) => Some((host.to_string(), url.port_or_known_default().unwrap_or(80))),
+            None => None
+        },
+        Err(err) => {
+            println!("Invalid proxy {}: {}", value, err);
+            None
+        }
+    }
+}
+
+/// Add the hosts of a comma or space separated list, without their ports
+fn push_hosts(hosts: &mut Vec<String>, value: &str) {
+    for host in value.split(|c: char| c == ',' || c.is_whitespace()) {
+        let host = host.split(':').next().unwrap_or("").trim().to_lowercase();
+        if ! host.is_empty() {
+            hosts.push(host);
+        }
+    }
+}
+
+/// TLS for every connection. Certificates are checked against the built in roots and the extra
+/// bundle, except on hosts listed as self signed, which are trusted to keep presenting the
+/// certificate they first showed in this session.
+#[derive(Clone)]
+pub struct SiteTls {
+    tls: TlsClient,
+    self_signed: Arc<Vec<String>>,
+    /// The setup for each self signed host, trusting only its own certificate
+    pinned: Arc<Mutex<BTreeMap<String, TlsClient>>>,
+}
+
+impl SiteTls {
+    pub fn new(config: &NetworkConfig) -> SiteTls {
+        let mut tls = TlsClient::new();
+        if let Some(ref path) = config.ca_bundle {
+            let result = File::open(path).map_err(|err| format!("{}", err)).and_then(|file| match Arc::get_mut(&mut tls.cfg) {
+                Some(cfg) => cfg.root_store.add_pem_file(&mut BufReader::new(file)).map_err(|_| "not a PEM file".to_string()),
+                None => Err("the TLS setup is already in use".to_string())
+            });
+            match result {
+                Ok((added, _)) => println!("Trusting {} certificates from {}", added, path.display()),
+                Err(err) => println!("Failed to read certificates from {}: {}", path.display(), err)
+            }
+        }
+
+        SiteTls {
+            tls: tls,
+            self_signed: Arc::new(config.self_signed.clone()),
+            pinned: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Has a host been opted in to certificates that are not checked
+    pub fn accepts_self_signed(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.self_signed.iter().any(|self_signed| *self_signed == host)
+    }
+
+    /// The setup trusting the certificate of a self signed host, read from a separate
+    /// connection to the address the stream is connected to
+    fn pinned(&self, stream: &HttpStream, host: &str) -> Result<TlsClient, String> {
+        if let Some(tls) = self.pinned.lock().unwrap().get(host) {
+            return Ok(tls.clone());
+        }
+
+        let addr = stream.0.peer_addr().map_err(|err| format!("{}", err))?;
+        let certs = peer_certificates(addr, host, &self.tls.cfg)?;
+
+        let mut cfg = ClientConfig::new();
+        let mut added = 0;
+        for cert in certs.iter() {
+            if cfg.root_store.add(cert).is_ok() {
+                added += 1;
+            }
+        }
+        if added == 0 {
+            return Err("the certificate could not be read".to_string());
+        }
+
+        let _ = write!(stderr(), "* WARNING: trusting the certificate of {} without checking it, as it is listed in self_signed\n", host);
+        let tls = TlsClient {
+            cfg: Arc::new(cfg)
+        };
+        self.pinned.lock().unwrap().insert(host.to_string(), tls.clone());
+        Ok(tls)
+    }
+}
+
+impl SslClient for SiteTls {
+    type Stream = TlsStream;
+
+    fn wrap_client(&self, stream: HttpStream, host: &str) -> hyper::Result<TlsStream> {
+        if self.accepts_self_signed(host) {
+            match self.pinned(&stream, host) {
+                Ok(tls) => return tls.wrap_client(stream, host),
+                Err(err) => println!("Failed to get the certificate of {}: {}", host, err)
+            }
+        }
+        self.tls.wrap_client(stream, host)
+    }
+}
+
+/// Start a handshake with a server only to take the certificates it presents. Checking them is
+/// expected to fail, which does not matter as they are kept all the same.
+fn peer_certificates(addr: SocketAddr, host: &str, cfg: &Arc<ClientConfig>) -> Result<Vec<Certificate>, String> {
+    let mut stream = TcpStream::connect(addr).map_err(|err| format!("{}", err))?;
+    stream.set_read_timeout(Some(Duration::new(TIMEOUT, 0))).map_err(|err| format!("{}", err))?;
+    stream.set_write_timeout(Some(Duration::new(TIMEOUT, 0))).map_err(|err| format!("{}", err))?;
+
+    let mut session = ClientSession::new(cfg, host);
+    loop {
+        while session.wants_write() {
+            session.write_tls(&mut stream).map_err(|err| format!("{}", err))?;
+        }
+
+        if session.read_tls(&mut stream).map_err(|err| format!("{}", err))? == 0 {
+            return Err("the connection was closed during the handshake".to_string());
+        }
+        let processed = session.process_new_packets();
+
+        if let Some(certs) = session.get_peer_certificates() {
+            if ! certs.is_empty() {
+                return Ok(certs);
+            }
+        }
+        if let Err(err) = processed {
+            return Err(format!("{}", err));
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use orbclient::Color;
+use url::Url;
+
+use super::Block;
+
+/// Points per CSS pixel, at 96 pixels to the inch
+const POINTS_PER_PIXEL: f32 = 0.75;
+
+/// Space left blank around every page, in points
+const MARGIN: f32 = 36.0;
+
+/// Where the baseline of text is, as a part of the height of its block
+const BASELINE: f32 = 0.8;
+
+/// Advance widths of the printable ASCII characters in Helvetica, in thousandths of the font size
+const HELVETICA_WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278,
+    556, 556, 556, 556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556,
+    1015, 667, 667, 722, 722, 667, 611, 778, 722, 278, 500, 667, 556, 833, 722, 778,
+    667, 778, 722, 667, 611, 722, 667, 944, 667, 667, 611, 278, 278, 278, 469, 556,
+    333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500, 222, 833, 556, 556,
+    556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Paper {
+    A4,
+    Letter,
+}
+
+impl Paper {
+    pub fn parse(name: &str) -> Option<Paper> {
+        match name.to_lowercase().as_str() {
+            "a4" => Some(Paper::A4),
+            "letter" => Some(Paper::Letter),
+            _ => None
+        }
+    }
+
+    /// Width and height in points
+    fn size(&self) -> (f32, f32) {
+        match *self {
+            Paper::A4 => (595.28, 841.89),
+            Paper::Letter => (612.0, 792.0),
+        }
+    }
+
+    /// The width to lay a page out at so that it prints at its normal size
+    pub fn layout_width(&self) -> i32 {
+        ((self.size().0 - 2.0 * MARGIN) / POINTS_PER_PIXEL) as i32
+    }
+}
+
+/// A name to save the PDF of a page under, made from its title
+pub fn pdf_filename(title: &str) -> String {
+    let name: String = title.chars().map(|c| if c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' { c } else { '_' }).collect();
+    let name = name.trim();
+    if name.is_empty() {
+        "page.pdf".to_string()
+    } else {
+        format!("{}.pdf", name.chars().take(100).collect::<String>())
+    }
+}
+
+/// Write blocks laid out at a width as a PDF, shrinking them to fit the paper if needed and
+/// breaking pages between lines of text. Text is drawn in Helvetica so that it can be searched
+/// and copied, and links become link annotations, going to their place in the document for
+/// anchors on the page.
+pub fn encode_pdf(blocks: &[Block], anchors: &BTreeMap<String, i32>, width: i32, url: &Url, title: &str, paper: Paper) -> Vec<u8> {
+    let (paper_w, paper_h) = paper.size();
+    let (print_w, print_h) = (paper_w - 2.0 * MARGIN, paper_h - 2.0 * MARGIN);
+    let scale = POINTS_PER_PIXEL.min(print_w / cmp::max(1, width) as f32);
+
+    let breaks = page_breaks(blocks, (print_h / scale) as i32);
+    let page_of = |y: i32| breaks.iter().rposition(|&top| top <= y).unwrap_or(0);
+
+    // The catalog, page tree, font and document information come first, then the images, then
+    // each page and its contents
+    let mut images = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        if block.image.is_some() {
+            images.push(i);
+        }
+    }
+    let image_id = |i: usize| 5 + images.iter().position(|&image| image == i).unwrap_or(0);
+    let page_id = |page: usize| 5 + images.len() + 2 * page;
+    let pages = breaks.len() - 1;
+
+    let mut pdf = PdfWriter::new(page_id(pages) - 1);
+    pdf.object(1, "<< /Type /Catalog /Pages 2 0 R >>");
+
+    let kids: Vec<String> = (0..pages).map(|page| format!("{} 0 R", page_id(page))).collect();
+    pdf.object(2, &format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages));
+    pdf.object(3, "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>");
+    pdf.object(4, &format!("<< /Title {} /Subject {} /Creator (Browser) >>", pdf_string(title), pdf_string(url.as_str())));
+
+    let mut xobjects = String::new();
+    for &i in images.iter() {
+        if let Some(ref image) = blocks[i].image {
+            let mut data = Vec::with_capacity(image.data().len() * 3);
+            for color in image.data().iter() {
+                // Transparent parts are blended with white paper
+                let alpha = color.data >> 24;
+                for shift in [16, 8, 0].iter() {
+                    let channel = (color.data >> *shift) & 0xFF;
+                    data.push(((channel * alpha + 255 * (255 - alpha)) / 255) as u8);
+                }
+            }
+            pdf.stream(image_id(i), &format!(
+                "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceRGB /BitsPerComponent 8",
+                image.width(), image.height()
+            ), &data);
+            xobjects.push_str(&format!(" /Im{} {} 0 R", i, image_id(i)));
+        }
+    }
+
+    for page in 0..pages {
+        let (top, bottom) = (breaks[page], breaks[page + 1]);
+        // Points for a place on the page, with y going up from the bottom of the paper
+        let point = |x: i32, y: i32| (MARGIN + x as f32 * scale, paper_h - MARGIN - (y - top) as f32 * scale);
+
+        let mut content = format!("{:.2} {:.2} {:.2} {:.2} re W n\n", MARGIN, MARGIN, print_w, print_h);
+        let mut annots = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            if block.y >= bottom || block.y + block.h <= top {
+                continue;
+            }
+
+            let (x0, y1) = point(block.x, block.y);
+            let (x1, y0) = point(block.x + block.w, block.y + block.h);
+
+            if let Some(background) = block.background {
+                fill(&mut content, background, x0, y0, x1 - x0, y1 - y0);
+            }
+
+            if let Some((border, color)) = block.border {
+                let border = cmp::min(border, cmp::min(block.w, block.h) / 2) as f32 * scale;
+                if border > 0.0 {
+                    fill(&mut content, color, x0, y1 - border, x1 - x0, border);
+                    fill(&mut content, color, x0, y0, x1 - x0, border);
+                    fill(&mut content, color, x0, y0, border, y1 - y0);
+                    fill(&mut content, color, x1 - border, y0, border, y1 - y0);
+                }
+            }
+
+            if let Some(ref image) = block.image {
+                let (w, h) = (image.width() as f32 * scale, image.height() as f32 * scale);
+                content.push_str(&format!("q {:.2} 0 0 {:.2} {:.2} {:.2} cm /Im{} Do Q\n", w, h, x0, y1 - h, i));
+            }
+
+            if block.text.is_some() && ! block.string.trim().is_empty() {
+                text(&mut content, block, scale, x0, y1);
+            }
+
+            if let Some(ref link) = block.link {
+                let action = if link.starts_with('#') {
+                    anchors.get(&link[1..]).map(|&y| {
+                        let page = page_of(y);
+                        let dest_y = paper_h - MARGIN - (y - breaks[page]) as f32 * scale;
+                        format!("/Dest [{} 0 R /XYZ null {:.2} null]", page_id(page), dest_y)
+                    })
+                } else {
+                    match url.join(link) {
+                        Ok(ref link_url) if link_url.scheme() != "javascript" => Some(format!("/A << /S /URI /URI {} >>", pdf_string(link_url.as_str()))),
+                        _ => None
+                    }
+                };
+                if let Some(action) = action {
+                    annots.push(format!(
+                        "<< /Type /Annot /Subtype /Link /Rect [{:.2} {:.2} {:.2} {:.2}] /Border [0 0 0] {} >>",
+                        x0, y0.max(MARGIN), x1, y1.min(paper_h - MARGIN), action
+                    ));
+                }
+            }
+        }
+
+        pdf.object(page_id(page), &format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /Font << /F1 3 0 R >> /XObject <<{} >> >> /Contents {} 0 R /Annots [{}] >>",
+            paper_w, paper_h, xobjects, page_id(page) + 1, annots.join(" ")
+        ));
+        pdf.stream(page_id(page) + 1, "", content.as_bytes());
+    }
+
+    pdf.finish(4)
+}
+
+/// The top of every page in pixels, followed by the bottom of the last one. A page ends above
+/// any line of text that would be cut in two, unless the line is taller than a page.
+fn page_breaks(blocks: &[Block], page_h: i32) -> Vec<i32> {
+    let page_h = cmp::max(1, page_h);
+    let mut height = 1;
+    for block in blocks.iter() {
+        height = cmp::max(height, block.y + block.h);
+    }
+
+    let mut breaks = vec![0];
+    let mut top = 0;
+    while top < height {
+        let mut bottom = top + page_h;
+        loop {
+            let mut moved = false;
+            for block in blocks.iter() {
+                if block.text.is_some() && block.y > top && block.y < bottom && block.y + block.h > bottom {
+                    bottom = block.y;
+                    moved = true;
+                }
+            }
+            if ! moved {
+                break;
+            }
+        }
+
+        breaks.push(bottom);
+        top = bottom;
+    }
+    breaks
+}
+
+fn fill(content: &mut String, color: Color, x: f32, y: f32, w: f32, h: f32) {
+    if color.data >> 24 == 0 {
+        return;
+    }
+    content.push_str(&format!("{} {:.2} {:.2} {:.2} {:.2} re f\n", rgb(color), x, y, w, h));
+}
+
+/// Fill colour operands and operator for a colour
+fn rgb(color: Color) -> String {
+    format!(
+        "{:.3} {:.3} {:.3} rg",
+        ((color.data >> 16) & 0xFF) as f32 / 255.0, ((color.data >> 8) & 0xFF) as f32 / 255.0, (color.data & 0xFF) as f32 / 255.0
+    )
+}
+
+/// Draw the text of a block, stretched so that it takes up the same width as on screen
+fn text(content: &mut String, block: &Block, scale: f32, x: f32, top: f32) {
+    let size = block.h as f32 * scale;
+    let mut natural = 0.0;
+    let mut string = String::new();
+    for c in block.string.chars() {
+        let (byte, width) = win_ansi(c);
+        natural += width as f32 * size / 1000.0;
+        match byte {
+            b'(' | b')' | b'\\' => {
+                string.push('\\');
+                string.push(byte as char);
+            },
+            32 ... 126 => string.push(byte as char),
+            _ => string.push_str(&format!("\\{:03o}", byte))
+        }
+    }
+    if natural <= 0.0 {
+        return;
+    }
+
+    let stretch = (100.0 * block.w as f32 * scale / natural).max(25.0).min(400.0);
+    content.push_str(&format!(
+        "BT /F1 {:.2} Tf {:.1} Tz {} {:.2} {:.2} Td ({}) Tj ET\n",
+        size, stretch, rgb(block.color), x, top - size * BASELINE, string
+    ));
+}
+
+/// The byte for a character in the WinAnsi encoding of the standard fonts, and its width
+fn win_ansi(c: char) -> (u8, u16) {
+    let code = c as u32;
+    if code >= 32 && code < 127 {
+        return (code as u8, HELVETICA_WIDTHS[(code - 32) as usize]);
+    }
+
+    match c {
+        '\u{a0}' => (32, 278),
+        '\u{2022}' => (0x95, 350),
+        '\u{2013}' => (0x96, 556),
+        '\u{2014}' => (0x97, 1000),
+        '\u{2018}' => (0x91, 222),
+        '\u{2019}' => (0x92, 222),
+        '\u{201c}' => (0x93, 333),
+        '\u{201d}' => (0x94, 333),
+        '\u{2026}' => (0x85, 1000),
+        '\u{20ac}' => (0x80, 556),
+        // Latin-1 letters and signs have the same codes
+        _ if code > 160 && code < 256 => (code as u8, 556),
+        _ => (b'?', 556)
+    }
+}
+
+/// A PDF string literal, with characters outside of ASCII left out
+fn pdf_string(s: &str) -> String {
+    let mut string = String::from("(");
+    for c in s.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                string.push('\\');
+                string.push(c);
+            },
+            ' ' ... '~' => string.push(c),
+            _ => ()
+        }
+    }
+    string.push(')');
+    string
+}
+
+/// Writes numbered objects, keeping where each starts for the cross reference table
+struct PdfWriter {
+    data: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl PdfWriter {
+    /// Start a document with objects numbered from 1 up to count
+    fn new(count: usize) -> PdfWriter {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n");
+        PdfWriter {
+            data: data,
+            offsets: vec![0; count + 1],
+        }
+    }
+
+    fn object(&mut self, id: usize, body: &str) {
+        self.offsets[id] = self.data.len();
+        self.data.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", id, body).as_bytes());
+    }
+
+    /// Write a stream object, with entries for its dictionary besides the length
+    fn stream(&mut self, id: usize, entries: &str, data: &[u8]) {
+        self.offsets[id] = self.data.len();
+        self.data.extend_from_slice(format!("{} 0 obj\n<< {} /Length {} >>\nstream\n", id, entries, data.len()).as_bytes());
+        self.data.extend_from_slice(data);
+        self.data.extend_from_slice(b"\nendstream\nendobj\n");
+    }
+
+    /// Write the cross reference table and trailer, with the catalog as object 1
+    fn finish(mut self, info: usize) -> Vec<u8> {
+        let xref = self.data.len();
+        self.data.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len()).as_bytes());
+        for offset in self.offsets[1..].iter() {
+            self.data.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        self.data.extend_from_slice(format!(
+            "trailer\n<< /Size {} /Root 1 0 R /Info {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len(), info, xref
+        ).as_bytes());
+        self.data
+    }
+}
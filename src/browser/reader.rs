@@ -0,0 +1,299 @@
+use std::default::Default;
+use std::str;
+
+use html5ever::parse_document;
+use html5ever::rcdom::{Element, Handle, RcDom, Text};
+use tendril::TendrilSink;
+use url::Url;
+
+use about::{error_page, escape_html};
+use decode::detect_charset;
+use session::Session;
+use super::url_download;
+
+/// Paragraphs shorter than this many characters do not count towards an article
+const MIN_PARAGRAPH: usize = 25;
+
+/// Styles of the reader view, a single narrow column of plain text
+const READER_CSS: &'static str = "
+body { max-width: 40em; margin: 0 auto; padding: 1em 2em; font-size: 18px; color: #222222; background-color: #fbfbf8 }
+h1 { font-size: 28px }
+pre { background-color: #eeeeee; padding: 0.5em }
+.source { font-size: 14px; color: #666666 }
+";
+
+/// Elements that are never part of an article
+const SKIPPED: [&'static str; 15] = [
+    "aside", "button", "footer", "form", "header", "iframe", "input", "nav", "noscript", "script",
+    "select", "style", "svg", "template", "textarea",
+];
+
+/// Elements kept in the reader view, without their attributes apart from links and image sources
+const KEPT: [&'static str; 41] = [
+    "a", "abbr", "b", "blockquote", "br", "caption", "cite", "code", "dd", "del", "dl", "dt", "em",
+    "figcaption", "figure", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i", "img", "ins", "kbd", "li",
+    "ol", "p", "pre", "q", "s", "small", "strong", "sub", "sup", "table", "td", "th", "tr", "ul",
+];
+
+/// Containers kept as plain blocks, so that their text stays apart from what is around it
+const BLOCKS: [&'static str; 5] = ["article", "div", "main", "section", "tbody"];
+
+/// Words in a class or id that make an element more or less likely to hold the article
+const POSITIVE: [&'static str; 8] = ["article", "body", "content", "entry", "main", "post", "story", "text"];
+const NEGATIVE: [&'static str; 10] = ["ad-", "comment", "footer", "menu", "nav", "promo", "related", "share", "sidebar", "social"];
+
+/// The reader view of a page, given as about:reader?url=...
+pub fn reader_url(url: &Url) -> Url {
+    let mut reader = Url::parse("about:reader").unwrap();
+    reader.query_pairs_mut().append_pair("url", url.as_str());
+    reader
+}
+
+/// The page a reader view is of
+pub fn reader_source(url: &Url) -> Option<Url> {
+    if url.scheme() != "about" || url.path() != "reader" {
+        return None;
+    }
+
+    for (name, value) in url.query_pairs() {
+        if name == "url" {
+            return Url::parse(&value).ok();
+        }
+    }
+    None
+}
+
+/// Download a page and build the HTML of its main article alone, found by where most of the
+/// text in paragraphs is
+pub fn reader_page(url: &Url, session: &Session) -> String {
+    let source = match reader_source(url) {
+        Some(source) => source,
+        None => return error_page("Reader view failed", "No page was given to show in the reader view", url)
+    };
+
+    let (headers, data) = match url_download(&source, session) {
+        Ok(download) => download,
+        Err(err) => return error_page("Reader view failed", &err, &source)
+    };
+    let content_type = headers.get_raw("content-type").and_then(|x| str::from_utf8(x[0].as_slice()).ok()).unwrap_or("text/html").to_string();
+    let text = detect_charset(&content_type, &data, true).decode(&data);
+    let dom = parse_document(RcDom::default(), Default::default()).one(text);
+
+    let title = find_title(&dom.document).unwrap_or_else(|| source.to_string());
+    let mut best = None;
+    find_article(&dom.document, &mut best);
+    let article = match best.map(|(_, article)| article).or_else(|| find_element(&dom.document, "body")) {
+        Some(article) => article,
+        None => return error_page("Reader view failed", "The page has no text to show", &source)
+    };
+
+    let mut content = String::new();
+    clean(&article, &source, &mut content);
+
+    let mut html = format!(
+        "<html><head><title>{}</title><style>{}</style></head><body>\n<p class=\"source\"><a href=\"{}\">{}</a></p>\n",
+        escape_html(&title), READER_CSS, escape_html(source.as_str()), escape_html(source.host_str().unwrap_or(source.as_str()))
+    );
+    // Articles usually start with their own heading
+    if find_element(&article, "h1").is_none() {
+        html.push_str(&format!("<h1>{}</h1>\n", escape_html(&title)));
+    }
+    html.push_str(&content);
+    html.push_str("\n</body></html>\n");
+    html
+}
+
+fn tag_name(handle: &Handle) -> Option<String> {
+    match handle.borrow().node {
+        Element(ref name, _, _) => Some(name.local.to_string()),
+        _ => None
+    }
+}
+
+fn attribute(handle: &Handle, name: &str) -> Option<String> {
+    if let Element(_, _, ref attrs) = handle.borrow().node {
+        for attr in attrs.iter() {
+            if &*attr.name.local == name {
+                return Some(attr.value.to_string());
+            }
+        }
+    }
+    None
+}
+
+/// The first element with a tag
+fn find_element(handle: &Handle, tag: &str) -> Option<Handle> {
+    if tag_name(handle).map_or(false, |name| name == tag) {
+        return Some(handle.clone());
+    }
+    for child in handle.borrow().children.iter() {
+        if let Some(found) = find_element(child, tag) {
+            return Some(found);
+        }
+    }
+    None
+}
+
+fn find_title(handle: &Handle) -> Option<String> {
+    let mut text = String::new();
+    if let Some(title) = find_element(handle, "title") {
+        collect_text(&title, &mut text);
+    }
+
+    let title = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if title.is_empty() { None } else { Some(title) }
+}
+
+fn collect_text(handle: &Handle, string: &mut String) {
+    if let Text(ref text) = handle.borrow().node {
+        string.push_str(text);
+    }
+    for child in handle.borrow().children.iter() {
+        collect_text(child, string);
+    }
+}
+
+/// The number of characters of text inside an element, and how many of them are in links
+fn text_length(handle: &Handle, in_link: bool) -> (usize, usize) {
+    let name = tag_name(handle);
+    if name.as_ref().map_or(false, |name| SKIPPED.contains(&name.as_str())) {
+        return (0, 0);
+    }
+
+    let in_link = in_link || name.as_ref().map_or(false, |name| name == "a");
+    let mut length = 0;
+    if let Text(ref text) = handle.borrow().node {
+        length = text.split_whitespace().map(|word| word.chars().count() + 1).sum();
+    }
+    let mut link_length = if in_link { length } else { 0 };
+
+    for child in handle.borrow().children.iter() {
+        let (child_length, child_link_length) = text_length(child, in_link);
+        length += child_length;
+        link_length += child_link_length;
+    }
+    (length, link_length)
+}
+
+/// How much a paragraph adds to the element holding it, for its length and commas
+fn paragraph_score(handle: &Handle) -> f32 {
+    match tag_name(handle) {
+        Some(ref name) if name == "p" || name == "pre" || name == "td" => (),
+        _ => return 0.0
+    }
+
+    let (length, _) = text_length(handle, false);
+    if length < MIN_PARAGRAPH {
+        return 0.0;
+    }
+
+    let mut text = String::new();
+    collect_text(handle, &mut text);
+    let commas = text.matches(',').count();
+    1.0 + commas as f32 + (length as f32 / 100.0).min(3.0)
+}
+
+/// Score every element by the paragraphs among its children, and half those among its
+/// grandchildren, keeping the best one
+fn find_article(handle: &Handle, best: &mut Option<(f32, Handle)>) {
+    let name = match tag_name(handle) {
+        Some(name) => name,
+        None => {
+            for child in handle.borrow().children.iter() {
+                find_article(child, best);
+            }
+            return;
+        }
+    };
+    if SKIPPED.contains(&name.as_str()) {
+        return;
+    }
+
+    let mut score = 0.0;
+    for child in handle.borrow().children.iter() {
+        score += paragraph_score(child);
+        for grandchild in child.borrow().children.iter() {
+            score += paragraph_score(grandchild) / 2.0;
+        }
+    }
+
+    if score > 0.0 {
+        let hints = format!("{} {}", attribute(handle, "class").unwrap_or(String::new()), attribute(handle, "id").unwrap_or(String::new())).to_lowercase();
+        if name == "article" || name == "main" || POSITIVE.iter().any(|word| hints.contains(word)) {
+            score += 25.0;
+        }
+        if NEGATIVE.iter().any(|word| hints.contains(word)) {
+            score -= 25.0;
+        }
+
+        // Lists of links such as menus have little text of their own
+        let (length, link_length) = text_length(handle, false);
+        if length > 0 {
+            score *= 1.0 - link_length as f32 / length as f32;
+        }
+
+        if best.as_ref().map_or(true, |&(best_score, _)| score > best_score) {
+            *best = Some((score, handle.clone()));
+        }
+    }
+
+    for child in handle.borrow().children.iter() {
+        find_article(child, best);
+    }
+}
+
+/// Write an element back out as HTML with only the elements and attributes the reader view
+/// keeps, making links and image sources absolute
+fn clean(handle: &Handle, base: &Url, html: &mut String) {
+    let node = handle.borrow();
+    let (name, attrs) = match node.node {
+        Text(ref text) => {
+            html.push_str(&escape_html(text));
+            return;
+        },
+        Element(ref name, _, ref attrs) => (name.local.to_string(), attrs),
+        _ => return
+    };
+
+    if SKIPPED.contains(&name.as_str()) {
+        return;
+    }
+
+    let tag = if KEPT.contains(&name.as_str()) {
+        Some(name.as_str())
+    } else if BLOCKS.contains(&name.as_str()) {
+        Some("div")
+    } else {
+        None
+    };
+
+    if let Some(tag) = tag {
+        html.push('<');
+        html.push_str(tag);
+        for attr in attrs.iter() {
+            let value = match (tag, &*attr.name.local) {
+                ("a", "href") | ("img", "src") => match base.join(&attr.value) {
+                    Ok(url) => url.into_string(),
+                    Err(_) => continue
+                },
+                ("img", "alt") | ("td", "colspan") | ("td", "rowspan") | ("th", "colspan") | ("th", "rowspan") | ("ol", "start") => attr.value.to_string(),
+                _ => continue
+            };
+            html.push_str(&format!(" {}=\"{}\"", &*attr.name.local, escape_html(&value)));
+        }
+        html.push('>');
+    }
+
+    match tag {
+        Some("br") | Some("hr") | Some("img") => return,
+        _ => ()
+    }
+
+    for child in node.children.iter() {
+        clean(child, base, html);
+    }
+
+    if let Some(tag) = tag {
+        html.push_str(&format!("</{}>", tag));
+    }
+}
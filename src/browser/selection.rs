@@ -0,0 +1,61 @@
+use super::Block;
+
+/// Is a block a word of the page text, rather than an image, form control or box
+pub fn is_text(block: &Block) -> bool {
+    block.text.is_some() && block.control.is_none() && ! block.string.is_empty()
+}
+
+/// What comes between two words of text: nothing when they touch, a space when there is a gap
+/// and a line break when the second starts a new line
+pub fn separator(prev: &Block, block: &Block) -> Option<char> {
+    if block.x <= prev.x || block.y >= prev.y + prev.h {
+        Some('\n')
+    } else if block.x > prev.x + prev.w {
+        Some(' ')
+    } else {
+        None
+    }
+}
+
+/// Find the word at a point on the page, or else the last word before it in reading order
+pub fn text_block_at(blocks: &[Block], x: i32, y: i32) -> Option<usize> {
+    let mut first = None;
+    let mut before = None;
+    for (i, block) in blocks.iter().enumerate() {
+        if ! is_text(block) {
+            continue;
+        }
+
+        if x >= block.x && x < block.x + block.w && y >= block.y && y < block.y + block.h {
+            return Some(i);
+        }
+
+        if first.is_none() {
+            first = Some(i);
+        }
+        if block.y + block.h <= y || (block.y <= y && block.x + block.w <= x) {
+            before = Some(i);
+        }
+    }
+    before.or(first)
+}
+
+/// The text of the words from start to end inclusive
+pub fn blocks_text(blocks: &[Block], start: usize, end: usize) -> String {
+    let mut text = String::new();
+    let mut prev: Option<&Block> = None;
+    for block in blocks[start..end + 1].iter() {
+        if ! is_text(block) {
+            continue;
+        }
+
+        if let Some(prev) = prev {
+            if let Some(c) = separator(prev, block) {
+                text.push(c);
+            }
+        }
+        text.push_str(&block.string);
+        prev = Some(block);
+    }
+    text
+}
@@ -0,0 +1,111 @@
+use std::net::TcpStream;
+use std::time::Duration;
+
+use hyper::Client;
+use hyper::client::{ProxyConfig, RedirectPolicy};
+use hyper::net::{HttpConnector, HttpStream, HttpsConnector, SslClient};
+use hyper_rustls::TlsStream;
+use url::Url;
+
+use bookmark::Bookmarks;
+use cache::Cache;
+use cookie::CookieJar;
+use download::Downloads;
+use error::LoadError;
+use history::History;
+use network::{NetworkConfig, SiteTls};
+use theme::Theme;
+use zoom::Zoom;
+
+/// Seconds to wait for a server before giving up
+pub const TIMEOUT: u64 = 5;
+
+/// The network state shared by every tab and loader thread
+pub struct Session {
+    /// One client for direct requests and one for each proxy, so that connections are reused
+    client: Client,
+    http_proxy: Option<Client>,
+    https_proxy: Option<Client>,
+    network: NetworkConfig,
+    /// The TLS setup for HTTPS and other protocols alike
+    tls: SiteTls,
+    pub cache: Cache,
+    pub cookies: CookieJar,
+    pub bookmarks: Bookmarks,
+    pub history: History,
+    pub downloads: Downloads,
+    pub zoom: Zoom,
+    pub theme: Theme,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        let network = NetworkConfig::load();
+        let tls = SiteTls::new(&network);
+
+        // HTTPS through a proxy is tunnelled with CONNECT, so certificates are still checked here
+        let proxy_client = |proxy: &Option<(String, u16)>| proxy.as_ref().map(|&(ref host, port)| {
+            println!("Using proxy {}:{}", host, port);
+            configure(Client::with_proxy_config(ProxyConfig::new("http", host.clone(), port, HttpConnector, tls.clone())))
+        });
+        let http_proxy = proxy_client(&network.http_proxy);
+        let https_proxy = proxy_client(&network.https_proxy);
+
+        Session {
+            client: configure(Client::with_connector(HttpsConnector::new(tls.clone()))),
+            http_proxy: http_proxy,
+            https_proxy: https_proxy,
+            network: network,
+            tls: tls,
+            cache: Cache::new(),
+            cookies: CookieJar::new(),
+            bookmarks: Bookmarks::new(),
+            history: History::new(),
+            downloads: Downloads::new(),
+            zoom: Zoom::new(),
+            theme: Theme::new(),
+        }
+    }
+
+    /// The client to request a URL with, which goes through the proxy for its scheme unless
+    /// the host is listed in no_proxy
+    pub fn client(&self, url: &Url) -> &Client {
+        let proxy = match url.scheme() {
+            "http" => self.http_proxy.as_ref(),
+            "https" => self.https_proxy.as_ref(),
+            _ => None
+        };
+        match proxy {
+            Some(client) if ! self.network.bypasses_proxy(url.host_str().unwrap_or("")) => client,
+            _ => &self.client
+        }
+    }
+
+    /// Is the certificate of a URL's host accepted without being checked
+    pub fn accepts_self_signed(&self, url: &Url) -> bool {
+        url.scheme() != "http" && self.tls.accepts_self_signed(url.host_str().unwrap_or(""))
+    }
+
+    /// Open a connection to the host of a URL, for protocols hyper does not speak
+    pub fn connect(&self, url: &Url, default_port: u16) -> Result<TcpStream, LoadError> {
+        let host = url.host_str().unwrap_or("");
+        let stream = TcpStream::connect((host, url.port().unwrap_or(default_port))).map_err(|err| LoadError::from_io(err, host.to_string()))?;
+        stream.set_read_timeout(Some(Duration::new(TIMEOUT, 0))).map_err(|err| LoadError::from_io(err, host.to_string()))?;
+        stream.set_write_timeout(Some(Duration::new(TIMEOUT, 0))).map_err(|err| LoadError::from_io(err, host.to_string()))?;
+        Ok(stream)
+    }
+
+    /// Open a TLS connection to the host of a URL, checking its certificate as for HTTPS
+    pub fn connect_tls(&self, url: &Url, default_port: u16) -> Result<TlsStream, LoadError> {
+        let stream = self.connect(url, default_port)?;
+        self.tls.wrap_client(HttpStream(stream), url.host_str().unwrap_or("")).map_err(|err| LoadError::Tls(format!("{}", err)))
+    }
+}
+
+fn configure(mut client: Client) -> Client {
+    client.set_read_timeout(Some(Duration::new(TIMEOUT, 0)));
+    client.set_write_timeout(Some(Duration::new(TIMEOUT, 0)));
+    // Redirects are followed by hand, so each hop goes through the cookie jar and cache
+    client.set_redirect_policy(RedirectPolicy::FollowNone);
+    client
+}
@@ -0,0 +1,398 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use orbclient::{Color, Renderer, Window};
+use orbfont::Font;
+use url::Url;
+
+use fonts::{fit_start, Fonts};
+use hint::{targets, Target};
+use session::Session;
+use layout::{self, LayoutBox, Page};
+use loader::{LoadEvent, Loader};
+use selection::blocks_text;
+use super::Block;
+
+/// Height of the tab strip above the toolbar
+pub const TAB_HEIGHT: i32 = 24;
+
+const TAB_MAX_WIDTH: i32 = 200;
+const CLOSE_WIDTH: i32 = 16;
+const NEW_WIDTH: i32 = 24;
+const FONT_SIZE: f32 = 13.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TabAction {
+    Select(usize),
+    Close(usize),
+    New,
+}
+
+/// A page with its own navigation history and scroll position
+pub struct Tab<'a> {
+    pub url: Url,
+    /// The pages before and after this one, each with the scroll offset it was left at
+    pub history: Vec<(Url, (i32, i32))>,
+    pub forward: Vec<(Url, (i32, i32))>,
+    pub page: Page,
+    /// The form data to post with the next load
+    pub post_body: Option<Vec<u8>>,
+    /// The form control with keyboard focus
+    pub focus: Option<usize>,
+    /// The links and form controls keyboard focus moves through
+    pub targets: Vec<Target>,
+    /// The target with keyboard focus when it is a link
+    pub link_focus: Option<usize>,
+    pub anchors: BTreeMap<String, i32>,
+    pub blocks: Vec<Block<'a>>,
+    /// The blocks where a mouse selection started and ended
+    pub selection: Option<(usize, usize)>,
+    pub offset: (i32, i32),
+    pub max_offset: (i32, i32),
+    /// The scroll offset to go back to as the page loads, when it came from the history
+    restore_offset: Option<(i32, i32)>,
+    pub reload: bool,
+    pub relayout: bool,
+    /// Has the loader not sent a page yet
+    first_page: bool,
+    /// The load in progress, if any
+    loader: Option<Loader>,
+    /// The width of the tab strip a title was last cut down to fit, the title and what fit
+    title_fit: Option<(i32, String, String)>,
+}
+
+impl<'a> Tab<'a> {
+    pub fn new(url: Url) -> Tab<'a> {
+        Tab {
+            url: url,
+            history: Vec::new(),
+            forward: Vec::new(),
+            page: Page::new(LayoutBox::message("Loading...")),
+            post_body: None,
+            focus: None,
+            targets: Vec::new(),
+            link_focus: None,
+            anchors: BTreeMap::new(),
+            blocks: Vec::new(),
+            selection: None,
+            offset: (0, 0),
+            max_offset: (0, 0),
+            restore_offset: None,
+            reload: true,
+            relayout: false,
+            first_page: false,
+            loader: None,
+            title_fit: None,
+        }
+    }
+
+    /// The page title, or the URL for pages without one
+    pub fn title(&self) -> String {
+        match self.page.title {
+            Some(ref title) if ! title.is_empty() => title.clone(),
+            _ => self.url.to_string()
+        }
+    }
+
+    /// Go to a new URL, posting body to it if given
+    pub fn navigate(&mut self, url: Url, body: Option<Vec<u8>>) {
+        self.history.push((self.url.clone(), self.offset));
+        self.forward.clear();
+        self.url = url;
+        self.post_body = body;
+        self.restore_offset = None;
+        self.reload = true;
+    }
+
+    pub fn back(&mut self) {
+        if let Some((url, offset)) = self.history.pop() {
+            self.forward.push((self.url.clone(), self.offset));
+            self.url = url;
+            self.restore_offset = Some(offset);
+            self.reload = true;
+        }
+    }
+
+    pub fn forward(&mut self) {
+        if let Some((url, offset)) = self.forward.pop() {
+            self.history.push((self.url.clone(), self.offset));
+            self.url = url;
+            self.restore_offset = Some(offset);
+            self.reload = true;
+        }
+    }
+
+    /// Start fetching the page on a worker thread, replacing any load in progress
+    pub fn load(&mut self, session: &Arc<Session>) {
+        self.reload = false;
+
+        self.loader = Some(Loader::new(self.url.clone(), self.post_body.take(), session.clone()));
+        self.page = Page::new(LayoutBox::message("Loading..."));
+        self.first_page = true;
+
+        self.focus = None;
+        self.link_focus = None;
+        self.offset = (0, 0);
+        self.relayout = true;
+    }
+
+    pub fn loading(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    /// Cancel the load in progress, keeping whatever has arrived so far
+    pub fn stop(&mut self) {
+        self.reload = false;
+        self.restore_offset = None;
+        if let Some(loader) = self.loader.take() {
+            loader.cancel();
+        }
+    }
+
+    /// Take in everything the loader has produced, returning true if the page changed
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            let event = match self.loader {
+                Some(ref loader) => loader.poll(),
+                None => None
+            };
+
+            match event {
+                Some(LoadEvent::Page(page)) => {
+                    // Later versions of the same page keep the scroll position and focus
+                    if self.first_page {
+                        self.first_page = false;
+                        self.focus = None;
+                        self.link_focus = None;
+                        self.offset = (0, 0);
+                    }
+                    // Pages from the history go back to where they were scrolled to, again for each
+                    // version since layout clamps the offset to pages still loading
+                    if let Some(offset) = self.restore_offset {
+                        self.offset = offset;
+                    }
+                    if let Some(ref url) = page.url {
+                        self.url = url.clone();
+                    }
+                    self.page = page;
+                    changed = true;
+                },
+                Some(LoadEvent::Image(i, result)) => if let Some(page_image) = self.page.images.get_mut(i) {
+                    match result {
+                        Ok(image) => {
+                            page_image.image = Some(image);
+                            *page_image.zoomed.borrow_mut() = None;
+                        },
+                        Err(err) => {
+                            println!("Failed to load image {}: {}", page_image.url, err);
+                            page_image.failed = true;
+                        }
+                    }
+                    changed = true;
+                },
+                Some(LoadEvent::Done) => {
+                    self.loader = None;
+                    self.restore_offset = None;
+                    changed = true;
+                },
+                None => break
+            }
+        }
+
+        if changed {
+            self.relayout = true;
+        }
+        changed
+    }
+
+    /// Lay the page out again at a new width and zoom level, keeping the scroll offset inside
+    /// the page
+    pub fn layout(&mut self, width: i32, height: i32, zoom: f32, fonts: &'a Fonts) {
+        self.relayout = false;
+
+        self.anchors.clear();
+        self.blocks.clear();
+        self.selection = None;
+        layout::layout(&self.page, self.focus, width, zoom, fonts, &mut self.anchors, &mut self.blocks);
+
+        self.targets = targets(&self.blocks);
+        if let Some(i) = self.link_focus {
+            if self.targets.get(i).map_or(true, |target| target.link.is_none()) {
+                self.link_focus = None;
+            }
+        }
+
+        self.max_offset = (0, 0);
+        for block in self.blocks.iter() {
+            if block.x + block.w > self.max_offset.0 {
+                self.max_offset.0 = block.x + block.w;
+            }
+            if block.y + block.h > self.max_offset.1 {
+                self.max_offset.1 = block.y + block.h;
+            }
+        }
+
+        self.scroll(0, 0, width, height);
+    }
+
+    /// The link with keyboard focus
+    pub fn focused_link(&self) -> Option<String> {
+        self.link_focus.and_then(|i| self.targets.get(i)).and_then(|target| target.link.clone())
+    }
+
+    /// Move keyboard focus to the next or previous link or form control, scrolling it into view
+    pub fn step_focus(&mut self, forward: bool, width: i32, height: i32) {
+        let len = self.targets.len();
+        if len == 0 {
+            return;
+        }
+
+        let current = match (self.link_focus, self.focus) {
+            (Some(i), _) => Some(i),
+            (None, Some(control)) => self.targets.iter().position(|target| target.control == Some(control)),
+            (None, None) => None
+        };
+        let next = match current {
+            Some(i) => if forward { (i + 1) % len } else { (i + len - 1) % len },
+            None => if forward { 0 } else { len - 1 }
+        };
+
+        let control = self.targets[next].control;
+        if control.is_some() {
+            self.link_focus = None;
+        } else {
+            self.link_focus = Some(next);
+        }
+        // Controls draw their own focus, so they are laid out again when it moves
+        if self.focus != control {
+            self.focus = control;
+            self.relayout = true;
+        }
+
+        let first = self.targets[next].first;
+        let block_y = self.blocks[first].y;
+        if block_y < self.offset.1 || block_y + self.blocks[first].h > self.offset.1 + height {
+            self.scroll_to_block(first, width, height);
+        }
+    }
+
+    /// Follow a link on the page, scrolling to it if it is an anchor on the same page
+    pub fn follow(&mut self, link: &str) {
+        if link.starts_with('#') {
+            if let Some(anchor) = self.anchors.get(&link[1..]) {
+                println!("Anchor {}: {}", link, *anchor);
+                self.offset.0 = 0;
+                self.offset.1 = *anchor;
+            } else {
+                println!("Anchor {} not found", link);
+            }
+        } else {
+            match self.url.join(link) {
+                Ok(link_url) => {
+                    println!("Navigate {}: {:#?}", link, link_url);
+                    self.navigate(link_url, None);
+                },
+                Err(err) => println!("Invalid link {}: {}", link, err)
+            }
+        }
+    }
+
+    /// The first and last selected block, in layout order
+    fn selected_range(&self) -> Option<(usize, usize)> {
+        self.selection.map(|(anchor, focus)| (cmp::min(anchor, focus), cmp::max(anchor, focus)))
+    }
+
+    pub fn selected(&self, i: usize) -> bool {
+        match self.selected_range() {
+            Some((start, end)) => i >= start && i <= end,
+            None => false
+        }
+    }
+
+    /// The text of the selection, with line breaks where the words wrap
+    pub fn selected_text(&self) -> Option<String> {
+        self.selected_range().map(|(start, end)| blocks_text(&self.blocks, start, end))
+    }
+
+    /// Scroll so that a block is a third of the way down the view
+    pub fn scroll_to_block(&mut self, i: usize, width: i32, height: i32) {
+        if let Some(block) = self.blocks.get(i) {
+            if block.x < self.offset.0 || block.x + block.w > self.offset.0 + width {
+                self.offset.0 = block.x - width / 3;
+            }
+            self.offset.1 = block.y - height / 3;
+        }
+        self.scroll(0, 0, width, height);
+    }
+
+    /// Scroll by a distance, clamped to the page for a view of the given size
+    pub fn scroll(&mut self, x: i32, y: i32, width: i32, height: i32) {
+        self.offset.0 = cmp::max(0, cmp::min(cmp::max(0, self.max_offset.0 - width), self.offset.0 + x));
+        self.offset.1 = cmp::max(0, cmp::min(cmp::max(0, self.max_offset.1 - height), self.offset.1 + y));
+    }
+}
+
+/// The width of each tab, at least a pixel even when the window is too narrow to fit them
+fn tab_width(count: usize, width: i32) -> i32 {
+    cmp::max(1, cmp::min(TAB_MAX_WIDTH, (width - NEW_WIDTH) / cmp::max(1, count as i32)))
+}
+
+/// Find what was clicked at a point inside the tab strip
+pub fn tab_click(x: i32, y: i32, count: usize, width: i32) -> Option<TabAction> {
+    if y < 0 || y >= TAB_HEIGHT || x < 0 {
+        return None;
+    }
+    // There is no room for the tabs
+    if count as i32 > width - NEW_WIDTH {
+        return None;
+    }
+
+    let tab_w = tab_width(count, width);
+    let i = (x / tab_w) as usize;
+    if i < count {
+        if x >= (i as i32 + 1) * tab_w - CLOSE_WIDTH {
+            Some(TabAction::Close(i))
+        } else {
+            Some(TabAction::Select(i))
+        }
+    } else if x < count as i32 * tab_w + NEW_WIDTH {
+        Some(TabAction::New)
+    } else {
+        None
+    }
+}
+
+pub fn draw_tabs(window: &mut Window, font: &Font, tabs: &mut [Tab], current: usize) {
+    let width = window.width() as i32;
+    window.rect(0, 0, width as u32, TAB_HEIGHT as u32, Color::rgb(200, 200, 200));
+
+    let tab_w = tab_width(tabs.len(), width);
+    for (i, tab) in tabs.iter_mut().enumerate() {
+        let x = i as i32 * tab_w;
+        if i == current {
+            window.rect(x, 0, tab_w as u32, TAB_HEIGHT as u32, Color::rgb(238, 238, 238));
+        }
+        window.rect(x + tab_w - 1, 4, 1, (TAB_HEIGHT - 8) as u32, Color::rgb(160, 160, 160));
+
+        // Cut the title down to fit in front of the close button, again only when either changes
+        let title = tab.title();
+        let title_w = tab_w - CLOSE_WIDTH - 8;
+        let fitted = match tab.title_fit {
+            Some((fit_w, ref fit_title, ref fit)) if fit_w == title_w && *fit_title == title => fit.clone(),
+            _ => fit_start(font, &title, FONT_SIZE, title_w).to_string()
+        };
+        tab.title_fit = Some((title_w, title, fitted.clone()));
+        if ! fitted.is_empty() {
+            let text = font.render(&fitted, FONT_SIZE);
+            text.draw(window, x + 4, (TAB_HEIGHT - text.height() as i32) / 2, Color::rgb(0, 0, 0));
+        }
+
+        let close = font.render("x", FONT_SIZE);
+        close.draw(window, x + tab_w - CLOSE_WIDTH + (CLOSE_WIDTH - close.width() as i32) / 2 - 1, (TAB_HEIGHT - close.height() as i32) / 2, Color::rgb(96, 96, 96));
+    }
+
+    let new = font.render("+", FONT_SIZE);
+    new.draw(window, tabs.len() as i32 * tab_w + (NEW_WIDTH - new.width() as i32) / 2, (TAB_HEIGHT - new.height() as i32) / 2, Color::rgb(0, 0, 0));
+}
@@ -0,0 +1,104 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use css::{Origin, Stylesheet};
+
+/// Light text on a dark background for every page, whatever colours it sets itself
+const DARK_CSS: &'static str = "
+* { color: #dddddd !important; background-color: transparent !important; border-color: #555555 !important }
+html, body { background-color: #1e1e1e !important }
+a:link { color: #8ab4f8 !important }
+input, textarea, select, button { color: #000000 !important }
+";
+
+/// The user stylesheet from ~/.config/browser/user.css, applied to every page, and dark mode,
+/// which is saved between runs. In dark mode, prefers-color-scheme: dark media queries match,
+/// so that the user stylesheet can have its own dark colours.
+pub struct Theme {
+    user_css: Option<String>,
+    dark: AtomicBool,
+    /// The file dark mode is saved to, if there is a home directory
+    path: Option<PathBuf>,
+}
+
+impl Theme {
+    pub fn new() -> Theme {
+        let dir = env::home_dir().map(|home| home.join(".config").join("browser"));
+
+        let user_css = dir.as_ref().and_then(|dir| {
+            let path = dir.join("user.css");
+            let mut css = String::new();
+            match File::open(&path).and_then(|mut file| file.read_to_string(&mut css)) {
+                Ok(_) => Some(css),
+                Err(ref err) if err.kind() == ErrorKind::NotFound => None,
+                Err(err) => {
+                    println!("Failed to read {}: {}", path.display(), err);
+                    None
+                }
+            }
+        });
+
+        let path = dir.map(|dir| dir.join("theme.txt"));
+        let mut data = String::new();
+        if let Some(ref path) = path {
+            if let Ok(mut file) = File::open(path) {
+                if let Err(err) = file.read_to_string(&mut data) {
+                    println!("Failed to read {}: {}", path.display(), err);
+                }
+            }
+        }
+
+        Theme {
+            user_css: user_css,
+            dark: AtomicBool::new(data.trim() == "dark"),
+            path: path,
+        }
+    }
+
+    pub fn dark(&self) -> bool {
+        self.dark.load(Ordering::SeqCst)
+    }
+
+    /// Switch dark mode on or off, returning true if it is now on
+    pub fn toggle_dark(&self) -> bool {
+        let dark = ! self.dark();
+        self.dark.store(dark, Ordering::SeqCst);
+        self.save();
+        dark
+    }
+
+    /// The stylesheet a page starts from, before its own styles are added
+    pub fn stylesheet(&self) -> Stylesheet {
+        Stylesheet::user_agent(self.dark())
+    }
+
+    /// Add the dark mode colours when they are on, and then the user stylesheet, to the styles
+    /// of a page
+    pub fn apply(&self, stylesheet: &mut Stylesheet) {
+        if self.dark() {
+            stylesheet.add(DARK_CSS, Origin::User);
+        }
+        if let Some(ref css) = self.user_css {
+            stylesheet.add(css, Origin::User);
+        }
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return
+        };
+
+        let data = if self.dark() { "dark\n" } else { "light\n" };
+        let result = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(())
+        }.and_then(|_| File::create(path)).and_then(|mut file| file.write_all(data.as_bytes()));
+        if let Err(err) = result {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use orbclient::{Color, Renderer, Window};
+use orbfont::Font;
+
+use history::Visit;
+
+/// Height of the toolbar above the page
+pub const TOOLBAR_HEIGHT: i32 = 32;
+
+const BUTTON_WIDTH: i32 = 32;
+const SUGGESTION_HEIGHT: i32 = 40;
+const FONT_SIZE: f32 = 16.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToolbarAction {
+    Back,
+    Forward,
+    Reload,
+    Stop,
+    Bookmark,
+    UrlBar,
+}
+
+/// The navigation buttons and the address bar
+pub struct Toolbar {
+    /// The address being shown or edited
+    pub text: String,
+    /// Is the address bar taking key presses
+    pub focused: bool,
+    /// Pages from the history matching the address being typed
+    pub suggestions: Vec<Visit>,
+    /// The suggestion chosen with the arrow keys
+    pub selected: Option<usize>,
+}
+
+impl Toolbar {
+    pub fn new() -> Toolbar {
+        Toolbar {
+            text: String::new(),
+            focused: false,
+            suggestions: Vec::new(),
+            selected: None,
+        }
+    }
+
+    fn buttons() -> [(ToolbarAction, &'static str); 5] {
+        [
+            (ToolbarAction::Back, "<"),
+            (ToolbarAction::Forward, ">"),
+            (ToolbarAction::Reload, "R"),
+            (ToolbarAction::Stop, "X"),
+            (ToolbarAction::Bookmark, "*"),
+        ]
+    }
+
+    /// Find what was clicked at a point, relative to the top left of the toolbar
+    pub fn click(&self, x: i32, y: i32) -> Option<ToolbarAction> {
+        if y < 0 || y >= TOOLBAR_HEIGHT || x < 0 {
+            return None;
+        }
+
+        let buttons = Toolbar::buttons();
+        let i = (x / BUTTON_WIDTH) as usize;
+        if i < buttons.len() {
+            Some(buttons[i].0)
+        } else {
+            Some(ToolbarAction::UrlBar)
+        }
+    }
+
+    /// Focus the address bar, ready to replace its contents
+    pub fn focus(&mut self) {
+        self.focused = true;
+        self.text.clear();
+        self.suggestions.clear();
+        self.selected = None;
+    }
+
+    /// Replace the suggestions after the address has been edited
+    pub fn suggest(&mut self, suggestions: Vec<Visit>) {
+        self.suggestions = suggestions;
+        self.selected = None;
+    }
+
+    /// Move the chosen suggestion up or down, going back to the typed address past either end
+    pub fn select(&mut self, down: bool) {
+        let count = self.suggestions.len();
+        self.selected = match (self.selected, down) {
+            _ if count == 0 => None,
+            (None, true) => Some(0),
+            (None, false) => Some(count - 1),
+            (Some(i), true) if i + 1 < count => Some(i + 1),
+            (Some(i), false) if i > 0 => Some(i - 1),
+            _ => None
+        };
+    }
+
+    /// The address to go to when Enter is pressed
+    pub fn address(&self) -> &str {
+        match self.selected.and_then(|i| self.suggestions.get(i)) {
+            Some(visit) => &visit.url,
+            None => &self.text
+        }
+    }
+
+    /// Find the suggestion at a point, relative to the bottom left of the toolbar
+    pub fn suggestion_click(&self, x: i32, y: i32) -> Option<usize> {
+        if ! self.focused || x < 0 || y < 0 {
+            return None;
+        }
+
+        let i = (y / SUGGESTION_HEIGHT) as usize;
+        if i < self.suggestions.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    /// Draw the suggestions over the page, below the toolbar whose bottom edge is at top
+    pub fn draw_suggestions(&self, window: &mut Window, top: i32, font: &Font) {
+        if ! self.focused || self.suggestions.is_empty() {
+            return;
+        }
+
+        let width = window.width() as i32;
+        for (i, visit) in self.suggestions.iter().enumerate() {
+            let y = top + i as i32 * SUGGESTION_HEIGHT;
+            let background = if self.selected == Some(i) {
+                Color::rgb(179, 215, 255)
+            } else {
+                Color::rgb(255, 255, 255)
+            };
+            window.rect(0, y, width as u32, SUGGESTION_HEIGHT as u32, background);
+
+            let title = if visit.title.is_empty() { &visit.url } else { &visit.title };
+            font.render(title, FONT_SIZE).draw(window, 8, y + 2, Color::rgb(0, 0, 0));
+            font.render(&visit.url, FONT_SIZE - 3.0).draw(window, 8, y + SUGGESTION_HEIGHT / 2 + 2, Color::rgb(0, 102, 0));
+        }
+
+        let bottom = top + self.suggestions.len() as i32 * SUGGESTION_HEIGHT;
+        window.rect(0, bottom, width as u32, 1, Color::rgb(160, 160, 160));
+    }
+
+    /// Draw the toolbar with its top edge at top. The address bar is tinted red on a site whose
+    /// certificate is not checked.
+    pub fn draw(&self, window: &mut Window, top: i32, font: &Font, can_back: bool, can_forward: bool, loading: bool, bookmarked: bool, unverified: bool) {
+        let width = window.width() as i32;
+        window.rect(0, top, width as u32, TOOLBAR_HEIGHT as u32, Color::rgb(238, 238, 238));
+        window.rect(0, top + TOOLBAR_HEIGHT - 1, width as u32, 1, Color::rgb(160, 160, 160));
+
+        for (i, &(action, label)) in Toolbar::buttons().iter().enumerate() {
+            let enabled = match action {
+                ToolbarAction::Back => can_back,
+                ToolbarAction::Forward => can_forward,
+                ToolbarAction::Reload => ! loading,
+                ToolbarAction::Stop => loading,
+                ToolbarAction::Bookmark | ToolbarAction::UrlBar => true,
+            };
+            let color = if action == ToolbarAction::Bookmark && bookmarked {
+                Color::rgb(230, 140, 0)
+            } else if enabled {
+                Color::rgb(0, 0, 0)
+            } else {
+                Color::rgb(160, 160, 160)
+            };
+
+            let text = font.render(label, FONT_SIZE);
+            let x = i as i32 * BUTTON_WIDTH + (BUTTON_WIDTH - text.width() as i32) / 2;
+            let y = top + (TOOLBAR_HEIGHT - text.height() as i32) / 2;
+            text.draw(window, x, y, color);
+        }
+
+        let bar_x = Toolbar::buttons().len() as i32 * BUTTON_WIDTH + 4;
+        let bar_w = width - bar_x - 4;
+        if bar_w <= 8 {
+            return;
+        }
+
+        let border = if self.focused {
+            Color::rgb(0, 0, 255)
+        } else {
+            Color::rgb(118, 118, 118)
+        };
+        window.rect(bar_x, top + 4, bar_w as u32, (TOOLBAR_HEIGHT - 8) as u32, border);
+        let background = if unverified && ! self.focused {
+            Color::rgb(255, 221, 221)
+        } else {
+            Color::rgb(255, 255, 255)
+        };
+        window.rect(bar_x + 1, top + 5, (bar_w - 2) as u32, (TOOLBAR_HEIGHT - 10) as u32, background);
+
+        // While editing keep the end in view, otherwise the start
+        let mut text = &self.text[..];
+        while ! text.is_empty() && font.render(text, FONT_SIZE).width() as i32 > bar_w - 8 {
+            let mut chars = text.chars();
+            if self.focused {
+                chars.next();
+            } else {
+                chars.next_back();
+            }
+            text = chars.as_str();
+        }
+
+        let mut cursor_x = bar_x + 4;
+        if ! text.is_empty() {
+            let rendered = font.render(text, FONT_SIZE);
+            rendered.draw(window, bar_x + 4, top + (TOOLBAR_HEIGHT - rendered.height() as i32) / 2, Color::rgb(0, 0, 0));
+            cursor_x += rendered.width() as i32;
+        }
+
+        if self.focused {
+            window.rect(cursor_x, top + 8, 1, (TOOLBAR_HEIGHT - 16) as u32, Color::rgb(0, 0, 0));
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use url::Url;
+
+/// The zoom levels stepped through with Ctrl+Plus and Ctrl+Minus
+const LEVELS: [f32; 13] = [0.3, 0.5, 0.67, 0.8, 0.9, 1.0, 1.1, 1.25, 1.5, 1.75, 2.0, 2.5, 3.0];
+
+/// The zoom level of every site that is not shown at its normal size, saved between runs
+pub struct Zoom {
+    /// Levels by host, or by scheme for URLs without one
+    levels: Mutex<BTreeMap<String, f32>>,
+    /// The file the levels are saved to, if there is a home directory
+    path: Option<PathBuf>,
+}
+
+impl Zoom {
+    pub fn new() -> Zoom {
+        let path = env::home_dir().map(|home| home.join(".config").join("browser").join("zoom.txt"));
+
+        let mut levels = BTreeMap::new();
+        if let Some(ref path) = path {
+            let mut data = String::new();
+            if let Ok(mut file) = File::open(path) {
+                match file.read_to_string(&mut data) {
+                    Ok(_) => for line in data.lines() {
+                        let mut parts = line.splitn(2, '\t');
+                        let site = parts.next().unwrap_or("");
+                        if let Some(Ok(level)) = parts.next().map(|level| level.parse::<f32>()) {
+                            if ! site.is_empty() && level > 0.0 {
+                                levels.insert(site.to_string(), level);
+                            }
+                        }
+                    },
+                    Err(err) => println!("Failed to read {}: {}", path.display(), err)
+                }
+            }
+        }
+
+        Zoom {
+            levels: Mutex::new(levels),
+            path: path,
+        }
+    }
+
+    /// The zoom level of the site a URL belongs to
+    pub fn get(&self, url: &Url) -> f32 {
+        self.levels.lock().unwrap().get(site(url)).cloned().unwrap_or(1.0)
+    }
+
+    /// Step the zoom level of a site up or down, or back to normal, returning the new level
+    pub fn change(&self, url: &Url, step: Option<bool>) -> f32 {
+        let current = self.get(url);
+        let level = match step {
+            Some(true) => LEVELS.iter().cloned().find(|&level| level > current + 0.001).unwrap_or(current),
+            Some(false) => LEVELS.iter().rev().cloned().find(|&level| level < current - 0.001).unwrap_or(current),
+            None => 1.0
+        };
+
+        {
+            let mut levels = self.levels.lock().unwrap();
+            if level == 1.0 {
+                levels.remove(site(url));
+            } else {
+                levels.insert(site(url).to_string(), level);
+            }
+        }
+        self.save();
+        level
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return
+        };
+
+        let mut data = String::new();
+        for (site, level) in self.levels.lock().unwrap().iter() {
+            data.push_str(&format!("{}\t{}\n", site, level));
+        }
+
+        let result = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(())
+        }.and_then(|_| File::create(path)).and_then(|mut file| file.write_all(data.as_bytes()));
+        if let Err(err) = result {
+            println!("Failed to write {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn site(url: &Url) -> &str {
+    url.host_str().unwrap_or(url.scheme())
+}